@@ -0,0 +1,34 @@
+//! Manual wrapper for values in llama.cpp/src/llama-grammar.h
+//!
+//! The grammar element types are no longer part of `llama.h`, so they are not picked up by bindgen.
+
+/// end of rule definition
+pub const LLAMA_GRETYPE_END: llama_gretype = 0;
+/// start of alternate definition for rule
+pub const LLAMA_GRETYPE_ALT: llama_gretype = 1;
+/// non-terminal element: reference to rule
+pub const LLAMA_GRETYPE_RULE_REF: llama_gretype = 2;
+/// terminal element: character (code point)
+pub const LLAMA_GRETYPE_CHAR: llama_gretype = 3;
+/// inverse char(s) (`[^a]`, `[^a-b]` `[^abc]`)
+pub const LLAMA_GRETYPE_CHAR_NOT: llama_gretype = 4;
+/// modifies a preceding `LLAMA_GRETYPE_CHAR` or `LLAMA_GRETYPE_CHAR_ALT` to
+/// be an inclusive range (`[a-z]`)
+pub const LLAMA_GRETYPE_CHAR_RNG_UPPER: llama_gretype = 5;
+/// modifies a preceding `LLAMA_GRETYPE_CHAR` or
+/// `LLAMA_GRETYPE_CHAR_RNG_UPPER` to add an alternate char to match (`[ab]`, `[a-zA]`)
+pub const LLAMA_GRETYPE_CHAR_ALT: llama_gretype = 6;
+/// any character (`.`)
+pub const LLAMA_GRETYPE_CHAR_ANY: llama_gretype = 7;
+/// grammar element type
+pub type llama_gretype = ::core::ffi::c_uint;
+
+/// grammar element
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct llama_grammar_element {
+    /// the element type
+    pub type_: llama_gretype,
+    /// Unicode code point or rule ID
+    pub value: u32,
+}
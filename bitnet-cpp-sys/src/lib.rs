@@ -5,3 +5,4 @@
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
 pub mod common;
+pub mod grammar;
@@ -17,11 +17,17 @@ use crate::{
     LlamaLoraAdapterSetError,
 };
 
+pub mod generate;
 pub mod kv_cache;
+pub mod parallel;
 pub mod params;
 pub mod perf;
+pub mod prefix_cache;
 pub mod sampler;
+pub mod scheduler;
+pub mod self_extend;
 pub mod session;
+pub mod shift;
 
 /// Safe wrapper around `llama_context`.
 #[allow(clippy::module_name_repetitions)]
@@ -67,6 +73,12 @@ impl<'model> LlamaContext<'model> {
         unsafe { bitnet_cpp_sys::llama_n_ubatch(self.context.as_ptr()) }
     }
 
+    /// Gets the max number of sequences that can be decoded in parallel.
+    #[must_use]
+    pub fn n_seq_max(&self) -> u32 {
+        unsafe { bitnet_cpp_sys::llama_n_seq_max(self.context.as_ptr()) }
+    }
+
     /// Gets the size of the context.
     #[must_use]
     pub fn n_ctx(&self) -> u32 {
@@ -0,0 +1,720 @@
+//! A high level generation loop on top of [`LlamaContext`].
+//!
+//! [`LlamaContext::generate`] tokenizes and decodes a prompt, then returns a [`Generator`] that
+//! samples one token per [`Iterator::next`] call and feeds it back into the context.
+//!
+//! ```no_run
+//! # use bitnet_cpp::context::LlamaContext;
+//! # use bitnet_cpp::context::generate::GenerateParams;
+//! # use bitnet_cpp::context::sampler::LlamaSampler;
+//! # fn example(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! let sampler = LlamaSampler::default();
+//! let params = GenerateParams::default().with_max_tokens(Some(64));
+//! for piece in ctx.generate("Hello! how are you?", &sampler, params)? {
+//!     print!("{}", piece?.text);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::sampler::LlamaSampler;
+use crate::context::self_extend::SelfExtend;
+use crate::context::shift::{ContextShift, ContextShifted};
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch, Pos, SeqId};
+use crate::model::{AddBos, LlamaModel, Special};
+use crate::token::LlamaToken;
+use crate::{DecodeError, StringToTokenError, TokenToStringError};
+
+/// Errors that can occur while generating text.
+#[derive(Debug, thiserror::Error)]
+pub enum GenerateError {
+    /// The prompt could not be tokenized.
+    #[error("{0}")]
+    Tokenize(#[from] StringToTokenError),
+    /// The prompt did not contain any tokens.
+    #[error("the prompt is empty")]
+    EmptyPrompt,
+    /// The prompt does not fit into the context.
+    #[error("the prompt ({n_prompt} tokens starting at {n_past}) does not fit into the context of {n_ctx} tokens")]
+    PromptTooLong {
+        /// The number of prompt tokens.
+        n_prompt: usize,
+        /// The position the prompt was to be placed at.
+        n_past: Pos,
+        /// The size of the context.
+        n_ctx: u32,
+    },
+    /// Adding a token to the batch failed.
+    #[error("{0}")]
+    BatchAdd(#[from] BatchAddError),
+    /// Decoding a batch failed.
+    #[error("{0}")]
+    Decode(#[from] DecodeError),
+    /// A sampled token could not be converted to text.
+    #[error("{0}")]
+    TokenToString(#[from] TokenToStringError),
+    /// The kv cache of the sequence could not be cleared after the position the prompt was to
+    /// be placed at.
+    #[error("could not clear the kv cache of sequence {seq_id} after {n_past}")]
+    ClearKvCache {
+        /// The sequence of the prompt.
+        seq_id: SeqId,
+        /// The position the prompt was to be placed at.
+        n_past: Pos,
+    },
+}
+
+/// Why a [`Generator`] stopped producing tokens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// The model sampled an end of generation token (see
+    /// [`LlamaModel::is_eog_token`](crate::model::LlamaModel::is_eog_token)).
+    EndOfGeneration(LlamaToken),
+    /// The configured maximum number of tokens was generated.
+    MaxTokens,
+    /// There is no room left in the context for another token.
+    ContextFull,
+    /// The generated text contained this stop sequence (see
+    /// [`GenerateParams::with_stop_sequences`]).
+    StopSequence(String),
+    /// The model sampled this stop token (see [`GenerateParams::with_stop_tokens`]).
+    StopToken(LlamaToken),
+}
+
+/// A token produced by a [`Generator`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeneratedToken {
+    /// The sampled token.
+    pub token: LlamaToken,
+    /// The text this token completes. This can be empty if the token ends in the middle of a
+    /// multi-byte character or might be the start of a stop sequence, in which case the text is
+    /// part of a later token.
+    ///
+    /// If the generation ends on an end of generation or stop token while text is held back, the
+    /// last item carries that token and the remaining text.
+    pub text: String,
+}
+
+/// Parameters for [`LlamaContext::generate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenerateParams {
+    max_tokens: Option<u32>,
+    add_bos: AddBos,
+    special: Special,
+    seq_id: SeqId,
+    n_past: Pos,
+    stop_sequences: Vec<String>,
+    stop_tokens: Vec<LlamaToken>,
+    context_shift: Option<ContextShift>,
+    self_extend: Option<SelfExtend>,
+}
+
+impl Default for GenerateParams {
+    fn default() -> Self {
+        Self {
+            max_tokens: None,
+            add_bos: AddBos::Always,
+            special: Special::Tokenize,
+            seq_id: SeqId(0),
+            n_past: Pos(0),
+            stop_sequences: Vec::new(),
+            stop_tokens: Vec::new(),
+            context_shift: None,
+            self_extend: None,
+        }
+    }
+}
+
+impl GenerateParams {
+    /// Set the maximum number of tokens to generate. `None` generates until the model ends the
+    /// generation or the context is full.
+    ///
+    /// ```rust
+    /// use bitnet_cpp::context::generate::GenerateParams;
+    /// let params = GenerateParams::default().with_max_tokens(Some(128));
+    /// assert_eq!(params.max_tokens(), Some(128));
+    /// ```
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: Option<u32>) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// Get the maximum number of tokens to generate.
+    #[must_use]
+    pub fn max_tokens(&self) -> Option<u32> {
+        self.max_tokens
+    }
+
+    /// Set whether a beginning of stream token is prepended to the prompt.
+    #[must_use]
+    pub fn with_add_bos(mut self, add_bos: AddBos) -> Self {
+        self.add_bos = add_bos;
+        self
+    }
+
+    /// Get whether a beginning of stream token is prepended to the prompt.
+    #[must_use]
+    pub fn add_bos(&self) -> AddBos {
+        self.add_bos
+    }
+
+    /// Set how special tokens are rendered in the generated text.
+    #[must_use]
+    pub fn with_special(mut self, special: Special) -> Self {
+        self.special = special;
+        self
+    }
+
+    /// Get how special tokens are rendered in the generated text.
+    #[must_use]
+    pub fn special(&self) -> Special {
+        self.special
+    }
+
+    /// Set the sequence the prompt and the generated tokens are placed in.
+    #[must_use]
+    pub fn with_seq_id(mut self, seq_id: SeqId) -> Self {
+        self.seq_id = seq_id;
+        self
+    }
+
+    /// Get the sequence the prompt and the generated tokens are placed in.
+    #[must_use]
+    pub fn seq_id(&self) -> SeqId {
+        self.seq_id
+    }
+
+    /// Set the position of the first prompt token. Everything at or after this position in the
+    /// sequence is removed from the kv cache, so a previous [`Generator::n_past`] can be used to
+    /// continue a conversation.
+    #[must_use]
+    pub fn with_n_past(mut self, n_past: Pos) -> Self {
+        self.n_past = n_past;
+        self
+    }
+
+    /// Get the position of the first prompt token.
+    #[must_use]
+    pub fn n_past(&self) -> Pos {
+        self.n_past
+    }
+
+    /// Stop the generation once the generated text contains any of `stop_sequences`. Matches can
+    /// span several tokens; text that might be the start of a stop sequence is held back until it
+    /// is known not to be, so a stop sequence is never part of the generated text. Empty stop
+    /// sequences are ignored.
+    ///
+    /// ```rust
+    /// use bitnet_cpp::context::generate::GenerateParams;
+    /// let params = GenerateParams::default().with_stop_sequences(["</answer>", "\nUser:"]);
+    /// assert_eq!(params.stop_sequences(), ["</answer>", "\nUser:"]);
+    /// ```
+    #[must_use]
+    pub fn with_stop_sequences<I, S>(mut self, stop_sequences: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.stop_sequences = stop_sequences
+            .into_iter()
+            .map(Into::into)
+            .filter(|stop| !stop.is_empty())
+            .collect();
+        self
+    }
+
+    /// Get the stop sequences.
+    #[must_use]
+    pub fn stop_sequences(&self) -> &[String] {
+        &self.stop_sequences
+    }
+
+    /// Stop the generation when the model samples any of `stop_tokens`, in addition to the end of
+    /// generation tokens of the model. Like those, the stop token itself is not generated.
+    #[must_use]
+    pub fn with_stop_tokens(mut self, stop_tokens: impl IntoIterator<Item = LlamaToken>) -> Self {
+        self.stop_tokens = stop_tokens.into_iter().collect();
+        self
+    }
+
+    /// Get the stop tokens.
+    #[must_use]
+    pub fn stop_tokens(&self) -> &[LlamaToken] {
+        &self.stop_tokens
+    }
+
+    /// Shift the context instead of stopping with [`StopReason::ContextFull`] when it is full,
+    /// see [`ContextShift`]. [`Generator::on_context_shift`] notifies about dropped history.
+    ///
+    /// ```rust
+    /// use bitnet_cpp::context::generate::GenerateParams;
+    /// use bitnet_cpp::context::shift::ContextShift;
+    /// let params = GenerateParams::default().with_context_shift(Some(ContextShift::new(32)));
+    /// assert_eq!(params.context_shift(), Some(ContextShift::new(32)));
+    /// ```
+    #[must_use]
+    pub fn with_context_shift(mut self, context_shift: Option<ContextShift>) -> Self {
+        self.context_shift = context_shift;
+        self
+    }
+
+    /// Get the context shift policy.
+    #[must_use]
+    pub fn context_shift(&self) -> Option<ContextShift> {
+        self.context_shift
+    }
+
+    /// Extend the context with Self-Extend, see [`SelfExtend`]. The prompt is decoded in chunks
+    /// of at most `ga_w` tokens. If set, the context is never shifted.
+    ///
+    /// ```rust
+    /// use bitnet_cpp::context::generate::GenerateParams;
+    /// use bitnet_cpp::context::self_extend::SelfExtend;
+    /// let self_extend = SelfExtend::new(4, 1024).unwrap();
+    /// let params = GenerateParams::default().with_self_extend(Some(self_extend));
+    /// assert_eq!(params.self_extend(), Some(self_extend));
+    /// ```
+    #[must_use]
+    pub fn with_self_extend(mut self, self_extend: Option<SelfExtend>) -> Self {
+        self.self_extend = self_extend;
+        self
+    }
+
+    /// Get the Self-Extend parameters.
+    #[must_use]
+    pub fn self_extend(&self) -> Option<SelfExtend> {
+        self.self_extend
+    }
+}
+
+type ContextShiftHook<'s> = Box<dyn FnMut(&ContextShifted) + 's>;
+
+/// An [`Iterator`] over generated tokens, created by [`LlamaContext::generate`].
+///
+/// Every yielded token has already been decoded, so after the iteration the kv cache of the
+/// sequence holds the prompt and all generated tokens, up to [`Generator::n_past`].
+pub struct Generator<'ctx, 'model, 's> {
+    ctx: &'ctx mut LlamaContext<'model>,
+    sampler: &'s LlamaSampler,
+    batch: LlamaBatch,
+    params: GenerateParams,
+    n_past: Pos,
+    /// The number of positions saved by Self-Extend, which still occupy kv cache cells.
+    n_compressed: i32,
+    self_extend: Option<SelfExtend>,
+    n_generated: u32,
+    decoder: Utf8Decoder,
+    stop_matcher: StopMatcher,
+    stop_reason: Option<StopReason>,
+    failed: bool,
+    on_context_shift: Option<ContextShiftHook<'s>>,
+}
+
+impl std::fmt::Debug for Generator<'_, '_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Generator")
+            .field("params", &self.params)
+            .field("n_past", &self.n_past)
+            .field("n_generated", &self.n_generated)
+            .field("stop_reason", &self.stop_reason)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'s> Generator<'_, '_, 's> {
+    /// Call `hook` whenever the context is shifted, see [`GenerateParams::with_context_shift`].
+    #[must_use]
+    pub fn on_context_shift(mut self, hook: impl FnMut(&ContextShifted) + 's) -> Self {
+        self.on_context_shift = Some(Box::new(hook));
+        self
+    }
+
+    /// The position the next token will be placed at.
+    #[must_use]
+    pub fn n_past(&self) -> Pos {
+        self.n_past
+    }
+
+    /// The number of tokens generated so far.
+    #[must_use]
+    pub fn n_generated(&self) -> u32 {
+        self.n_generated
+    }
+
+    /// Why the generation stopped, or `None` if it did not stop (yet).
+    #[must_use]
+    pub fn stop_reason(&self) -> Option<&StopReason> {
+        self.stop_reason.as_ref()
+    }
+
+    fn step(&mut self) -> Result<Option<GeneratedToken>, GenerateError> {
+        if self
+            .params
+            .max_tokens
+            .is_some_and(|max_tokens| self.n_generated >= max_tokens)
+        {
+            self.stop_reason = Some(StopReason::MaxTokens);
+            return Ok(None);
+        }
+
+        let token = self.sampler.sample(self.ctx, self.batch.n_tokens() - 1);
+        let stop_reason = if self.params.stop_tokens.contains(&token) {
+            Some(StopReason::StopToken(token))
+        } else if self.ctx.model.is_eog_token(token) {
+            Some(StopReason::EndOfGeneration(token))
+        } else {
+            None
+        };
+        if stop_reason.is_some() {
+            self.stop_reason = stop_reason;
+            let text = self.stop_matcher.flush();
+            return Ok((!text.is_empty()).then_some(GeneratedToken { token, text }));
+        }
+        self.n_generated += 1;
+
+        let decoded =
+            self.decoder
+                .decode(&token_to_bytes(self.ctx.model, token, self.params.special)?);
+        let (mut text, stop_sequence) = self.stop_matcher.push(&decoded);
+
+        let mut context_full = u32::try_from(self.n_past.0 + self.n_compressed)
+            .is_ok_and(|n_cells| n_cells >= self.ctx.n_ctx());
+        if context_full && self.shift_context() {
+            context_full = false;
+        }
+        if !context_full {
+            let mut decoded = self.decode_token(token);
+            if matches!(
+                decoded,
+                Err(GenerateError::Decode(DecodeError::NoKvCacheSlot))
+            ) && self.shift_context()
+            {
+                decoded = self.decode_token(token);
+            }
+            decoded?;
+            self.n_past += 1;
+        }
+
+        if let Some(stop_sequence) = stop_sequence {
+            self.stop_reason = Some(StopReason::StopSequence(stop_sequence));
+        } else if context_full {
+            self.stop_reason = Some(StopReason::ContextFull);
+        } else if self
+            .params
+            .max_tokens
+            .is_some_and(|max_tokens| self.n_generated >= max_tokens)
+        {
+            self.stop_reason = Some(StopReason::MaxTokens);
+        }
+        if self.stop_reason.is_some() {
+            text.push_str(&self.stop_matcher.flush());
+        }
+
+        Ok(Some(GeneratedToken { token, text }))
+    }
+}
+
+impl Generator<'_, '_, '_> {
+    fn decode_token(&mut self, token: LlamaToken) -> Result<(), GenerateError> {
+        if let Some(self_extend) = &mut self.self_extend {
+            let n_past = self_extend.apply(self.ctx, self.params.seq_id, self.n_past);
+            self.n_compressed += self.n_past.0 - n_past.0;
+            self.n_past = n_past;
+        }
+        self.batch.clear();
+        self.batch
+            .add(token, self.n_past, &[self.params.seq_id], true)?;
+        self.ctx.decode(&mut self.batch)?;
+        Ok(())
+    }
+
+    /// Apply the context shift policy, if any. Returns whether tokens were discarded.
+    fn shift_context(&mut self) -> bool {
+        if self.self_extend.is_some() {
+            return false;
+        }
+        let Some(shifted) = self
+            .params
+            .context_shift
+            .and_then(|shift| shift.apply(self.ctx, self.params.seq_id, self.n_past))
+        else {
+            return false;
+        };
+        self.n_past = shifted.n_past;
+        if let Some(hook) = &mut self.on_context_shift {
+            hook(&shifted);
+        }
+        true
+    }
+}
+
+impl Iterator for Generator<'_, '_, '_> {
+    type Item = Result<GeneratedToken, GenerateError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.stop_reason.is_some() {
+            return None;
+        }
+        let result = self.step();
+        self.failed = result.is_err();
+        result.transpose()
+    }
+}
+
+impl<'model> LlamaContext<'model> {
+    /// Tokenize and decode `prompt`, then generate a continuation using `sampler`.
+    ///
+    /// The prompt is decoded in chunks of [`LlamaContext::n_batch`] tokens before this returns.
+    /// See [`GenerateParams`] for the options.
+    ///
+    /// # Errors
+    ///
+    /// See [`GenerateError`] for more information.
+    pub fn generate<'ctx, 's>(
+        &'ctx mut self,
+        prompt: &str,
+        sampler: &'s LlamaSampler,
+        params: GenerateParams,
+    ) -> Result<Generator<'ctx, 'model, 's>, GenerateError> {
+        let tokens = self.model.str_to_token(prompt, params.add_bos)?;
+        self.generate_from_tokens(&tokens, sampler, params)
+    }
+
+    /// Like [`LlamaContext::generate`], but with an already tokenized prompt. The `add_bos`
+    /// parameter is ignored.
+    ///
+    /// # Errors
+    ///
+    /// See [`GenerateError`] for more information.
+    ///
+    /// # Panics
+    ///
+    /// - `n_batch` does not fit into a usize
+    pub fn generate_from_tokens<'ctx, 's>(
+        &'ctx mut self,
+        tokens: &[LlamaToken],
+        sampler: &'s LlamaSampler,
+        params: GenerateParams,
+    ) -> Result<Generator<'ctx, 'model, 's>, GenerateError> {
+        if tokens.is_empty() {
+            return Err(GenerateError::EmptyPrompt);
+        }
+        let n_ctx = self.n_ctx();
+        let fits = i32::try_from(tokens.len())
+            .ok()
+            .and_then(|n_prompt| params.n_past.0.checked_add(n_prompt))
+            .and_then(|end| u32::try_from(end).ok())
+            .is_some_and(|end| end <= n_ctx);
+        if !fits {
+            return Err(GenerateError::PromptTooLong {
+                n_prompt: tokens.len(),
+                n_past: params.n_past,
+                n_ctx,
+            });
+        }
+
+        if !self.clear_kv_cache_seq(Some(params.seq_id), params.n_past..) {
+            return Err(GenerateError::ClearKvCache {
+                seq_id: params.seq_id,
+                n_past: params.n_past,
+            });
+        }
+
+        let n_batch = usize::try_from(self.n_batch())
+            .expect("n_batch fits into a usize")
+            .max(1);
+        let mut batch = LlamaBatch::new(n_batch, 1);
+        let mut self_extend = params.self_extend;
+        let n_chunk = self_extend.map_or(n_batch, |self_extend| {
+            n_batch.min(usize::try_from(self_extend.ga_w()).expect("ga_w fits into a usize"))
+        });
+        let mut n_past = params.n_past;
+        let mut n_compressed = 0;
+        let last_index = tokens.len() - 1;
+        for (chunk_index, chunk) in tokens.chunks(n_chunk).enumerate() {
+            if let Some(self_extend) = &mut self_extend {
+                let extended = self_extend.apply(self, params.seq_id, n_past);
+                n_compressed += n_past.0 - extended.0;
+                n_past = extended;
+            }
+            batch.clear();
+            for (i, token) in chunk.iter().enumerate() {
+                let is_last = chunk_index * n_chunk + i == last_index;
+                batch.add(*token, n_past, &[params.seq_id], is_last)?;
+                n_past += 1;
+            }
+            self.decode(&mut batch)?;
+        }
+
+        let stop_sequences = params.stop_sequences.clone();
+        Ok(Generator {
+            ctx: self,
+            sampler,
+            batch,
+            params,
+            n_past,
+            n_compressed,
+            self_extend,
+            n_generated: 0,
+            decoder: Utf8Decoder::default(),
+            stop_matcher: StopMatcher::new(stop_sequences),
+            stop_reason: None,
+            failed: false,
+            on_context_shift: None,
+        })
+    }
+
+    /// Generate a continuation of `prompt` and collect it into a [`String`].
+    ///
+    /// # Errors
+    ///
+    /// See [`GenerateError`] for more information.
+    pub fn completion(
+        &mut self,
+        prompt: &str,
+        sampler: &LlamaSampler,
+        params: GenerateParams,
+    ) -> Result<String, GenerateError> {
+        self.generate(prompt, sampler, params)?
+            .map(|token| token.map(|token| token.text))
+            .collect()
+    }
+}
+
+/// The bytes of `token`, retrying with a larger buffer for long tokens.
+pub(crate) fn token_to_bytes(
+    model: &LlamaModel,
+    token: LlamaToken,
+    special: Special,
+) -> Result<Vec<u8>, TokenToStringError> {
+    match model.token_to_bytes(token, special) {
+        Err(TokenToStringError::InsufficientBufferSpace(size)) => model.token_to_bytes_with_size(
+            token,
+            usize::try_from(-size).expect("the required size is positive"),
+            special,
+            None,
+        ),
+        bytes => bytes,
+    }
+}
+
+/// Decodes UTF-8 split over several chunks of bytes. Incomplete characters at the end of a chunk
+/// are held back until the next chunk; invalid bytes are replaced with
+/// [`char::REPLACEMENT_CHARACTER`].
+#[derive(Debug, Default)]
+pub(crate) struct Utf8Decoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    pub(crate) fn decode(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let mut out = String::new();
+        loop {
+            match std::str::from_utf8(&self.pending) {
+                Ok(valid) => {
+                    out.push_str(valid);
+                    self.pending.clear();
+                    return out;
+                }
+                Err(error) => {
+                    let (valid, rest) = self.pending.split_at(error.valid_up_to());
+                    out.push_str(std::str::from_utf8(valid).expect("checked to be valid"));
+                    let Some(invalid_len) = error.error_len() else {
+                        self.pending = rest.to_vec();
+                        return out;
+                    };
+                    out.push(char::REPLACEMENT_CHARACTER);
+                    self.pending.drain(..error.valid_up_to() + invalid_len);
+                }
+            }
+        }
+    }
+}
+
+/// Holds back generated text that might be the start of a stop sequence.
+#[derive(Debug, Default)]
+struct StopMatcher {
+    stop_sequences: Vec<String>,
+    held: String,
+}
+
+impl StopMatcher {
+    fn new(stop_sequences: Vec<String>) -> Self {
+        Self {
+            stop_sequences,
+            held: String::new(),
+        }
+    }
+
+    /// Add newly generated `text`. Returns the text that can be emitted and the stop sequence
+    /// that matched, if any. Everything from the match onwards is dropped.
+    fn push(&mut self, text: &str) -> (String, Option<String>) {
+        self.held.push_str(text);
+
+        let earliest = self
+            .stop_sequences
+            .iter()
+            .filter_map(|stop| Some((self.held.find(stop.as_str())?, stop)))
+            .min_by_key(|(index, _)| *index);
+        if let Some((index, stop)) = earliest {
+            let stop = stop.clone();
+            self.held.truncate(index);
+            return (std::mem::take(&mut self.held), Some(stop));
+        }
+
+        let keep = self
+            .stop_sequences
+            .iter()
+            .map(|stop| partial_match_len(&self.held, stop))
+            .max()
+            .unwrap_or(0);
+        let emit = self.held.len() - keep;
+        (self.held.drain(..emit).collect(), None)
+    }
+
+    /// Take all held back text.
+    fn flush(&mut self) -> String {
+        std::mem::take(&mut self.held)
+    }
+}
+
+/// The length of the longest suffix of `text` that is a proper prefix of `stop`.
+fn partial_match_len(text: &str, stop: &str) -> usize {
+    (1..stop.len().min(text.len() + 1))
+        .rev()
+        .find(|&len| stop.is_char_boundary(len) && text.ends_with(&stop[..len]))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utf8_decoder_holds_back_partial_chars() {
+        let mut decoder = Utf8Decoder::default();
+        let bytes = "aé😀".as_bytes();
+        assert_eq!(decoder.decode(&bytes[..2]), "a");
+        assert_eq!(decoder.decode(&bytes[2..5]), "é");
+        assert_eq!(decoder.decode(&bytes[5..]), "😀");
+        assert_eq!(decoder.decode(&[0xff, b'b']), "\u{FFFD}b");
+    }
+
+    #[test]
+    fn stop_matcher_holds_back_partial_matches() {
+        let mut matcher = StopMatcher::new(vec!["</answer>".to_owned(), "\nUser:".to_owned()]);
+        assert_eq!(matcher.push("42 <"), ("42 ".to_owned(), None));
+        assert_eq!(matcher.push("/ans"), (String::new(), None));
+        assert_eq!(matcher.push("wer"), (String::new(), None));
+        assert_eq!(matcher.push("!"), ("</answer!".to_owned(), None));
+        assert_eq!(matcher.push("\nUs"), (String::new(), None));
+        assert_eq!(matcher.flush(), "\nUs");
+        assert_eq!(
+            matcher.push("ok</answer>ignored"),
+            ("ok".to_owned(), Some("</answer>".to_owned()))
+        );
+    }
+}
@@ -1,21 +1,25 @@
 //! utilities for working with the kv cache
 
 use crate::context::LlamaContext;
+use crate::llama_batch::{Pos, SeqId};
+use bitnet_cpp_sys::llama_pos;
 use std::ffi::c_int;
-use std::num::{NonZeroU8, TryFromIntError};
+use std::num::NonZeroU8;
+use std::ops::{Bound, RangeBounds};
 
-/// Errors that can occur when attempting to prepare values for the kv cache
-#[derive(Debug, Eq, PartialEq, thiserror::Error)]
-pub enum KvCacheConversionError {
-    /// Sequence id conversion to i32 failed
-    #[error("Provided sequence id is too large for a i32")]
-    SeqIdTooLarge(#[source] TryFromIntError),
-    /// Position 0 conversion to i32 failed
-    #[error("Provided start position is too large for a i32")]
-    P0TooLarge(#[source] TryFromIntError),
-    /// Position 1 conversion to i32 failed
-    #[error("Provided end position is too large for a i32")]
-    P1TooLarge(#[source] TryFromIntError),
+/// Convert `range` to the `[p0, p1)` pair llama.cpp expects, where a negative value is unbounded.
+fn to_p0_p1(range: &impl RangeBounds<Pos>) -> (llama_pos, llama_pos) {
+    let p0 = match range.start_bound() {
+        Bound::Included(&Pos(p0)) => p0,
+        Bound::Excluded(&Pos(p0)) => p0.saturating_add(1),
+        Bound::Unbounded => -1,
+    };
+    let p1 = match range.end_bound() {
+        Bound::Included(&Pos(p1)) => p1.saturating_add(1),
+        Bound::Excluded(&Pos(p1)) => p1,
+        Bound::Unbounded => -1,
+    };
+    (p0, p1)
 }
 
 impl LlamaContext<'_> {
@@ -26,69 +30,45 @@ impl LlamaContext<'_> {
     /// * `src` - The sequence id to copy the cache from.
     /// * `dest` - The sequence id to copy the cache to.
     /// * `size` - The size of the cache to copy.
+    #[deprecated(note = "use `copy_kv_cache_seq(src, dest, ..Pos(size))` instead")]
     pub fn copy_cache(&mut self, src: i32, dest: i32, size: i32) {
-        unsafe { bitnet_cpp_sys::llama_kv_cache_seq_cp(self.context.as_ptr(), src, dest, 0, size) }
+        self.copy_kv_cache_seq(SeqId(src), SeqId(dest), ..Pos(size));
     }
 
     /// Copy the cache from one sequence to another.
     ///
-    /// # Returns
-    /// A `Result` indicating whether the operation was successful. If the either position exceeds
-    /// the maximum i32 value, no copy is attempted and an `Err` is returned.
-    ///
     /// # Parameters
     ///
     /// * `src` - The sequence id to copy the cache from.
     /// * `dest` - The sequence id to copy the cache to.
-    /// * `p0` - The start position of the cache to clear. If `None`, the entire cache is copied up to `p1`.
-    /// * `p1` - The end position of the cache to clear. If `None`, the entire cache is copied starting from `p0`.
-    pub fn copy_kv_cache_seq(
-        &mut self,
-        src: i32,
-        dest: i32,
-        p0: Option<u32>,
-        p1: Option<u32>,
-    ) -> Result<(), KvCacheConversionError> {
-        let p0 = p0
-            .map_or(Ok(-1), i32::try_from)
-            .map_err(|e| KvCacheConversionError::P0TooLarge(e))?;
-        let p1 = p1
-            .map_or(Ok(-1), i32::try_from)
-            .map_err(|e| KvCacheConversionError::P1TooLarge(e))?;
+    /// * `range` - The positions to copy, e.g. `..` for the entire sequence or `Pos(4)..` for
+    ///   everything starting at position 4.
+    pub fn copy_kv_cache_seq(&mut self, src: SeqId, dest: SeqId, range: impl RangeBounds<Pos>) {
+        let (p0, p1) = to_p0_p1(&range);
         unsafe {
-            bitnet_cpp_sys::llama_kv_cache_seq_cp(self.context.as_ptr(), src, dest, p0, p1);
+            bitnet_cpp_sys::llama_kv_cache_seq_cp(self.context.as_ptr(), src.0, dest.0, p0, p1);
         }
-        Ok(())
     }
 
-    /// Clear the kv cache for the given sequence within the specified range `[p0, p1)`
+    /// Clear the kv cache for the given sequence within the specified range of positions.
     /// Returns `false` only when partial sequence removals fail. Full sequence removals always succeed.
     ///
-    /// # Returns
-    /// A `Result` indicating whether the operation was successful. If the sequence id or
-    /// either position exceeds the maximum i32 value, no removal is attempted and an `Err` is returned.
-    ///
     /// # Parameters
     ///
     /// * `src` - The sequence id to clear the cache for. If `None`, matches all sequences
-    /// * `p0` - The start position of the cache to clear. If `None`, the entire cache is cleared up to `p1`.
-    /// * `p1` - The end position of the cache to clear. If `None`, the entire cache is cleared from `p0`.
-    pub fn clear_kv_cache_seq(
-        &mut self,
-        src: Option<u32>,
-        p0: Option<u32>,
-        p1: Option<u32>,
-    ) -> Result<bool, KvCacheConversionError> {
-        let src = src
-            .map_or(Ok(-1), i32::try_from)
-            .map_err(|e| KvCacheConversionError::SeqIdTooLarge(e))?;
-        let p0 = p0
-            .map_or(Ok(-1), i32::try_from)
-            .map_err(|e| KvCacheConversionError::P0TooLarge(e))?;
-        let p1 = p1
-            .map_or(Ok(-1), i32::try_from)
-            .map_err(|e| KvCacheConversionError::P1TooLarge(e))?;
-        Ok(unsafe { bitnet_cpp_sys::llama_kv_cache_seq_rm(self.context.as_ptr(), src, p0, p1) })
+    /// * `range` - The positions to clear, e.g. `..` for the entire sequence or `Pos(4)..` for
+    ///   everything starting at position 4.
+    #[must_use]
+    pub fn clear_kv_cache_seq(&mut self, src: Option<SeqId>, range: impl RangeBounds<Pos>) -> bool {
+        let src = src.map_or(-1, |SeqId(src)| src);
+        let (p0, p1) = to_p0_p1(&range);
+        unsafe { bitnet_cpp_sys::llama_kv_cache_seq_rm(self.context.as_ptr(), src, p0, p1) }
+    }
+
+    /// Clear the whole kv cache of `seq_id`, which unlike a partial removal can not fail.
+    pub(crate) fn clear_kv_cache_seq_all(&mut self, seq_id: SeqId) {
+        let cleared = self.clear_kv_cache_seq(Some(seq_id), ..);
+        debug_assert!(cleared, "removing a whole sequence always succeeds");
     }
 
     /// Returns the number of used KV cells (i.e. have at least one sequence assigned to them)
@@ -107,43 +87,26 @@ impl LlamaContext<'_> {
     /// # Parameters
     ///
     /// * `seq_id` - The sequence id to keep
-    pub fn llama_kv_cache_seq_keep(&mut self, seq_id: i32) {
-        unsafe { bitnet_cpp_sys::llama_kv_cache_seq_keep(self.context.as_ptr(), seq_id) }
+    pub fn llama_kv_cache_seq_keep(&mut self, seq_id: SeqId) {
+        unsafe { bitnet_cpp_sys::llama_kv_cache_seq_keep(self.context.as_ptr(), seq_id.0) }
     }
 
     #[allow(clippy::doc_markdown)]
-    /// Adds relative position "delta" to all tokens that belong to the specified sequence and have positions in `[p0, p1)`
+    /// Adds relative position "delta" to all tokens that belong to the specified sequence and have positions in `range`
     /// If the KV cache is RoPEd, the KV data is updated accordingly:
     ///   - lazily on next [`LlamaContext::decode`]
     ///   - explicitly with [`Self::kv_cache_update`]
     ///
-    /// # Returns
-    /// A `Result` indicating whether the operation was successful. If either position
-    /// exceeds the maximum i32 value, no update is attempted and an `Err` is returned.
-    ///
     /// # Parameters
     ///
     /// * `seq_id` - The sequence id to update
-    /// * `p0` - The start position of the cache to update. If `None`, the entire cache is updated up to `p1`.
-    /// * `p1` - The end position of the cache to update. If `None`, the entire cache is updated starting from `p0`.
+    /// * `range` - The positions to update, e.g. `..` for the entire sequence.
     /// * `delta` - The relative position to add to the tokens
-    pub fn kv_cache_seq_add(
-        &mut self,
-        seq_id: i32,
-        p0: Option<u32>,
-        p1: Option<u32>,
-        delta: i32,
-    ) -> Result<(), KvCacheConversionError> {
-        let p0 = p0
-            .map_or(Ok(-1), i32::try_from)
-            .map_err(|e| KvCacheConversionError::P0TooLarge(e))?;
-        let p1 = p1
-            .map_or(Ok(-1), i32::try_from)
-            .map_err(|e| KvCacheConversionError::P1TooLarge(e))?;
+    pub fn kv_cache_seq_add(&mut self, seq_id: SeqId, range: impl RangeBounds<Pos>, delta: i32) {
+        let (p0, p1) = to_p0_p1(&range);
         unsafe {
-            bitnet_cpp_sys::llama_kv_cache_seq_add(self.context.as_ptr(), seq_id, p0, p1, delta);
+            bitnet_cpp_sys::llama_kv_cache_seq_add(self.context.as_ptr(), seq_id.0, p0, p1, delta);
         }
-        Ok(())
     }
 
     /// Integer division of the positions by factor of `d > 1`
@@ -151,42 +114,30 @@ impl LlamaContext<'_> {
     ///   - lazily on next [`LlamaContext::decode`]
     ///   - explicitly with [`Self::kv_cache_update`]
     ///
-    /// # Returns
-    /// A `Result` indicating whether the operation was successful. If either position
-    /// exceeds the maximum i32 value, no update is attempted and an `Err` is returned.
-    ///
     /// # Parameters
     ///
     /// * `seq_id` - The sequence id to update
-    /// * `p0` - The start position of the cache to update. If `None`, the entire cache is updated up to `p1`.
-    /// * `p1` - The end position of the cache to update. If `None`, the entire cache is updated starting from `p0`.
+    /// * `range` - The positions to update, e.g. `..` for the entire sequence.
     /// * `d` - The factor to divide the positions by
-    pub fn kv_cache_seq_div(
-        &mut self,
-        seq_id: i32,
-        p0: Option<u32>,
-        p1: Option<u32>,
-        d: NonZeroU8,
-    ) -> Result<(), KvCacheConversionError> {
-        let p0 = p0
-            .map_or(Ok(-1), i32::try_from)
-            .map_err(|e| KvCacheConversionError::P0TooLarge(e))?;
-        let p1 = p1
-            .map_or(Ok(-1), i32::try_from)
-            .map_err(|e| KvCacheConversionError::P1TooLarge(e))?;
+    pub fn kv_cache_seq_div(&mut self, seq_id: SeqId, range: impl RangeBounds<Pos>, d: NonZeroU8) {
+        let (p0, p1) = to_p0_p1(&range);
         let d = c_int::from(d.get());
-        unsafe { bitnet_cpp_sys::llama_kv_cache_seq_div(self.context.as_ptr(), seq_id, p0, p1, d) }
-        Ok(())
+        unsafe {
+            bitnet_cpp_sys::llama_kv_cache_seq_div(self.context.as_ptr(), seq_id.0, p0, p1, d);
+        }
     }
 
-    /// Returns the largest position present in the KV cache for the specified sequence
+    /// Returns the largest position present in the KV cache for the specified sequence, or `None`
+    /// if the sequence is empty
     ///
     /// # Parameters
     ///
     /// * `seq_id` - The sequence id to get the max position for
     #[must_use]
-    pub fn kv_cache_seq_pos_max(&self, seq_id: i32) -> i32 {
-        unsafe { bitnet_cpp_sys::llama_kv_cache_seq_pos_max(self.context.as_ptr(), seq_id) }
+    pub fn kv_cache_seq_pos_max(&self, seq_id: SeqId) -> Option<Pos> {
+        let pos_max =
+            unsafe { bitnet_cpp_sys::llama_kv_cache_seq_pos_max(self.context.as_ptr(), seq_id.0) };
+        (pos_max >= 0).then_some(Pos(pos_max))
     }
 
     /// Defragment the KV cache
@@ -229,7 +180,7 @@ impl LlamaContext<'_> {
 pub struct KVCacheViewCell {
     /// The position for this cell. Takes KV cache shifts into account.
     /// May be negative if the cell is not populated.
-    pub pos: bitnet_cpp_sys::llama_pos,
+    pub pos: Pos,
 }
 
 /// An updateable view of the KV cache. (use only for debugging purposes)
@@ -293,7 +244,7 @@ impl<'a> KVCacheView<'a> {
             )
         }
         .iter()
-        .map(|&cell| KVCacheViewCell { pos: cell.pos })
+        .map(|&cell| KVCacheViewCell { pos: Pos(cell.pos) })
     }
 
     /// The sequences for each cell. There will be `n_max_seq` items per cell.
@@ -302,10 +253,11 @@ impl<'a> KVCacheView<'a> {
     ///
     /// - if `n_cells * n_max_seq` does not fit into usize.
     /// - if `n_max_seq` does not fit into usize.
-    pub fn cells_sequences(&self) -> impl Iterator<Item = &[bitnet_cpp_sys::llama_seq_id]> {
+    pub fn cells_sequences(&self) -> impl Iterator<Item = &[SeqId]> {
+        // `SeqId` is a transparent wrapper around `llama_seq_id`
         unsafe {
             std::slice::from_raw_parts(
-                self.view.cells_sequences,
+                self.view.cells_sequences.cast::<SeqId>(),
                 usize::try_from(self.view.n_cells * self.view.n_seq_max)
                     .expect("failed to fit n_cells * n_max_seq into usize"),
             )
@@ -321,3 +273,17 @@ impl<'a> Drop for KVCacheView<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_convert_to_half_open_positions() {
+        assert_eq!(to_p0_p1(&(..)), (-1, -1));
+        assert_eq!(to_p0_p1(&(Pos(4)..)), (4, -1));
+        assert_eq!(to_p0_p1(&(..Pos(8))), (-1, 8));
+        assert_eq!(to_p0_p1(&(Pos(4)..Pos(8))), (4, 8));
+        assert_eq!(to_p0_p1(&(Pos(4)..=Pos(8))), (4, 9));
+    }
+}
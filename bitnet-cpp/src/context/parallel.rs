@@ -0,0 +1,440 @@
+//! Decode several independent sequences in one batch.
+//!
+//! A [`ParallelSession`] manages up to [`LlamaContext::n_seq_max`] sequences over one context,
+//! each with its own prompt and [`LlamaSampler`]. Every [`ParallelSession::step`] decodes a
+//! single batch with the pending tokens of all sequences and samples the next token of each
+//! sequence whose prompt is complete. Once a sequence finishes, its kv cache cells are freed and
+//! its id is reused for the next sequence that is added. The sequences share the kv cache, so
+//! each may hold at most `n_ctx / n_seq_max` tokens.
+//!
+//! ```no_run
+//! # use bitnet_cpp::context::LlamaContext;
+//! # use bitnet_cpp::context::parallel::ParallelSession;
+//! # use bitnet_cpp::context::sampler::LlamaSampler;
+//! # use bitnet_cpp::model::AddBos;
+//! # fn example(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! let model = ctx.model;
+//! let mut session = ParallelSession::new(ctx);
+//! for prompt in ["The capital of France is", "Once upon a time"] {
+//!     let tokens = model.str_to_token(prompt, AddBos::Always)?;
+//!     session.add_sequence(&tokens, LlamaSampler::default(), Some(32))?;
+//! }
+//! while !session.is_empty() {
+//!     for sampled in session.step()? {
+//!         println!("{}: {}", sampled.seq_id, sampled.token);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+
+use crate::context::generate::StopReason;
+use crate::context::sampler::LlamaSampler;
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch, Pos, SeqId};
+use crate::token::LlamaToken;
+use crate::DecodeError;
+
+/// Errors that can occur while running a [`ParallelSession`].
+#[derive(Debug, thiserror::Error)]
+pub enum ParallelSessionError {
+    /// All sequences of the context are in use.
+    #[error("all {0} sequences are in use")]
+    NoFreeSequence(u32),
+    /// The prompt did not contain any tokens.
+    #[error("the prompt is empty")]
+    EmptyPrompt,
+    /// The prompt does not fit into the part of the context of one sequence.
+    #[error(
+        "the prompt ({n_prompt} tokens) does not fit into the context of {n_ctx_seq} tokens per sequence"
+    )]
+    PromptTooLong {
+        /// The number of prompt tokens.
+        n_prompt: usize,
+        /// The context size of one sequence, `n_ctx / n_seq_max`.
+        n_ctx_seq: u32,
+    },
+    /// Adding a token to the batch failed.
+    #[error("{0}")]
+    BatchAdd(#[from] BatchAddError),
+    /// Decoding a batch failed.
+    #[error("{0}")]
+    Decode(#[from] DecodeError),
+}
+
+/// A token sampled for one of the sequences of a [`ParallelSession`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParallelToken {
+    /// The sequence the token was sampled for.
+    pub seq_id: SeqId,
+    /// The sampled token.
+    pub token: LlamaToken,
+    /// Why the sequence finished with this token, or `None` if it continues. If the sequence
+    /// ended with an end of generation token, `token` is that token.
+    pub stop_reason: Option<StopReason>,
+}
+
+/// The tokens of a sequence that still have to be decoded.
+#[derive(Debug, Default)]
+struct PendingTokens {
+    /// The rest of the prompt or the last sampled token.
+    tokens: VecDeque<LlamaToken>,
+    /// The position of the first token.
+    n_past: Pos,
+    /// The number of tokens in the current batch. They are only removed once it is decoded.
+    n_batched: usize,
+}
+
+impl PendingTokens {
+    /// Put as many tokens into the current batch as fit into `n_free` places.
+    fn start_batch(&mut self, n_free: usize) -> usize {
+        self.n_batched = self.tokens.len().min(n_free);
+        self.n_batched
+    }
+
+    /// The tokens in the current batch and their positions.
+    fn batched(&self) -> impl Iterator<Item = (LlamaToken, Pos)> + '_ {
+        self.tokens
+            .iter()
+            .copied()
+            .zip((self.n_past.0..).map(Pos))
+            .take(self.n_batched)
+    }
+
+    /// Whether the last pending token is in the current batch.
+    fn is_complete(&self) -> bool {
+        self.n_batched > 0 && self.n_batched == self.tokens.len()
+    }
+
+    /// Remove the tokens of the decoded batch.
+    fn commit(&mut self) {
+        self.tokens.drain(..self.n_batched);
+        self.n_past += i32::try_from(self.n_batched).expect("a batch fits into a i32");
+        self.n_batched = 0;
+    }
+}
+
+fn check_prompt(n_prompt: usize, n_ctx_seq: u32) -> Result<(), ParallelSessionError> {
+    // leave room for at least one sampled token
+    if u32::try_from(n_prompt).map_or(true, |n_prompt| n_prompt >= n_ctx_seq) {
+        return Err(ParallelSessionError::PromptTooLong {
+            n_prompt,
+            n_ctx_seq,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Sequence {
+    sampler: LlamaSampler,
+    pending: PendingTokens,
+    /// The index of the logits of the last pending token in the current batch.
+    i_batch: Option<i32>,
+    n_generated: u32,
+    max_tokens: Option<u32>,
+}
+
+impl Sequence {
+    /// Whether the prompt has been decoded and the pending token is the last sampled one.
+    fn is_generating(&self) -> bool {
+        self.n_generated > 0
+    }
+
+    /// Add up to `n_free` pending tokens to `batch`. Returns the number of added tokens.
+    fn add_to_batch(
+        &mut self,
+        batch: &mut LlamaBatch,
+        seq_id: SeqId,
+        n_free: usize,
+    ) -> Result<usize, BatchAddError> {
+        self.i_batch = None;
+        let n_batched = self.pending.start_batch(n_free);
+        let is_complete = self.pending.is_complete();
+        for (i, (token, pos)) in self.pending.batched().enumerate() {
+            let is_last = is_complete && i + 1 == n_batched;
+            if is_last {
+                self.i_batch = Some(batch.n_tokens());
+            }
+            batch.add(token, pos, &[seq_id], is_last)?;
+        }
+        Ok(n_batched)
+    }
+}
+
+/// Runs independent sequences side by side in one [`LlamaContext`], see the
+/// [module documentation](self).
+///
+/// The context must be created with
+/// [`LlamaContextParams::with_n_seq_max`](crate::context::params::LlamaContextParams::with_n_seq_max)
+/// set to the number of sequences that should run at the same time.
+pub struct ParallelSession<'ctx, 'model> {
+    ctx: &'ctx mut LlamaContext<'model>,
+    batch: LlamaBatch,
+    n_batch: usize,
+    /// The number of kv cache cells of one sequence.
+    n_ctx_seq: u32,
+    /// Indexed by sequence id, `None` for unused ids.
+    sequences: Vec<Option<Sequence>>,
+}
+
+impl std::fmt::Debug for ParallelSession<'_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ParallelSession")
+            .field("n_batch", &self.n_batch)
+            .field("n_ctx_seq", &self.n_ctx_seq)
+            .field("sequences", &self.sequences)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'ctx, 'model> ParallelSession<'ctx, 'model> {
+    /// Create a session with room for [`LlamaContext::n_seq_max`] sequences.
+    ///
+    /// # Panics
+    ///
+    /// - `n_batch` or `n_seq_max` does not fit into a usize
+    #[must_use]
+    pub fn new(ctx: &'ctx mut LlamaContext<'model>) -> Self {
+        let n_batch = usize::try_from(ctx.n_batch())
+            .expect("n_batch fits into a usize")
+            .max(1);
+        let n_ctx_seq = ctx.n_ctx() / ctx.n_seq_max().max(1);
+        let n_seq_max = usize::try_from(ctx.n_seq_max()).expect("n_seq_max fits into a usize");
+        Self {
+            ctx,
+            batch: LlamaBatch::new(n_batch, 1),
+            n_batch,
+            n_ctx_seq,
+            sequences: (0..n_seq_max).map(|_| None).collect(),
+        }
+    }
+
+    /// Add a sequence that starts with `tokens` and samples with `sampler`. The prompt is decoded
+    /// by the following calls to [`Self::step`]. `max_tokens` limits the number of sampled
+    /// tokens, `None` samples until the model ends the generation or the context is full.
+    ///
+    /// # Errors
+    ///
+    /// See [`ParallelSessionError`] for more information.
+    ///
+    /// # Panics
+    ///
+    /// - the id of the sequence does not fit into a [`SeqId`]
+    pub fn add_sequence(
+        &mut self,
+        tokens: &[LlamaToken],
+        sampler: LlamaSampler,
+        max_tokens: Option<u32>,
+    ) -> Result<SeqId, ParallelSessionError> {
+        if tokens.is_empty() {
+            return Err(ParallelSessionError::EmptyPrompt);
+        }
+        check_prompt(tokens.len(), self.n_ctx_seq)?;
+        let Some(index) = self.sequences.iter().position(Option::is_none) else {
+            return Err(ParallelSessionError::NoFreeSequence(self.ctx.n_seq_max()));
+        };
+
+        let seq_id = SeqId(i32::try_from(index).expect("sequence id fits into a i32"));
+        self.ctx.clear_kv_cache_seq_all(seq_id);
+        self.sequences[index] = Some(Sequence {
+            sampler,
+            pending: PendingTokens {
+                tokens: tokens.iter().copied().collect(),
+                ..PendingTokens::default()
+            },
+            i_batch: None,
+            n_generated: 0,
+            max_tokens,
+        });
+        Ok(seq_id)
+    }
+
+    /// Decode one batch and sample the next token of every sequence whose prompt has been
+    /// decoded completely. The batch first gets the sampled token of every generating sequence,
+    /// like the llama.cpp server does, so a long prompt never stalls them. The remaining space is
+    /// filled with prompt tokens in the order of the sequence ids, prompts that do not fit are
+    /// decoded over several steps.
+    ///
+    /// Sequences that finish are removed from the session and their kv cache cells are freed.
+    /// Returns an empty [`Vec`] once there are no sequences left.
+    ///
+    /// # Errors
+    ///
+    /// See [`ParallelSessionError`] for more information. The sequences are unchanged after an
+    /// error, so the step can be retried, e.g. after finishing a sequence to free kv cache cells.
+    /// A sequence whose partially decoded tokens can not be removed from the kv cache is removed
+    /// from the session instead.
+    pub fn step(&mut self) -> Result<Vec<ParallelToken>, ParallelSessionError> {
+        self.batch.clear();
+        let mut n_tokens = 0;
+        // the sampled tokens first, then the prompts
+        for generating in [true, false] {
+            for (seq_id, sequence) in (0..).map(SeqId).zip(&mut self.sequences) {
+                let Some(sequence) = sequence else {
+                    continue;
+                };
+                if sequence.is_generating() != generating {
+                    continue;
+                }
+                n_tokens +=
+                    sequence.add_to_batch(&mut self.batch, seq_id, self.n_batch - n_tokens)?;
+            }
+        }
+        if n_tokens == 0 {
+            return Ok(Vec::new());
+        }
+        if let Err(error) = self.ctx.decode(&mut self.batch) {
+            // the batch may have been decoded partially, drop what it left in the kv cache
+            for (seq_id, sequence) in (0..).map(SeqId).zip(&mut self.sequences) {
+                let Some(pending) = sequence.as_ref().map(|sequence| &sequence.pending) else {
+                    continue;
+                };
+                if !self.ctx.clear_kv_cache_seq(Some(seq_id), pending.n_past..) {
+                    // a sequence that can not be rolled back can not be continued either
+                    self.ctx.clear_kv_cache_seq_all(seq_id);
+                    *sequence = None;
+                }
+            }
+            return Err(error.into());
+        }
+
+        let mut sampled = Vec::new();
+        for (seq_id, index) in (0..).map(SeqId).zip(0..self.sequences.len()) {
+            let Some(sequence) = &mut self.sequences[index] else {
+                continue;
+            };
+            sequence.pending.commit();
+            let Some(i_batch) = sequence.i_batch.take() else {
+                continue;
+            };
+            let token = sequence.sampler.sample(self.ctx, i_batch);
+            let stop_reason = if self.ctx.model.is_eog_token(token) {
+                Some(StopReason::EndOfGeneration(token))
+            } else {
+                sequence.n_generated += 1;
+                if sequence
+                    .max_tokens
+                    .is_some_and(|max_tokens| sequence.n_generated >= max_tokens)
+                {
+                    Some(StopReason::MaxTokens)
+                } else if u32::try_from(sequence.pending.n_past.0)
+                    .is_ok_and(|n_past| n_past >= self.n_ctx_seq)
+                {
+                    Some(StopReason::ContextFull)
+                } else {
+                    sequence.pending.tokens.push_back(token);
+                    None
+                }
+            };
+            if stop_reason.is_some() {
+                self.finish(seq_id);
+            }
+            sampled.push(ParallelToken {
+                seq_id,
+                token,
+                stop_reason,
+            });
+        }
+        Ok(sampled)
+    }
+
+    /// Remove a sequence before it finished on its own and free its kv cache cells. Returns
+    /// `false` if there is no such sequence.
+    pub fn finish(&mut self, seq_id: SeqId) -> bool {
+        let sequence = usize::try_from(seq_id.0)
+            .ok()
+            .and_then(|index| self.sequences.get_mut(index))
+            .and_then(Option::take);
+        if sequence.is_some() {
+            self.ctx.clear_kv_cache_seq_all(seq_id);
+        }
+        sequence.is_some()
+    }
+
+    /// The position the next token of `seq_id` will be placed at, or `None` if there is no such
+    /// sequence.
+    #[must_use]
+    pub fn n_past(&self, seq_id: SeqId) -> Option<Pos> {
+        self.sequence(seq_id)
+            .map(|sequence| sequence.pending.n_past)
+    }
+
+    /// The number of tokens sampled for `seq_id` so far, or `None` if there is no such sequence.
+    #[must_use]
+    pub fn n_generated(&self, seq_id: SeqId) -> Option<u32> {
+        self.sequence(seq_id).map(|sequence| sequence.n_generated)
+    }
+
+    /// The number of sequences that have not finished yet.
+    #[must_use]
+    pub fn n_active(&self) -> usize {
+        self.sequences.iter().flatten().count()
+    }
+
+    /// Whether all sequences have finished.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.n_active() == 0
+    }
+
+    fn sequence(&self, seq_id: SeqId) -> Option<&Sequence> {
+        usize::try_from(seq_id.0)
+            .ok()
+            .and_then(|index| self.sequences.get(index))
+            .and_then(Option::as_ref)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budgets_the_context_per_sequence() {
+        assert!(check_prompt(1023, 1024).is_ok());
+        assert!(matches!(
+            check_prompt(1024, 1024),
+            Err(ParallelSessionError::PromptTooLong {
+                n_prompt: 1024,
+                n_ctx_seq: 1024
+            })
+        ));
+    }
+
+    #[test]
+    fn removes_pending_tokens_once_decoded() {
+        let mut pending = PendingTokens {
+            tokens: (1..=5).map(LlamaToken).collect(),
+            ..PendingTokens::default()
+        };
+        assert_eq!(pending.start_batch(3), 3);
+        assert!(!pending.is_complete());
+        let batched: Vec<_> = pending.batched().collect();
+        assert_eq!(
+            batched,
+            [
+                (LlamaToken(1), Pos(0)),
+                (LlamaToken(2), Pos(1)),
+                (LlamaToken(3), Pos(2))
+            ]
+        );
+
+        // a failed decode does not commit, the next batch starts at the same token
+        assert_eq!(pending.start_batch(8), 5);
+        assert!(pending.is_complete());
+        assert_eq!(pending.batched().next(), Some((LlamaToken(1), Pos(0))));
+
+        pending.start_batch(3);
+        pending.commit();
+        assert_eq!(pending.n_past, Pos(3));
+        assert_eq!(pending.start_batch(8), 2);
+        assert!(pending.is_complete());
+        assert_eq!(pending.batched().next(), Some((LlamaToken(4), Pos(3))));
+        pending.commit();
+        assert!(pending.tokens.is_empty());
+        assert_eq!(pending.n_past, Pos(5));
+    }
+}
@@ -0,0 +1,182 @@
+//! Reuse the kv cache of a shared prompt prefix across requests.
+//!
+//! A [`PrefixCache`] remembers which tokens are in the kv cache of each sequence. For a new
+//! prompt, [`PrefixCache::prepare`] keeps the longest common prefix, removes the cells after it
+//! and returns the position to continue at, so only the rest of the prompt has to be decoded. If
+//! another sequence holds a longer matching prefix (e.g. the same system prompt), it is copied
+//! with [`LlamaContext::copy_kv_cache_seq`], which shares the cells instead of decoding them
+//! again.
+//!
+//! ```no_run
+//! # use bitnet_cpp::context::LlamaContext;
+//! # use bitnet_cpp::context::generate::GenerateParams;
+//! # use bitnet_cpp::context::prefix_cache::PrefixCache;
+//! # use bitnet_cpp::context::sampler::LlamaSampler;
+//! # use bitnet_cpp::llama_batch::SeqId;
+//! # use bitnet_cpp::model::AddBos;
+//! # fn example(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! let mut cache = PrefixCache::default();
+//! let sampler = LlamaSampler::default();
+//! for question in ["What is BitNet?", "What is a ternary weight?"] {
+//!     let prompt = format!("You are a helpful assistant.\nUser: {question}\nAssistant:");
+//!     let tokens = ctx.model.str_to_token(&prompt, AddBos::Always)?;
+//!     let seq_id = SeqId(0);
+//!     let n_past = cache.prepare(ctx, seq_id, &tokens);
+//!     let n_reused = usize::try_from(n_past.0)?;
+//!     let params = GenerateParams::default().with_seq_id(seq_id).with_n_past(n_past);
+//!     let mut generated = Vec::new();
+//!     for token in ctx.generate_from_tokens(&tokens[n_reused..], &sampler, params)? {
+//!         generated.push(token?.token);
+//!     }
+//!     cache.extend(seq_id, generated);
+//!     // the last token might not have been decoded, e.g. an end of generation token
+//!     cache.sync(ctx, seq_id);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The cache assumes that the token at index `i` of a sequence is at position `i`, so it can
+//! not be combined with [`ContextShift`](crate::context::shift::ContextShift) or
+//! [`SelfExtend`](crate::context::self_extend::SelfExtend).
+
+use std::collections::BTreeMap;
+
+use crate::context::LlamaContext;
+use crate::llama_batch::{Pos, SeqId};
+use crate::token::LlamaToken;
+
+/// The tokens in the kv cache of each sequence, see the [module documentation](self).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrefixCache {
+    sequences: BTreeMap<SeqId, Vec<LlamaToken>>,
+}
+
+impl PrefixCache {
+    /// The tokens recorded for `seq_id`.
+    #[must_use]
+    pub fn tokens(&self, seq_id: SeqId) -> &[LlamaToken] {
+        self.sequences.get(&seq_id).map_or(&[], Vec::as_slice)
+    }
+
+    /// The number of leading tokens of `prompt` that are in the kv cache of `seq_id`.
+    #[must_use]
+    pub fn common_prefix_len(&self, seq_id: SeqId, prompt: &[LlamaToken]) -> usize {
+        common_prefix_len(self.tokens(seq_id), prompt)
+    }
+
+    /// Make the kv cache of `seq_id` hold the longest available prefix of `prompt` and nothing
+    /// else. Returns the position of the first token of `prompt` that still has to be decoded,
+    /// which is also the number of reused tokens.
+    ///
+    /// At least the last token of `prompt` is always left to decode, so that its logits are
+    /// computed. The whole `prompt` is recorded for `seq_id`, assuming it is decoded next. If the
+    /// cells after the prefix can not be removed, the whole sequence is cleared and nothing is
+    /// reused.
+    ///
+    /// # Panics
+    ///
+    /// - the length of the prefix does not fit into a [`Pos`]
+    pub fn prepare(&mut self, ctx: &mut LlamaContext, seq_id: SeqId, prompt: &[LlamaToken]) -> Pos {
+        let (source, n_reused) = self.best_prefix(seq_id, prompt);
+        let mut n_past = Pos(i32::try_from(n_reused).expect("prefix length fits into a i32"));
+        if source != seq_id {
+            ctx.clear_kv_cache_seq_all(seq_id);
+            ctx.copy_kv_cache_seq(source, seq_id, ..n_past);
+        } else if !ctx.clear_kv_cache_seq(Some(seq_id), n_past..) {
+            ctx.clear_kv_cache_seq_all(seq_id);
+            n_past = Pos(0);
+        }
+        self.sequences.insert(seq_id, prompt.to_vec());
+        n_past
+    }
+
+    /// Copy the first `n_tokens` cached tokens of `src` into `dest`, replacing the kv cache of
+    /// `dest`. Returns the number of copied tokens, which is less than `n_tokens` if `src` is
+    /// shorter.
+    ///
+    /// # Panics
+    ///
+    /// - the number of copied tokens does not fit into a [`Pos`]
+    pub fn copy_prefix(
+        &mut self,
+        ctx: &mut LlamaContext,
+        src: SeqId,
+        dest: SeqId,
+        n_tokens: usize,
+    ) -> usize {
+        let prefix: Vec<_> = self.tokens(src).iter().copied().take(n_tokens).collect();
+        let n_copied = prefix.len();
+        let end = Pos(i32::try_from(n_copied).expect("prefix length fits into a i32"));
+        ctx.clear_kv_cache_seq_all(dest);
+        ctx.copy_kv_cache_seq(src, dest, ..end);
+        self.sequences.insert(dest, prefix);
+        n_copied
+    }
+
+    /// Record that `tokens` were decoded at the end of `seq_id`.
+    pub fn extend(&mut self, seq_id: SeqId, tokens: impl IntoIterator<Item = LlamaToken>) {
+        self.sequences.entry(seq_id).or_default().extend(tokens);
+    }
+
+    /// Drop recorded tokens of `seq_id` that are not in the kv cache, e.g. because they were
+    /// never decoded or decoding failed.
+    pub fn sync(&mut self, ctx: &LlamaContext, seq_id: SeqId) {
+        let n_cached = ctx
+            .kv_cache_seq_pos_max(seq_id)
+            .map_or(0, |Pos(pos_max)| usize::try_from(pos_max + 1).unwrap_or(0));
+        if let Some(tokens) = self.sequences.get_mut(&seq_id) {
+            tokens.truncate(n_cached);
+        }
+    }
+
+    /// Forget `seq_id` and clear its kv cache.
+    pub fn remove(&mut self, ctx: &mut LlamaContext, seq_id: SeqId) {
+        self.sequences.remove(&seq_id);
+        ctx.clear_kv_cache_seq_all(seq_id);
+    }
+
+    /// The sequence with the longest usable prefix of `prompt`, preferring `seq_id` itself.
+    fn best_prefix(&self, seq_id: SeqId, prompt: &[LlamaToken]) -> (SeqId, usize) {
+        let max_len = prompt.len().saturating_sub(1);
+        let own = self.common_prefix_len(seq_id, prompt).min(max_len);
+        self.sequences
+            .iter()
+            .map(|(&source, tokens)| (source, common_prefix_len(tokens, prompt).min(max_len)))
+            .filter(|&(_, len)| len > own)
+            .max_by_key(|&(source, len)| (len, std::cmp::Reverse(source)))
+            .unwrap_or((seq_id, own))
+    }
+}
+
+fn common_prefix_len(a: &[LlamaToken], b: &[LlamaToken]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(ids: &[i32]) -> Vec<LlamaToken> {
+        ids.iter().copied().map(LlamaToken).collect()
+    }
+
+    #[test]
+    fn prefers_the_longest_prefix() {
+        let mut cache = PrefixCache::default();
+        cache.extend(SeqId(0), tokens(&[1, 2, 3]));
+        cache.extend(SeqId(1), tokens(&[1, 2, 3, 4, 5]));
+        cache.extend(SeqId(2), tokens(&[1, 2, 3, 4, 5]));
+
+        assert_eq!(
+            cache.best_prefix(SeqId(0), &tokens(&[1, 2, 3, 4, 9])),
+            (SeqId(1), 4)
+        );
+        // never reuse the last prompt token, its logits are needed
+        assert_eq!(
+            cache.best_prefix(SeqId(0), &tokens(&[1, 2, 3])),
+            (SeqId(0), 2)
+        );
+        assert_eq!(cache.best_prefix(SeqId(3), &tokens(&[7, 8])), (SeqId(3), 0));
+    }
+}
@@ -1,37 +1,113 @@
 //! Sampler implementation for llama.cpp
 //!
 use std::{
+    cell::RefCell,
+    ffi::{CStr, CString, NulError},
     fmt::{Debug, Formatter},
     ptr::NonNull,
+    str::FromStr,
 };
 
 use bitnet_cpp_sys::{
-    common::common_sampler_params, llama_sampler_chain_add, llama_sampler_chain_default_params,
-    llama_sampler_chain_init, llama_sampler_chain_params, llama_sampler_init_dist,
+    common::{
+        common_sampler_params, common_sampler_type, COMMON_SAMPLER_TYPE_DRY,
+        COMMON_SAMPLER_TYPE_MIN_P, COMMON_SAMPLER_TYPE_TEMPERATURE, COMMON_SAMPLER_TYPE_TFS_Z,
+        COMMON_SAMPLER_TYPE_TOP_K, COMMON_SAMPLER_TYPE_TOP_P, COMMON_SAMPLER_TYPE_TYPICAL_P,
+        COMMON_SAMPLER_TYPE_XTC,
+    },
+    llama_logit_bias, llama_sampler_accept, llama_sampler_apply, llama_sampler_chain_add,
+    llama_sampler_chain_default_params, llama_sampler_chain_get, llama_sampler_chain_init,
+    llama_sampler_chain_n, llama_sampler_chain_params, llama_sampler_clone, llama_sampler_free,
+    llama_sampler_init_dist, llama_sampler_init_grammar, llama_sampler_init_logit_bias,
     llama_sampler_init_min_p, llama_sampler_init_mirostat, llama_sampler_init_mirostat_v2,
     llama_sampler_init_penalties, llama_sampler_init_tail_free, llama_sampler_init_temp,
     llama_sampler_init_temp_ext, llama_sampler_init_top_k, llama_sampler_init_top_p,
-    llama_sampler_init_typical, /*llama_sampler_init_xtc,*/ llama_sampler_sample, llama_token,
+    llama_sampler_init_typical, llama_sampler_name, llama_sampler_reset, llama_sampler_sample,
 };
 
+use crate::grammar::{GrammarParseError, LlamaGrammar};
+use crate::model::LlamaModel;
+use crate::token::data::LlamaTokenData;
+use crate::token::data_array::LlamaTokenDataArray;
 use crate::token::LlamaToken;
 
 use super::LlamaContext;
 
+pub mod config;
+pub mod dry;
+pub mod logit_bias;
+pub mod stage;
+pub mod xtc;
+
+use config::{SamplerConfig, SamplerStageConfig};
+use dry::Dry;
+use logit_bias::LogitBias;
+use stage::SamplerStage;
+use xtc::Xtc;
+
+/// Failed to add a grammar stage to a [`LlamaSampler`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SamplerGrammarError {
+    /// The grammar is not valid GBNF.
+    #[error(transparent)]
+    ParseError(#[from] GrammarParseError),
+    /// The grammar does not define the requested root rule.
+    #[error("the grammar has no rule named {0:?}")]
+    MissingRoot(String),
+    /// The grammar or root contained a null byte and thus could not be converted to a C string.
+    #[error("null byte in string {0}")]
+    NullError(#[from] NulError),
+    /// llama.cpp returned null (e.g. the grammar is left recursive).
+    #[error("null result from llama cpp")]
+    NullResult,
+}
+
+/// Failed to build a [`LlamaSampler`] with [`LlamaSampler::from_common_params`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum CommonSamplerParamsError {
+    /// The grammar stage could not be added.
+    #[error(transparent)]
+    Grammar(#[from] SamplerGrammarError),
+    /// `params.samplers` contains a type without a sampler in the vendored llama.cpp, e.g.
+    /// `COMMON_SAMPLER_TYPE_INFILL`.
+    #[error("unsupported sampler type {0}")]
+    UnsupportedSampler(common_sampler_type),
+}
+
+/// A token picked by [`LlamaSampler::sample_with_probs`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampledToken {
+    /// the sampled token
+    pub token: LlamaToken,
+    /// the probability of `token` after all stages of the chain ran
+    pub p: f32,
+    /// the natural logarithm of `p`
+    pub logprob: f32,
+    /// the most likely candidates after all stages of the chain ran, most likely first. `p()`
+    /// holds their probability.
+    pub top: Vec<LlamaTokenData>,
+}
+
 /// Safe wrapper around `llama_sampler`.
 ///
 /// Original PR for the Sampler in llama.cpp
 ///
 /// https://github.com/ggerganov/llama.cpp/pull/9294
+///
+/// The sampler owns the underlying chain and every stage added to it; they are freed on drop.
 #[allow(clippy::module_name_repetitions)]
 pub struct LlamaSampler {
     pub(crate) sampler: NonNull<bitnet_cpp_sys::llama_sampler>,
+    no_perf: Option<bool>,
+    stages: RefCell<Vec<SamplerStageConfig>>,
 }
 
 impl Debug for LlamaSampler {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         f.debug_struct("LlamaSampler")
             .field("sampler", &self.sampler)
+            .field("no_perf", &self.no_perf)
+            .field("stages", &self.stages.borrow())
             .finish()
     }
 }
@@ -105,166 +181,610 @@ impl LlamaSampler {
 
         Self {
             sampler: NonNull::new(unsafe { llama_sampler_chain_init(sparams) }).unwrap(),
+            no_perf,
+            stages: RefCell::new(Vec::new()),
         }
     }
 
+    /// Add `stage` to the end of the chain, which takes ownership of it, and record `config`.
+    fn add(&self, stage: *mut bitnet_cpp_sys::llama_sampler, config: SamplerStageConfig) -> &Self {
+        unsafe {
+            llama_sampler_chain_add(self.sampler.as_ptr(), stage);
+        };
+        self.stages.borrow_mut().push(config);
+
+        self
+    }
+
+    /// Top-p with a configurable `min_keep`, which [`Self::with_top_p`] fixes to `1`.
+    pub(crate) fn add_top_p(&self, p: f32, min_keep: usize) -> &Self {
+        self.add(
+            unsafe { llama_sampler_init_top_p(p, min_keep) },
+            SamplerStageConfig::TopP { p, min_keep },
+        )
+    }
+
+    /// The names of the stages of the chain, as reported by llama.cpp.
+    #[must_use]
+    pub fn stage_names(&self) -> Vec<String> {
+        let n = unsafe { llama_sampler_chain_n(self.sampler.as_ptr()) };
+        (0..n)
+            .map(|i| unsafe {
+                let stage = llama_sampler_chain_get(self.sampler.as_ptr(), i);
+                CStr::from_ptr(llama_sampler_name(stage))
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect()
+    }
+
+    /// The stages of the chain and their parameters, see [`SamplerConfig`].
+    #[must_use]
+    pub fn config(&self) -> SamplerConfig {
+        SamplerConfig {
+            no_perf: self.no_perf,
+            stages: self.stages.borrow().clone(),
+        }
+    }
+
+    /// Build the sampler chain described by `params`, like `common_sampler_init` in llama.cpp.
+    ///
+    /// The chain starts with the grammar (if any), the logit biases and the penalties. With
+    /// `mirostat == 0` the stages listed in `params.samplers` follow in that order, and the token
+    /// is picked from the resulting distribution using `params.seed`. With `mirostat == 1` or `2`
+    /// the stages are replaced by a temperature stage and the respective mirostat sampler.
+    ///
+    /// As in llama.cpp, a `penalty_last_n` of `-1` uses `n_ctx`, the size of the context the
+    /// sampler is used with, and a `dry_penalty_last_n` of `-1` the training context size of
+    /// `model`. The DRY stage is only added if `dry_multiplier` is not zero.
+    ///
+    /// # Errors
+    ///
+    /// - `params.grammar` is not a valid grammar, see [`SamplerGrammarError`]
+    /// - `params.samplers` contains `COMMON_SAMPLER_TYPE_INFILL`, which the vendored llama.cpp
+    ///   has no sampler for, or an unknown type
+    ///
+    /// # Safety
+    ///
+    /// If `params.grammar` is set, the sampler keeps a reference to the vocabulary of `model`, so
+    /// it must be dropped before `model`. See [`Self::with_grammar`].
+    ///
+    /// ```no_run
+    /// # use bitnet_cpp::context::LlamaContext;
+    /// # use bitnet_cpp::context::sampler::LlamaSampler;
+    /// # use bitnet_cpp_sys::common::common_sampler_params;
+    /// # fn example(ctx: &LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+    /// let params = common_sampler_params {
+    ///     temp: 0.6,
+    ///     penalty_repeat: 1.1,
+    ///     ..common_sampler_params::default()
+    /// };
+    /// // SAFETY: the sampler is dropped before the model
+    /// let sampler = unsafe { LlamaSampler::from_common_params(ctx.model, ctx.n_ctx(), &params)? };
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub unsafe fn from_common_params(
+        model: &LlamaModel,
+        n_ctx: u32,
+        params: &common_sampler_params,
+    ) -> Result<Self, CommonSamplerParamsError> {
+        let sampler = Self::new(Some(params.no_perf));
+
+        let grammar = params.grammar.join("\n");
+        if !grammar.trim().is_empty() {
+            // SAFETY: the caller keeps `model` alive for as long as the sampler
+            unsafe { sampler.with_grammar(model, &grammar, "root")? };
+        }
+
+        let logit_bias = params
+            .logit_bias
+            .iter()
+            .map(|&(token, bias)| {
+                #[allow(clippy::cast_possible_truncation)]
+                (LlamaToken::new(token), bias as f32)
+            })
+            .collect::<LogitBias>();
+        if !logit_bias.is_empty() {
+            sampler.with_logit_bias(model, &logit_bias);
+        }
+
+        let penalty_last_n = if params.penalty_last_n == -1 {
+            i32::try_from(n_ctx).unwrap_or(i32::MAX)
+        } else {
+            params.penalty_last_n
+        };
+        sampler.with_penalties(
+            model,
+            penalty_last_n,
+            params.penalty_repeat,
+            params.penalty_freq,
+            params.penalty_present,
+            params.penalize_nl,
+            params.ignore_eos,
+        );
+
+        match params.mirostat {
+            1 => {
+                sampler.with_temp(params.temp).with_mirostat(
+                    model.n_vocab(),
+                    params.seed,
+                    params.mirostat_tau,
+                    params.mirostat_eta,
+                    100,
+                );
+            }
+            2 => {
+                sampler.with_temp(params.temp).with_mirostat_v2(
+                    params.seed,
+                    params.mirostat_tau,
+                    params.mirostat_eta,
+                );
+            }
+            _ => {
+                sampler.with_common_samplers(model, params)?;
+                sampler.with_seed(params.seed);
+            }
+        }
+
+        Ok(sampler)
+    }
+
+    /// Add the stages of `params.samplers` in order, see [`Self::from_common_params`].
+    fn with_common_samplers(
+        &self,
+        model: &LlamaModel,
+        params: &common_sampler_params,
+    ) -> Result<&Self, CommonSamplerParamsError> {
+        let min_keep = usize::try_from(params.min_keep).unwrap_or(0);
+        for &sampler_type in &params.samplers {
+            match sampler_type {
+                COMMON_SAMPLER_TYPE_DRY if params.dry_multiplier == 0.0 => self,
+                COMMON_SAMPLER_TYPE_DRY => self.with_dry(
+                    model,
+                    params.dry_multiplier,
+                    params.dry_base,
+                    params.dry_allowed_length,
+                    params.dry_penalty_last_n,
+                    &params.dry_sequence_breakers,
+                ),
+                COMMON_SAMPLER_TYPE_TOP_K => self.with_top_k(params.top_k),
+                COMMON_SAMPLER_TYPE_TOP_P => self.add_top_p(params.top_p, min_keep),
+                COMMON_SAMPLER_TYPE_MIN_P => self.with_min_p(params.min_p, min_keep),
+                COMMON_SAMPLER_TYPE_TFS_Z => self.with_tail_free(params.tfs_z, min_keep),
+                COMMON_SAMPLER_TYPE_TYPICAL_P => self.with_typical(params.typ_p, min_keep),
+                COMMON_SAMPLER_TYPE_XTC => self.with_xtc(
+                    params.xtc_probability,
+                    params.xtc_threshold,
+                    min_keep,
+                    params.seed,
+                ),
+                COMMON_SAMPLER_TYPE_TEMPERATURE => {
+                    self.with_temp_ext(params.temp, params.dynatemp_range, params.dynatemp_exponent)
+                }
+                _ => return Err(CommonSamplerParamsError::UnsupportedSampler(sampler_type)),
+            };
+        }
+
+        Ok(self)
+    }
+
     /// sample next token
+    ///
+    /// The sampled token is accepted by every stage of the chain, so there is no need to call
+    /// [`Self::accept`] for it.
     pub fn sample(&self, ctx: &LlamaContext, idx: i32) -> LlamaToken {
         let token_id =
             unsafe { llama_sampler_sample(self.sampler.as_ptr(), ctx.context.as_ptr(), idx) };
         LlamaToken::new(token_id)
     }
 
+    /// Run every stage of the chain on `candidates` without accepting a token.
+    ///
+    /// # Panics
+    ///
+    /// - a stage of the chain added candidates
+    pub fn apply(&self, candidates: &mut LlamaTokenDataArray) {
+        candidates.modify_as_c_llama_token_data_array(|array| unsafe {
+            llama_sampler_apply(self.sampler.as_ptr(), array);
+        });
+    }
+
+    /// Sample the next token like [`Self::sample`], and also report its probability and the
+    /// `n_probs` most likely candidates, e.g. to provide `logprobs`.
+    ///
+    /// The probabilities are those of the distribution the token was drawn from, so they reflect
+    /// all stages of the chain: candidates removed by e.g. top-k have no probability.
+    ///
+    /// # Panics
+    ///
+    /// - the logits of `idx` were not computed, see [`LlamaContext::candidates_ith`]
+    /// - the chain did not select a token, i.e. it does not end with a stage such as
+    ///   [`Self::with_seed`]
+    ///
+    /// ```no_run
+    /// # use bitnet_cpp::context::LlamaContext;
+    /// # use bitnet_cpp::context::sampler::LlamaSampler;
+    /// # fn example(ctx: &LlamaContext, sampler: &LlamaSampler, idx: i32) {
+    /// let sampled = sampler.sample_with_probs(ctx, idx, 5);
+    /// println!("{} (logprob {})", sampled.token, sampled.logprob);
+    /// for alternative in &sampled.top {
+    ///     println!("  {} {}", alternative.id(), alternative.p().ln());
+    /// }
+    /// # }
+    /// ```
+    pub fn sample_with_probs(&self, ctx: &LlamaContext, idx: i32, n_probs: usize) -> SampledToken {
+        let mut candidates = LlamaTokenDataArray::from_iter(ctx.candidates_ith(idx), false);
+        self.apply(&mut candidates);
+        let token = candidates
+            .selected_token()
+            .expect("the sampler chain selected a token");
+        self.accept(token);
+
+        candidates.softmax();
+        let p = candidates
+            .data
+            .iter()
+            .find(|candidate| candidate.id() == token)
+            .map_or(0.0, LlamaTokenData::p);
+        candidates.data.truncate(n_probs);
+
+        SampledToken {
+            token,
+            p,
+            logprob: p.ln(),
+            top: candidates.data,
+        }
+    }
+
+    /// Feed `token` to every stage of the chain as if it had been sampled.
+    ///
+    /// Use this for tokens that did not come from [`Self::sample`] (e.g. prompt tokens or tokens
+    /// forced by the caller) so that stateful stages such as penalties, mirostat or a grammar
+    /// take them into account.
+    pub fn accept(&self, token: LlamaToken) {
+        unsafe { llama_sampler_accept(self.sampler.as_ptr(), token.0) }
+    }
+
+    /// Reset the state of every stage of the chain (e.g. the penalty history, the mirostat `mu`
+    /// or the grammar position) so that the sampler can be reused for a new request.
+    pub fn reset(&self) {
+        unsafe { llama_sampler_reset(self.sampler.as_ptr()) }
+    }
+
     #[doc = " @details Top-K sampling described in academic paper \"The Curious Case of Neural Text Degeneration\" https://arxiv.org/abs/1904.09751"]
     pub fn with_top_k(&self, top_k: i32) -> &Self {
-        unsafe {
-            llama_sampler_chain_add(self.sampler.as_ptr(), llama_sampler_init_top_k(top_k));
-        };
-
-        self
+        self.add(
+            unsafe { llama_sampler_init_top_k(top_k) },
+            SamplerStageConfig::TopK { k: top_k },
+        )
     }
 
     #[doc = " @details Nucleus sampling described in academic paper \"The Curious Case of Neural Text Degeneration\" https://arxiv.org/abs/1904.09751"]
     pub fn with_top_p(&self, top_p: f32) -> &Self {
-        unsafe {
-            llama_sampler_chain_add(self.sampler.as_ptr(), llama_sampler_init_top_p(top_p, 1));
-        };
-
-        self
+        self.add_top_p(top_p, 1)
     }
 
     #[doc = " #details Updates the logits l_i` = l_i/t. When t <= 0.0f, the maximum logit is kept at it's original value, the rest are set to -inf"]
     pub fn with_temp(&self, temp: f32) -> &Self {
-        unsafe {
-            llama_sampler_chain_add(self.sampler.as_ptr(), llama_sampler_init_temp(temp));
-        };
-
-        self
+        self.add(
+            unsafe { llama_sampler_init_temp(temp) },
+            SamplerStageConfig::Temp { t: temp },
+        )
     }
 
     #[doc = " @details Dynamic temperature implementation (a.k.a. entropy) described in the paper https://arxiv.org/abs/2309.02772."]
     pub fn with_temp_ext(&self, temp: f32, delta: f32, exponent: f32) -> &Self {
-        unsafe {
-            llama_sampler_chain_add(
-                self.sampler.as_ptr(),
-                llama_sampler_init_temp_ext(temp, delta, exponent),
-            );
-        };
-
-        self
+        self.add(
+            unsafe { llama_sampler_init_temp_ext(temp, delta, exponent) },
+            SamplerStageConfig::TempExt {
+                t: temp,
+                delta,
+                exponent,
+            },
+        )
     }
 
     #[doc = " @details Minimum P sampling as described in https://github.com/ggerganov/llama.cpp/pull/3841"]
     pub fn with_min_p(&self, p: f32, min_keep: usize) -> &Self {
-        unsafe {
-            llama_sampler_chain_add(self.sampler.as_ptr(), llama_sampler_init_min_p(p, min_keep));
-        };
-
-        self
+        self.add(
+            unsafe { llama_sampler_init_min_p(p, min_keep) },
+            SamplerStageConfig::MinP { p, min_keep },
+        )
     }
 
     #[doc = " @details Tail Free Sampling described in https://www.trentonbricken.com/Tail-Free-Sampling/."]
     pub fn with_tail_free(&self, z: f32, min_keep: usize) -> &Self {
-        unsafe {
-            llama_sampler_chain_add(
-                self.sampler.as_ptr(),
-                llama_sampler_init_tail_free(z, min_keep),
-            );
-        };
-
-        self
+        self.add(
+            unsafe { llama_sampler_init_tail_free(z, min_keep) },
+            SamplerStageConfig::TailFree { z, min_keep },
+        )
     }
 
     #[doc = " @details Locally Typical Sampling implementation described in the paper https://arxiv.org/abs/2202.00666."]
     pub fn with_typical(&self, p: f32, min_keep: usize) -> &Self {
-        unsafe {
-            llama_sampler_chain_add(
-                self.sampler.as_ptr(),
-                llama_sampler_init_typical(p, min_keep),
-            );
-        };
-
-        self
+        self.add(
+            unsafe { llama_sampler_init_typical(p, min_keep) },
+            SamplerStageConfig::Typical { p, min_keep },
+        )
     }
 
-    // #[doc = " @details XTC sampler as described in https://github.com/oobabooga/text-generation-webui/pull/6335"]
-    // pub fn with_xtc(&self, p: f32, t: f32, min_keep: usize, seed: u32) -> &Self {
-    //     unsafe {
-    //         llama_sampler_chain_add(
-    //             self.sampler.as_ptr(),
-    //             llama_sampler_init_xtc(p, t, min_keep, seed),
-    //         );
-    //     };
-
-    //     self
-    // }
+    /// XTC sampler as described in <https://github.com/oobabooga/text-generation-webui/pull/6335>,
+    /// see [`Xtc::new`].
+    pub fn with_xtc(&self, p: f32, t: f32, min_keep: usize, seed: u32) -> &Self {
+        self.add(
+            stage::new_stage_sampler(Xtc::new(p, t, min_keep, seed)),
+            SamplerStageConfig::Xtc {
+                probability: p,
+                threshold: t,
+                min_keep,
+                seed,
+            },
+        )
+    }
 
     #[doc = " @details Mirostat 1.0 algorithm described in the paper https://arxiv.org/abs/2007.14966. Uses tokens instead of words.\n @param candidates A vector of `llama_token_data` containing the candidate tokens, their probabilities (p), and log-odds (logit) for the current position in the generated text.\n @param tau  The target cross-entropy (or surprise) value you want to achieve for the generated text. A higher value corresponds to more surprising or less predictable text, while a lower value corresponds to less surprising or more predictable text.\n @param eta The learning rate used to update `mu` based on the error between the target and observed surprisal of the sampled word. A larger learning rate will cause `mu` to be updated more quickly, while a smaller learning rate will result in slower updates.\n @param m The number of tokens considered in the estimation of `s_hat`. This is an arbitrary value that is used to calculate `s_hat`, which in turn helps to calculate the value of `k`. In the paper, they use `m = 100`, but you can experiment with different values to see how it affects the performance of the algorithm.\n @param mu Maximum cross-entropy. This value is initialized to be twice the target cross-entropy (`2 * tau`) and is updated in the algorithm based on the error between the target and observed surprisal."]
     pub fn with_mirostat(&self, n_vocab: i32, seed: u32, tau: f32, eta: f32, m: i32) -> &Self {
-        unsafe {
-            llama_sampler_chain_add(
-                self.sampler.as_ptr(),
-                llama_sampler_init_mirostat(n_vocab, seed, tau, eta, m),
-            );
-        };
-
-        self
+        self.add(
+            unsafe { llama_sampler_init_mirostat(n_vocab, seed, tau, eta, m) },
+            SamplerStageConfig::Mirostat {
+                n_vocab,
+                seed,
+                tau,
+                eta,
+                m,
+            },
+        )
     }
 
     #[doc = " @details Mirostat 2.0 algorithm described in the paper https://arxiv.org/abs/2007.14966. Uses tokens instead of words.\n @param candidates A vector of `llama_token_data` containing the candidate tokens, their probabilities (p), and log-odds (logit) for the current position in the generated text.\n @param tau  The target cross-entropy (or surprise) value you want to achieve for the generated text. A higher value corresponds to more surprising or less predictable text, while a lower value corresponds to less surprising or more predictable text.\n @param eta The learning rate used to update `mu` based on the error between the target and observed surprisal of the sampled word. A larger learning rate will cause `mu` to be updated more quickly, while a smaller learning rate will result in slower updates.\n @param mu Maximum cross-entropy. This value is initialized to be twice the target cross-entropy (`2 * tau`) and is updated in the algorithm based on the error between the target and observed surprisal."]
     pub fn with_mirostat_v2(&self, seed: u32, tau: f32, eta: f32) -> &Self {
-        unsafe {
-            llama_sampler_chain_add(
-                self.sampler.as_ptr(),
-                llama_sampler_init_mirostat_v2(seed, tau, eta),
-            );
+        self.add(
+            unsafe { llama_sampler_init_mirostat_v2(seed, tau, eta) },
+            SamplerStageConfig::MirostatV2 { seed, tau, eta },
+        )
+    }
+
+    /// Constrain sampling to the GBNF grammar `gbnf`, starting at rule `root`.
+    ///
+    /// The grammar is parsed in Rust first so that errors are reported with line and column
+    /// information. Tokens that cannot continue the grammar get their logits set to `-inf`, so
+    /// the stage has to come before the stage that picks the token (e.g. [`Self::with_seed`]).
+    /// The grammar state advances with every sampled token.
+    ///
+    /// # Errors
+    ///
+    /// See [`SamplerGrammarError`] for more information.
+    ///
+    /// # Safety
+    ///
+    /// The grammar stage keeps a reference to the vocabulary of `model`, so the sampler must be
+    /// dropped before `model`.
+    ///
+    /// ```no_run
+    /// # use bitnet_cpp::context::sampler::LlamaSampler;
+    /// # use bitnet_cpp::model::LlamaModel;
+    /// # fn example(model: &LlamaModel) -> Result<(), Box<dyn std::error::Error>> {
+    /// let sampler = LlamaSampler::new(None);
+    /// // SAFETY: the sampler is dropped before the model
+    /// unsafe { sampler.with_grammar(model, r#"root ::= "yes" | "no""#, "root")? }
+    ///     .with_temp(0.8)
+    ///     .with_seed(1234);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub unsafe fn with_grammar(
+        &self,
+        model: &LlamaModel,
+        gbnf: &str,
+        root: &str,
+    ) -> Result<&Self, SamplerGrammarError> {
+        let grammar = LlamaGrammar::from_str(gbnf)?;
+        // SAFETY: forwarded to the caller
+        unsafe { self.with_llama_grammar(model, &grammar, root) }
+    }
+
+    /// Constrain sampling to an already parsed [`LlamaGrammar`], starting at rule `root`.
+    ///
+    /// See [`Self::with_grammar`].
+    ///
+    /// # Errors
+    ///
+    /// See [`SamplerGrammarError`] for more information.
+    ///
+    /// # Safety
+    ///
+    /// The sampler must be dropped before `model`, see [`Self::with_grammar`].
+    pub unsafe fn with_llama_grammar(
+        &self,
+        model: &LlamaModel,
+        grammar: &LlamaGrammar,
+        root: &str,
+    ) -> Result<&Self, SamplerGrammarError> {
+        if !grammar.contains_rule(root) {
+            return Err(SamplerGrammarError::MissingRoot(root.to_owned()));
+        }
+        let grammar_str = CString::new(grammar.as_str())?;
+        let grammar_root = CString::new(root)?;
+
+        let stage = unsafe {
+            llama_sampler_init_grammar(
+                model.model.as_ptr(),
+                grammar_str.as_ptr(),
+                grammar_root.as_ptr(),
+            )
         };
+        let stage = NonNull::new(stage).ok_or(SamplerGrammarError::NullResult)?;
+
+        Ok(self.add(
+            stage.as_ptr(),
+            SamplerStageConfig::Grammar {
+                grammar: grammar.as_str().to_owned(),
+                root: root.to_owned(),
+            },
+        ))
+    }
 
-        self
+    /// Add the biases of `logit_bias` to the logits, see [`LogitBias`].
+    ///
+    /// Banned tokens get a logit of `-inf`, so the stage has to come before the stage that picks
+    /// the token (e.g. [`Self::with_seed`]).
+    ///
+    /// # Panics
+    ///
+    /// - `logit_bias` holds more than [`i32::MAX`] biases
+    pub fn with_logit_bias(&self, model: &LlamaModel, logit_bias: &LogitBias) -> &Self {
+        let biases = logit_bias
+            .biases
+            .iter()
+            .map(|&(token, bias)| llama_logit_bias {
+                token: token.0,
+                bias,
+            })
+            .collect::<Vec<_>>();
+        let n_logit_bias = i32::try_from(biases.len()).expect("logit bias count fits into an i32");
+
+        self.add(
+            unsafe {
+                llama_sampler_init_logit_bias(model.n_vocab(), n_logit_bias, biases.as_ptr())
+            },
+            SamplerStageConfig::logit_bias(logit_bias),
+        )
+    }
+
+    /// Add a sampler stage implemented in Rust, see [`SamplerStage`].
+    ///
+    /// The stage is recorded as [`SamplerStageConfig::Custom`] in [`Self::config`].
+    pub fn with_stage<S: SamplerStage>(&self, stage: S) -> &Self {
+        let name = stage.name().to_owned();
+        self.add(
+            stage::new_stage_sampler(stage),
+            SamplerStageConfig::Custom { name },
+        )
+    }
+
+    /// Add a DRY ("Don't Repeat Yourself") repetition penalty, see [`Dry::new`].
+    ///
+    /// The sequence breakers are mapped to token sequences by scanning the vocabulary of
+    /// `model`, which takes a moment for large vocabularies. The stage keeps its own copy of
+    /// what it needs, so unlike [`Self::with_grammar`] the sampler may outlive `model`.
+    pub fn with_dry<S: AsRef<str>>(
+        &self,
+        model: &LlamaModel,
+        multiplier: f32,
+        base: f32,
+        allowed_length: i32,
+        penalty_last_n: i32,
+        sequence_breakers: &[S],
+    ) -> &Self {
+        self.add(
+            stage::new_stage_sampler(Dry::new(
+                model,
+                multiplier,
+                base,
+                allowed_length,
+                penalty_last_n,
+                sequence_breakers,
+            )),
+            SamplerStageConfig::Dry {
+                multiplier,
+                base,
+                allowed_length,
+                penalty_last_n,
+                sequence_breakers: sequence_breakers
+                    .iter()
+                    .map(|breaker| breaker.as_ref().to_owned())
+                    .collect(),
+            },
+        )
     }
 
     /// init seed distribution
     pub fn with_seed(&self, seed: u32) -> &Self {
-        unsafe {
-            llama_sampler_chain_add(self.sampler.as_ptr(), llama_sampler_init_dist(seed));
-        };
-
-        self
+        self.add(
+            unsafe { llama_sampler_init_dist(seed) },
+            SamplerStageConfig::Dist { seed },
+        )
     }
 
-    /// init with penalties sampler
+    /// Penalize tokens that already occur in the last `penalty_last_n` accepted tokens.
+    ///
+    /// * `model` - provides the vocabulary size and the end of stream and newline tokens
+    /// * `penalty_last_n` - number of most recent tokens to consider, `0` disables the penalties
+    /// * `penalty_repeat` - divides the logits of repeated tokens, `1.0` disables it
+    /// * `penalty_freq` - subtracted once per occurrence, `0.0` disables it
+    /// * `penalty_present` - subtracted once if the token occurs at all, `0.0` disables it
+    /// * `penalize_nl` - whether the newline token may be penalized
+    /// * `ignore_eos` - set the logit of the end of stream token to `-inf`
+    ///
+    /// The history is built from the sampled tokens; use [`Self::accept`] to add prompt tokens.
+    ///
+    /// ```no_run
+    /// # use bitnet_cpp::context::sampler::LlamaSampler;
+    /// # use bitnet_cpp::model::LlamaModel;
+    /// # fn example(model: &LlamaModel) {
+    /// let sampler = LlamaSampler::new(None);
+    /// sampler
+    ///     .with_penalties(model, 64, 1.1, 0.0, 0.0, false, false)
+    ///     .with_temp(0.8)
+    ///     .with_seed(1234);
+    /// # }
+    /// ```
+    #[allow(clippy::too_many_arguments, clippy::fn_params_excessive_bools)]
     pub fn with_penalties(
         &self,
+        model: &LlamaModel,
+        penalty_last_n: i32,
         penalty_repeat: f32,
         penalty_freq: f32,
         penalty_present: f32,
         penalize_nl: bool,
         ignore_eos: bool,
     ) -> &Self {
-        // TODO: should this be pulled from context instead?
-        let n_vocab: i32 = 0;
-        let special_eos_id: llama_token = 0;
-        let linefeed_id: llama_token = 0;
-        let penalty_last_n: i32 = 0;
-
-        unsafe {
-            llama_sampler_chain_add(
-                self.sampler.as_ptr(),
-                llama_sampler_init_penalties(
-                    n_vocab,
-                    special_eos_id,
-                    linefeed_id,
-                    penalty_last_n,
-                    penalty_repeat,
-                    penalty_freq,
-                    penalty_present,
-                    penalize_nl,
-                    ignore_eos,
-                ),
-            );
+        let stage = unsafe {
+            llama_sampler_init_penalties(
+                model.n_vocab(),
+                model.token_eos().0,
+                model.token_nl().0,
+                penalty_last_n,
+                penalty_repeat,
+                penalty_freq,
+                penalty_present,
+                penalize_nl,
+                ignore_eos,
+            )
         };
 
-        self
+        self.add(
+            stage,
+            SamplerStageConfig::Penalties {
+                last_n: penalty_last_n,
+                repeat: penalty_repeat,
+                freq: penalty_freq,
+                present: penalty_present,
+                penalize_nl,
+                ignore_eos,
+            },
+        )
+    }
+}
+
+impl Clone for LlamaSampler {
+    /// Copy the chain including the current state of every stage, e.g. to fork a sampler for a
+    /// parallel sequence that shares the same history.
+    fn clone(&self) -> Self {
+        let sampler = unsafe { llama_sampler_clone(self.sampler.as_ptr()) };
+        Self {
+            sampler: NonNull::new(sampler).expect("llama_sampler_clone returned null"),
+            no_perf: self.no_perf,
+            stages: self.stages.clone(),
+        }
+    }
+}
+
+impl Drop for LlamaSampler {
+    fn drop(&mut self) {
+        unsafe { llama_sampler_free(self.sampler.as_ptr()) }
     }
 }
 
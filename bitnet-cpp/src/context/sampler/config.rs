@@ -0,0 +1,277 @@
+//! A description of a [`LlamaSampler`] chain that can be logged, persisted and rebuilt.
+//!
+//! Every `with_*` call on a [`LlamaSampler`] records the stage and its parameters, and
+//! [`LlamaSampler::config`] returns them as a [`SamplerConfig`]. With the `serde` feature the
+//! config can be (de)serialized, e.g. to store the exact sampling setup of a request.
+//!
+//! ```no_run
+//! # use bitnet_cpp::context::sampler::LlamaSampler;
+//! # use bitnet_cpp::model::LlamaModel;
+//! # fn example(model: &LlamaModel) -> Result<(), Box<dyn std::error::Error>> {
+//! let sampler = LlamaSampler::new(None);
+//! sampler.with_top_k(40).with_temp(0.7).with_seed(1234);
+//! assert_eq!(sampler.stage_names(), ["top-k", "temp", "dist"]);
+//!
+//! let config = sampler.config();
+//! // SAFETY: the copy is dropped before the model
+//! let copy = unsafe { config.build(model)? };
+//! assert_eq!(copy.config(), config);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::sampler::logit_bias::LogitBias;
+use crate::context::sampler::{LlamaSampler, SamplerGrammarError};
+use crate::model::LlamaModel;
+use crate::token::LlamaToken;
+
+/// A stage of a [`LlamaSampler`] chain and the parameters it was added with. The variants
+/// correspond to the `with_*` methods of [`LlamaSampler`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "type", rename_all = "snake_case")
+)]
+#[allow(missing_docs)]
+pub enum SamplerStageConfig {
+    /// [`LlamaSampler::with_top_k`]
+    TopK { k: i32 },
+    /// [`LlamaSampler::with_top_p`]
+    TopP { p: f32, min_keep: usize },
+    /// [`LlamaSampler::with_min_p`]
+    MinP { p: f32, min_keep: usize },
+    /// [`LlamaSampler::with_tail_free`]
+    TailFree { z: f32, min_keep: usize },
+    /// [`LlamaSampler::with_typical`]
+    Typical { p: f32, min_keep: usize },
+    /// [`LlamaSampler::with_temp`]
+    Temp { t: f32 },
+    /// [`LlamaSampler::with_temp_ext`]
+    TempExt { t: f32, delta: f32, exponent: f32 },
+    /// [`LlamaSampler::with_xtc`]
+    Xtc {
+        probability: f32,
+        threshold: f32,
+        min_keep: usize,
+        seed: u32,
+    },
+    /// [`LlamaSampler::with_mirostat`]
+    Mirostat {
+        n_vocab: i32,
+        seed: u32,
+        tau: f32,
+        eta: f32,
+        m: i32,
+    },
+    /// [`LlamaSampler::with_mirostat_v2`]
+    MirostatV2 { seed: u32, tau: f32, eta: f32 },
+    /// [`LlamaSampler::with_grammar`]
+    Grammar { grammar: String, root: String },
+    /// [`LlamaSampler::with_logit_bias`]. Banned tokens are listed separately since JSON has no
+    /// representation for `-inf`.
+    LogitBias {
+        biases: Vec<(i32, f32)>,
+        banned: Vec<i32>,
+    },
+    /// [`LlamaSampler::with_dry`]
+    Dry {
+        multiplier: f32,
+        base: f32,
+        allowed_length: i32,
+        penalty_last_n: i32,
+        sequence_breakers: Vec<String>,
+    },
+    /// [`LlamaSampler::with_seed`]
+    Dist { seed: u32 },
+    /// [`LlamaSampler::with_penalties`]
+    Penalties {
+        last_n: i32,
+        repeat: f32,
+        freq: f32,
+        present: f32,
+        penalize_nl: bool,
+        ignore_eos: bool,
+    },
+    /// A stage added with [`LlamaSampler::with_stage`]. It can not be rebuilt from its name.
+    Custom { name: String },
+}
+
+impl SamplerStageConfig {
+    pub(crate) fn logit_bias(logit_bias: &LogitBias) -> Self {
+        let (banned, biases): (Vec<_>, Vec<_>) = logit_bias
+            .biases()
+            .iter()
+            .map(|&(token, bias)| (token.0, bias))
+            .partition(|&(_, bias)| bias == f32::NEG_INFINITY);
+        Self::LogitBias {
+            biases,
+            banned: banned.into_iter().map(|(token, _)| token).collect(),
+        }
+    }
+}
+
+/// The stages of a [`LlamaSampler`] chain, see [`LlamaSampler::config`].
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::unsafe_derive_deserialize)] // `build` is unsafe because of the model, not the config
+pub struct SamplerConfig {
+    /// whether to skip measuring performance timings, see [`LlamaSampler::new`]
+    pub no_perf: Option<bool>,
+    /// the stages in the order they are applied
+    pub stages: Vec<SamplerStageConfig>,
+}
+
+/// Failed to build a [`LlamaSampler`] from a [`SamplerConfig`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SamplerConfigError {
+    /// A grammar stage could not be added.
+    #[error(transparent)]
+    Grammar(#[from] SamplerGrammarError),
+    /// The config contains a stage implemented in Rust, which can not be rebuilt.
+    #[error("the custom stage {0:?} can not be rebuilt from a config")]
+    CustomStage(String),
+}
+
+impl SamplerConfig {
+    /// Build a sampler chain with the stages of `self`.
+    ///
+    /// `model` provides the vocabulary for the stages that need it (e.g. grammars and
+    /// penalties).
+    ///
+    /// # Errors
+    ///
+    /// See [`SamplerConfigError`] for more information.
+    ///
+    /// # Safety
+    ///
+    /// If the config contains a grammar stage, the sampler keeps a reference to the vocabulary
+    /// of `model` and must be dropped before `model`, see [`LlamaSampler::with_grammar`].
+    pub unsafe fn build(&self, model: &LlamaModel) -> Result<LlamaSampler, SamplerConfigError> {
+        let sampler = LlamaSampler::new(self.no_perf);
+        for stage in &self.stages {
+            match stage {
+                &SamplerStageConfig::TopK { k } => sampler.with_top_k(k),
+                &SamplerStageConfig::TopP { p, min_keep } => sampler.add_top_p(p, min_keep),
+                &SamplerStageConfig::MinP { p, min_keep } => sampler.with_min_p(p, min_keep),
+                &SamplerStageConfig::TailFree { z, min_keep } => {
+                    sampler.with_tail_free(z, min_keep)
+                }
+                &SamplerStageConfig::Typical { p, min_keep } => sampler.with_typical(p, min_keep),
+                &SamplerStageConfig::Temp { t } => sampler.with_temp(t),
+                &SamplerStageConfig::TempExt { t, delta, exponent } => {
+                    sampler.with_temp_ext(t, delta, exponent)
+                }
+                &SamplerStageConfig::Xtc {
+                    probability,
+                    threshold,
+                    min_keep,
+                    seed,
+                } => sampler.with_xtc(probability, threshold, min_keep, seed),
+                &SamplerStageConfig::Mirostat {
+                    n_vocab,
+                    seed,
+                    tau,
+                    eta,
+                    m,
+                } => sampler.with_mirostat(n_vocab, seed, tau, eta, m),
+                &SamplerStageConfig::MirostatV2 { seed, tau, eta } => {
+                    sampler.with_mirostat_v2(seed, tau, eta)
+                }
+                SamplerStageConfig::Grammar { grammar, root } => {
+                    // SAFETY: the caller keeps `model` alive for as long as the sampler
+                    unsafe { sampler.with_grammar(model, grammar, root)? }
+                }
+                SamplerStageConfig::LogitBias { biases, banned } => {
+                    let logit_bias = biases
+                        .iter()
+                        .map(|&(token, bias)| (LlamaToken(token), bias))
+                        .chain(
+                            banned
+                                .iter()
+                                .map(|&token| (LlamaToken(token), f32::NEG_INFINITY)),
+                        )
+                        .collect::<LogitBias>();
+                    sampler.with_logit_bias(model, &logit_bias)
+                }
+                SamplerStageConfig::Dry {
+                    multiplier,
+                    base,
+                    allowed_length,
+                    penalty_last_n,
+                    sequence_breakers,
+                } => sampler.with_dry(
+                    model,
+                    *multiplier,
+                    *base,
+                    *allowed_length,
+                    *penalty_last_n,
+                    sequence_breakers,
+                ),
+                &SamplerStageConfig::Dist { seed } => sampler.with_seed(seed),
+                &SamplerStageConfig::Penalties {
+                    last_n,
+                    repeat,
+                    freq,
+                    present,
+                    penalize_nl,
+                    ignore_eos,
+                } => sampler.with_penalties(
+                    model,
+                    last_n,
+                    repeat,
+                    freq,
+                    present,
+                    penalize_nl,
+                    ignore_eos,
+                ),
+                SamplerStageConfig::Custom { name } => {
+                    return Err(SamplerConfigError::CustomStage(name.clone()))
+                }
+            };
+        }
+        Ok(sampler)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn banned_tokens_are_listed_separately() {
+        let logit_bias = LogitBias::new()
+            .with_token(LlamaToken(1), 2.5)
+            .with_banned_token(LlamaToken(2));
+        let stage = SamplerStageConfig::logit_bias(&logit_bias);
+        assert_eq!(
+            stage,
+            SamplerStageConfig::LogitBias {
+                biases: vec![(1, 2.5)],
+                banned: vec![2],
+            }
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn config_round_trips_through_json() {
+        let config = SamplerConfig {
+            no_perf: Some(true),
+            stages: vec![
+                SamplerStageConfig::TopK { k: 40 },
+                SamplerStageConfig::Temp { t: 0.7 },
+                SamplerStageConfig::Dist { seed: 1234 },
+            ],
+        };
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(
+            json["stages"][0],
+            serde_json::json!({ "type": "top_k", "k": 40 })
+        );
+        assert_eq!(
+            serde_json::from_value::<SamplerConfig>(json).unwrap(),
+            config
+        );
+    }
+}
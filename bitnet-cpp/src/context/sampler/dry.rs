@@ -0,0 +1,340 @@
+//! DRY ("Don't Repeat Yourself") repetition penalty, see
+//! <https://github.com/oobabooga/text-generation-webui/pull/5677>.
+//!
+//! DRY penalizes tokens that would extend a sequence that already occurred earlier in the
+//! context. The penalty grows exponentially with the length of the repetition:
+//! `multiplier * base ^ (length - allowed_length)`. Repetitions never extend across a sequence
+//! breaker (e.g. a newline), so that repeated structure like `"name": ` is not penalized.
+//!
+//! This is a port of the DRY sampler of later llama.cpp versions, implemented as a
+//! [`SamplerStage`].
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::context::generate::token_to_bytes;
+use crate::context::sampler::stage::SamplerStage;
+use crate::model::{AddBos, LlamaModel, Special};
+use crate::token::data_array::LlamaTokenDataArray;
+use crate::token::LlamaToken;
+
+/// Sequence breakers are truncated to this many bytes.
+const MAX_BREAKER_LEN: usize = 40;
+/// The tokens of a sequence breaker after its first token are truncated to this many tokens.
+const MAX_BREAKER_TAIL_LEN: usize = 20;
+/// `ln(f32::MAX)`, the largest exponent of `base` that does not overflow.
+const FLOAT_MAX_LOG: f32 = 88.722_84;
+
+/// The DRY sampler stage. Add it with [`LlamaSampler::with_dry`](super::LlamaSampler::with_dry).
+#[derive(Debug, Clone)]
+pub struct Dry {
+    multiplier: f32,
+    base: f32,
+    allowed_length: usize,
+    penalty_last_n: usize,
+    /// the token sequences that break a repetition, keyed by their first token
+    breakers: HashMap<LlamaToken, Vec<Vec<LlamaToken>>>,
+    last_tokens: VecDeque<LlamaToken>,
+    repeat_count: Vec<usize>,
+    max_token_repeat: HashMap<LlamaToken, usize>,
+}
+
+impl Dry {
+    /// Create a DRY stage.
+    ///
+    /// * `model` - used to map the `sequence_breakers` to token sequences
+    /// * `multiplier` - the penalty for the shortest penalized repetition, `0.0` disables DRY
+    /// * `base` - how fast the penalty grows with the length of the repetition
+    /// * `allowed_length` - repetitions up to this length are not penalized
+    /// * `penalty_last_n` - how many tokens to scan for repetitions, `0` disables DRY and `-1`
+    ///   uses the training context size of `model`
+    /// * `sequence_breakers` - strings that end a repetition, such as `"\n"` or `":"`
+    pub fn new<S: AsRef<str>>(
+        model: &LlamaModel,
+        multiplier: f32,
+        base: f32,
+        allowed_length: i32,
+        penalty_last_n: i32,
+        sequence_breakers: &[S],
+    ) -> Self {
+        let penalty_last_n = if penalty_last_n == -1 {
+            usize::try_from(model.n_ctx_train()).unwrap_or(usize::MAX)
+        } else {
+            usize::try_from(penalty_last_n).unwrap_or(0)
+        };
+        let mut breakers = HashMap::<LlamaToken, Vec<Vec<LlamaToken>>>::new();
+        for breaker in sequence_breakers {
+            let breaker = truncate_breaker(breaker.as_ref());
+            for (head, tail) in overlapping_token_sequences(model, breaker) {
+                let tails = breakers.entry(head).or_default();
+                if !tails.contains(&tail) {
+                    tails.push(tail);
+                }
+            }
+        }
+
+        Self::with_breaker_sequences(
+            multiplier,
+            base,
+            usize::try_from(allowed_length).unwrap_or(0),
+            penalty_last_n,
+            breakers,
+        )
+    }
+
+    fn with_breaker_sequences(
+        multiplier: f32,
+        base: f32,
+        allowed_length: usize,
+        penalty_last_n: usize,
+        breakers: HashMap<LlamaToken, Vec<Vec<LlamaToken>>>,
+    ) -> Self {
+        Self {
+            multiplier,
+            base,
+            allowed_length,
+            penalty_last_n,
+            breakers,
+            last_tokens: VecDeque::with_capacity(penalty_last_n.min(4096)),
+            repeat_count: Vec::new(),
+            max_token_repeat: HashMap::new(),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.multiplier != 0.0 && self.base >= 1.0 && self.penalty_last_n > 0
+    }
+
+    /// The `i`-th most recent token.
+    fn recent(&self, i: usize) -> LlamaToken {
+        self.last_tokens[self.last_tokens.len() - 1 - i]
+    }
+
+    /// Only scan back up to the most recent sequence breaker.
+    fn repetition_limit(&self, last_n_repeat: usize) -> usize {
+        for i in 0..last_n_repeat {
+            let Some(tails) = self.breakers.get(&self.recent(i)) else {
+                continue;
+            };
+            let longest_match = tails
+                .iter()
+                .filter(|tail| tail.len() <= i)
+                .filter(|tail| {
+                    tail.iter()
+                        .enumerate()
+                        .all(|(offset, &token)| token == self.recent(i - offset - 1))
+                })
+                .map(Vec::len)
+                .max();
+            if let Some(longest_match) = longest_match {
+                return i - longest_match;
+            }
+        }
+        last_n_repeat
+    }
+
+    /// Compute for every earlier position how long the sequence ending there matches the end of
+    /// the context, using the Z-algorithm on the reversed context.
+    fn count_repeats(&mut self, last_n_repeat: usize, rep_limit: usize) {
+        self.repeat_count.clear();
+        self.repeat_count.resize(last_n_repeat, 0);
+        let last = last_n_repeat - 1;
+        let (mut lt, mut rt) = (0, 0);
+        for k in 1..last_n_repeat {
+            if k > rt {
+                let mut n = 0;
+                while n + k < last_n_repeat && self.recent(n) == self.recent(n + k) {
+                    n += 1;
+                }
+                self.repeat_count[last - k] = n.min(rep_limit);
+                if n > 0 {
+                    lt = k;
+                    rt = k + n - 1;
+                }
+            } else {
+                let p = k - lt;
+                let right_part_len = rt - k + 1;
+                if self.repeat_count[last - p] < right_part_len {
+                    self.repeat_count[last - k] = self.repeat_count[last - p].min(rep_limit);
+                } else {
+                    let mut i = right_part_len;
+                    while i + k < last_n_repeat && self.recent(i) == self.recent(i - k) {
+                        i += 1;
+                    }
+                    let n = i.min(rep_limit);
+                    self.repeat_count[last - k] = n;
+                    lt = k;
+                    rt = k + n - 1;
+                }
+            }
+        }
+    }
+}
+
+impl SamplerStage for Dry {
+    fn name(&self) -> &'static str {
+        "dry"
+    }
+
+    fn apply(&mut self, candidates: &mut LlamaTokenDataArray) {
+        if !self.is_enabled() {
+            return;
+        }
+        let last_n_repeat = self.last_tokens.len().min(self.penalty_last_n);
+        if last_n_repeat <= self.allowed_length {
+            return;
+        }
+
+        let rep_limit = self.repetition_limit(last_n_repeat);
+        if rep_limit < self.allowed_length {
+            return;
+        }
+
+        self.count_repeats(last_n_repeat, rep_limit);
+
+        // The token following a repeated sequence is the one that would extend the repetition.
+        self.max_token_repeat.clear();
+        for i in 0..last_n_repeat - 1 {
+            let repeat_len = self.repeat_count[i];
+            if repeat_len >= self.allowed_length {
+                let token = self.recent(last_n_repeat - 2 - i);
+                let max = self.max_token_repeat.entry(token).or_default();
+                *max = (*max).max(repeat_len);
+            }
+        }
+        if self.max_token_repeat.is_empty() {
+            return;
+        }
+
+        let max_exponent = if self.base > 1.000_001 {
+            FLOAT_MAX_LOG / self.base.ln()
+        } else {
+            0.0
+        };
+        for candidate in &mut candidates.data {
+            if let Some(&repeat_len) = self.max_token_repeat.get(&candidate.id()) {
+                #[allow(clippy::cast_precision_loss)]
+                let mut exponent = (repeat_len - self.allowed_length) as f32;
+                if max_exponent > 0.0 && exponent > max_exponent {
+                    exponent = max_exponent;
+                }
+                let penalty = self.multiplier * self.base.powf(exponent);
+                candidate.set_logit(candidate.logit() - penalty);
+            }
+        }
+        candidates.sorted = false;
+    }
+
+    fn accept(&mut self, token: LlamaToken) {
+        if self.penalty_last_n == 0 {
+            return;
+        }
+        if self.last_tokens.len() == self.penalty_last_n {
+            self.last_tokens.pop_front();
+        }
+        self.last_tokens.push_back(token);
+    }
+
+    fn reset(&mut self) {
+        self.last_tokens.clear();
+        self.repeat_count.clear();
+        self.max_token_repeat.clear();
+    }
+}
+
+fn truncate_breaker(breaker: &str) -> &str {
+    let mut end = breaker.len().min(MAX_BREAKER_LEN);
+    while !breaker.is_char_boundary(end) {
+        end -= 1;
+    }
+    &breaker[..end]
+}
+
+/// Find every token whose text contains `breaker` or ends with a prefix of it, together with
+/// the tokens of the rest of `breaker`.
+fn overlapping_token_sequences(
+    model: &LlamaModel,
+    breaker: &str,
+) -> Vec<(LlamaToken, Vec<LlamaToken>)> {
+    let breaker = breaker.as_bytes();
+    let Some(&first) = breaker.first() else {
+        return Vec::new();
+    };
+    let mut sequences = Vec::new();
+    for token in (0..model.n_vocab()).map(LlamaToken::new) {
+        // pieces longer than the default buffer are retried with the required size
+        let Ok(word) = token_to_bytes(model, token, Special::Tokenize) else {
+            continue;
+        };
+        if word.windows(breaker.len()).any(|window| window == breaker) {
+            sequences.push((token, Vec::new()));
+            continue;
+        }
+        for pos in (0..word.len()).filter(|&pos| word[pos] == first) {
+            let overlap = (word.len() - pos).min(breaker.len());
+            if word[pos..pos + overlap] != breaker[..overlap] {
+                continue;
+            }
+            let rest = String::from_utf8_lossy(&breaker[overlap..]);
+            let Ok(mut tail) = model.str_to_token(&rest, AddBos::Never) else {
+                continue;
+            };
+            tail.truncate(MAX_BREAKER_TAIL_LEN);
+            let sequence = (token, tail);
+            if !sequences.contains(&sequence) {
+                sequences.push(sequence);
+            }
+        }
+    }
+    sequences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::data::LlamaTokenData;
+
+    fn penalties(dry: &mut Dry, history: &[i32]) -> Vec<f32> {
+        for &token in history {
+            dry.accept(LlamaToken(token));
+        }
+        let mut candidates = LlamaTokenDataArray::from_iter(
+            (0..5).map(|id| LlamaTokenData::new(LlamaToken(id), 0.0, 0.0)),
+            false,
+        );
+        dry.apply(&mut candidates);
+        candidates.data.iter().map(|c| -c.logit()).collect()
+    }
+
+    #[test]
+    fn dry_penalizes_tokens_extending_a_repetition() {
+        let mut dry = Dry::with_breaker_sequences(1.0, 2.0, 2, 64, HashMap::new());
+        // "0 1 2 3 0 1 2" - continuing with 3 would repeat "0 1 2 3".
+        assert_eq!(
+            penalties(&mut dry, &[0, 1, 2, 3, 0, 1, 2]),
+            [0.0, 0.0, 0.0, 2.0, 0.0]
+        );
+
+        dry.reset();
+        assert_eq!(
+            penalties(&mut dry, &[0, 1, 2, 0, 1]),
+            [0.0, 0.0, 1.0, 0.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn dry_stops_at_sequence_breakers() {
+        let history = [0, 4, 1, 2, 3, 0, 4, 1, 2];
+        let mut dry = Dry::with_breaker_sequences(1.0, 2.0, 2, 64, HashMap::new());
+        assert_eq!(penalties(&mut dry, &history), [0.0, 0.0, 0.0, 4.0, 0.0]);
+
+        // the repetition can not extend past the single token breaker "4"
+        let breakers = HashMap::from([(LlamaToken(4), vec![vec![]])]);
+        let mut dry = Dry::with_breaker_sequences(1.0, 2.0, 2, 64, breakers);
+        assert_eq!(penalties(&mut dry, &history), [0.0, 0.0, 0.0, 1.0, 0.0]);
+
+        // "4 1" is a breaker, leaving "2" which is shorter than the allowed length
+        let breakers = HashMap::from([(LlamaToken(4), vec![vec![LlamaToken(1)]])]);
+        let mut dry = Dry::with_breaker_sequences(1.0, 2.0, 2, 64, breakers);
+        assert_eq!(penalties(&mut dry, &history), [0.0; 5]);
+    }
+}
@@ -0,0 +1,108 @@
+//! Logit biases for [`LlamaSampler::with_logit_bias`](super::LlamaSampler::with_logit_bias).
+//!
+//! ```no_run
+//! # use bitnet_cpp::context::sampler::LlamaSampler;
+//! # use bitnet_cpp::context::sampler::logit_bias::LogitBias;
+//! # use bitnet_cpp::model::LlamaModel;
+//! # fn example(model: &LlamaModel) -> Result<(), Box<dyn std::error::Error>> {
+//! let bias = LogitBias::new()
+//!     .with_banned_token(model.token_bos())
+//!     .with_banned_str(model, "darn")?
+//!     .with_str(model, " Rust", 2.0)?;
+//! let sampler = LlamaSampler::new(None);
+//! sampler.with_logit_bias(model, &bias).with_seed(1234);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::model::{AddBos, LlamaModel};
+use crate::token::LlamaToken;
+use crate::StringToTokenError;
+
+/// A list of `(token, bias)` pairs that are added to the logits before sampling.
+///
+/// A bias of [`f32::NEG_INFINITY`] bans a token. Biases for the same token add up.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LogitBias {
+    pub(crate) biases: Vec<(LlamaToken, f32)>,
+}
+
+impl LogitBias {
+    /// An empty list of biases.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `bias` to the logit of `token`.
+    #[must_use]
+    pub fn with_token(mut self, token: LlamaToken, bias: f32) -> Self {
+        self.biases.push((token, bias));
+        self
+    }
+
+    /// Never sample `token`.
+    #[must_use]
+    pub fn with_banned_token(self, token: LlamaToken) -> Self {
+        self.with_token(token, f32::NEG_INFINITY)
+    }
+
+    /// Add `bias` to the logit of every token `text` is tokenized into by `model`.
+    ///
+    /// Special tokens such as `<s>` are recognized. Note that most tokenizers treat a leading
+    /// space as part of the token, so `" word"` and `"word"` usually map to different tokens.
+    ///
+    /// # Errors
+    ///
+    /// If `text` could not be tokenized, see [`StringToTokenError`].
+    pub fn with_str(
+        mut self,
+        model: &LlamaModel,
+        text: &str,
+        bias: f32,
+    ) -> Result<Self, StringToTokenError> {
+        let tokens = model.str_to_token(text, AddBos::Never)?;
+        self.biases
+            .extend(tokens.into_iter().map(|token| (token, bias)));
+        Ok(self)
+    }
+
+    /// Never sample any of the tokens `text` is tokenized into by `model`, see [`Self::with_str`].
+    ///
+    /// # Errors
+    ///
+    /// If `text` could not be tokenized, see [`StringToTokenError`].
+    pub fn with_banned_str(
+        self,
+        model: &LlamaModel,
+        text: &str,
+    ) -> Result<Self, StringToTokenError> {
+        self.with_str(model, text, f32::NEG_INFINITY)
+    }
+
+    /// The `(token, bias)` pairs in the order they were added.
+    #[must_use]
+    pub fn biases(&self) -> &[(LlamaToken, f32)] {
+        &self.biases
+    }
+
+    /// Returns `true` if no bias was added.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.biases.is_empty()
+    }
+}
+
+impl FromIterator<(LlamaToken, f32)> for LogitBias {
+    fn from_iter<T: IntoIterator<Item = (LlamaToken, f32)>>(iter: T) -> Self {
+        Self {
+            biases: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl Extend<(LlamaToken, f32)> for LogitBias {
+    fn extend<T: IntoIterator<Item = (LlamaToken, f32)>>(&mut self, iter: T) {
+        self.biases.extend(iter);
+    }
+}
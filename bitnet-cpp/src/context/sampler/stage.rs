@@ -0,0 +1,221 @@
+//! Sampler stages written in Rust.
+//!
+//! A [`SamplerStage`] is bridged to llama.cpp's `llama_sampler_i` interface, so it can be added to
+//! a [`LlamaSampler`](super::LlamaSampler) chain with
+//! [`LlamaSampler::with_stage`](super::LlamaSampler::with_stage) and mixed freely with the
+//! native stages.
+//!
+//! ```no_run
+//! # use bitnet_cpp::context::sampler::LlamaSampler;
+//! # use bitnet_cpp::context::sampler::stage::SamplerStage;
+//! # use bitnet_cpp::token::data_array::LlamaTokenDataArray;
+//! # use bitnet_cpp::token::LlamaToken;
+//! /// Boost a fixed set of tokens.
+//! #[derive(Clone)]
+//! struct Boost(Vec<LlamaToken>);
+//!
+//! impl SamplerStage for Boost {
+//!     fn name(&self) -> &str {
+//!         "boost"
+//!     }
+//!
+//!     fn apply(&mut self, candidates: &mut LlamaTokenDataArray) {
+//!         for candidate in &mut candidates.data {
+//!             if self.0.contains(&candidate.id()) {
+//!                 candidate.set_logit(candidate.logit() + 1.0);
+//!             }
+//!         }
+//!         candidates.sorted = false;
+//!     }
+//! }
+//!
+//! let sampler = LlamaSampler::new(None);
+//! sampler
+//!     .with_top_k(40)
+//!     .with_stage(Boost(vec![LlamaToken::new(42)]))
+//!     .with_seed(1234);
+//! ```
+
+use std::ffi::{c_char, CString};
+
+use bitnet_cpp_sys::{
+    llama_sampler, llama_sampler_i, llama_sampler_init_greedy, llama_token, llama_token_data_array,
+};
+
+use crate::token::data_array::LlamaTokenDataArray;
+use crate::token::LlamaToken;
+
+/// A sampler stage implemented in Rust.
+///
+/// The methods are called from within llama.cpp and must not panic; a panic aborts the process.
+pub trait SamplerStage: Clone + 'static {
+    /// The name of the stage as reported by `llama_sampler_name`.
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+
+    /// Modify the candidates, e.g. change their logits, remove or reorder them, or select one.
+    ///
+    /// The underlying array cannot grow, so adding candidates is not supported; any candidates
+    /// past the original length are dropped. Set `candidates.sorted` to `false` if the order no
+    /// longer matches the logits.
+    fn apply(&mut self, candidates: &mut LlamaTokenDataArray);
+
+    /// Called for every token that is sampled or passed to
+    /// [`LlamaSampler::accept`](super::LlamaSampler::accept).
+    fn accept(&mut self, _token: LlamaToken) {}
+
+    /// Reset the state of the stage, see [`LlamaSampler::reset`](super::LlamaSampler::reset).
+    fn reset(&mut self) {}
+}
+
+/// The `ctx` of a native sampler wrapping a [`SamplerStage`].
+///
+/// The interface lives next to the stage so that every monomorphization gets its own vtable.
+struct StageContext<S> {
+    iface: llama_sampler_i,
+    name: CString,
+    stage: S,
+    candidates: LlamaTokenDataArray,
+}
+
+impl<S: SamplerStage> StageContext<S> {
+    fn new(stage: S) -> Self {
+        let name = CString::new(stage.name().replace('\0', "")).expect("no null bytes left");
+        Self {
+            iface: llama_sampler_i {
+                name: Some(stage_name::<S>),
+                accept: Some(stage_accept::<S>),
+                apply: Some(stage_apply::<S>),
+                reset: Some(stage_reset::<S>),
+                clone: Some(stage_clone::<S>),
+                free: Some(stage_free::<S>),
+            },
+            name,
+            stage,
+            candidates: LlamaTokenDataArray::default(),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `smpl` must have been created by [`new_stage_sampler`] with the same `S`.
+    unsafe fn from_sampler<'a>(smpl: *const llama_sampler) -> &'a mut Self {
+        &mut *(*smpl).ctx.cast::<Self>()
+    }
+}
+
+/// Create a native sampler that forwards to `stage`.
+///
+/// The vendored llama.cpp has no `llama_sampler_init`, and the sampler struct is released with
+/// `delete` by llama.cpp, so we let llama.cpp allocate a greedy sampler (which has no state) and
+/// replace its interface and context.
+pub(crate) fn new_stage_sampler<S: SamplerStage>(stage: S) -> *mut llama_sampler {
+    let context = Box::into_raw(Box::new(StageContext::new(stage)));
+    unsafe {
+        let smpl = llama_sampler_init_greedy();
+        assert!(!smpl.is_null(), "llama_sampler_init_greedy returned null");
+        (*smpl).iface = std::ptr::addr_of_mut!((*context).iface);
+        (*smpl).ctx = context.cast();
+        smpl
+    }
+}
+
+unsafe extern "C" fn stage_name<S: SamplerStage>(smpl: *const llama_sampler) -> *const c_char {
+    StageContext::<S>::from_sampler(smpl).name.as_ptr()
+}
+
+unsafe extern "C" fn stage_accept<S: SamplerStage>(smpl: *mut llama_sampler, token: llama_token) {
+    StageContext::<S>::from_sampler(smpl)
+        .stage
+        .accept(LlamaToken(token));
+}
+
+unsafe extern "C" fn stage_apply<S: SamplerStage>(
+    smpl: *mut llama_sampler,
+    cur_p: *mut llama_token_data_array,
+) {
+    let context = StageContext::<S>::from_sampler(smpl);
+    let cur_p = &mut *cur_p;
+    context.candidates.copy_from_c(cur_p);
+    context.stage.apply(&mut context.candidates);
+    context.candidates.copy_to_c(cur_p);
+}
+
+unsafe extern "C" fn stage_reset<S: SamplerStage>(smpl: *mut llama_sampler) {
+    StageContext::<S>::from_sampler(smpl).stage.reset();
+}
+
+unsafe extern "C" fn stage_clone<S: SamplerStage>(
+    smpl: *const llama_sampler,
+) -> *mut llama_sampler {
+    new_stage_sampler(StageContext::<S>::from_sampler(smpl).stage.clone())
+}
+
+unsafe extern "C" fn stage_free<S: SamplerStage>(smpl: *mut llama_sampler) {
+    // llama.cpp deletes `smpl` itself, we only own the context.
+    drop(Box::from_raw((*smpl).ctx.cast::<StageContext<S>>()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::data::LlamaTokenData;
+
+    #[derive(Clone, Default)]
+    struct KeepAccepted(Vec<LlamaToken>);
+
+    impl SamplerStage for KeepAccepted {
+        fn apply(&mut self, candidates: &mut LlamaTokenDataArray) {
+            candidates
+                .data
+                .retain(|candidate| self.0.contains(&candidate.id()));
+            candidates
+                .data
+                .push(LlamaTokenData::new(LlamaToken(99), 0.0, 0.0));
+            candidates.selected = Some(0);
+        }
+
+        fn accept(&mut self, token: LlamaToken) {
+            self.0.push(token);
+        }
+    }
+
+    #[test]
+    fn stage_bridge_round_trips_candidates() {
+        let mut context = Box::new(StageContext::new(KeepAccepted::default()));
+        let mut smpl = llama_sampler {
+            iface: std::ptr::addr_of_mut!(context.iface),
+            ctx: std::ptr::addr_of_mut!(*context).cast(),
+        };
+        let mut data = (0..4)
+            .map(|id| bitnet_cpp_sys::llama_token_data {
+                id,
+                logit: 0.0,
+                p: 0.0,
+            })
+            .collect::<Vec<_>>();
+        let mut cur_p = llama_token_data_array {
+            data: data.as_mut_ptr(),
+            size: data.len(),
+            selected: -1,
+            sorted: false,
+        };
+
+        unsafe {
+            let iface = *smpl.iface;
+            let smpl = std::ptr::addr_of_mut!(smpl);
+            iface.accept.unwrap()(smpl, 1);
+            iface.accept.unwrap()(smpl, 3);
+            iface.apply.unwrap()(smpl, std::ptr::addr_of_mut!(cur_p));
+        }
+
+        assert_eq!(cur_p.size, 3);
+        assert_eq!(cur_p.selected, 0);
+        assert_eq!(
+            data.iter().take(3).map(|d| d.id).collect::<Vec<_>>(),
+            [1, 3, 99]
+        );
+        assert!(context.stage.0 == [LlamaToken(1), LlamaToken(3)]);
+    }
+}
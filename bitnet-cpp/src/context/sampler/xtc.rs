@@ -0,0 +1,160 @@
+//! XTC ("Exclude Top Choices"), see <https://github.com/oobabooga/text-generation-webui/pull/6335>.
+//!
+//! With a chance of `probability`, XTC removes all candidates with a probability of at least
+//! `threshold` except for the least likely of them. This steers the model away from its most
+//! predictable continuations while keeping the output coherent.
+//!
+//! The vendored llama.cpp predates `llama_sampler_init_xtc`, so this is a port implemented as a
+//! [`SamplerStage`]. The random numbers come from a small built-in generator, so a fixed seed
+//! always removes the same candidates for the same inputs.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bitnet_cpp_sys::LLAMA_DEFAULT_SEED;
+
+use crate::context::sampler::stage::SamplerStage;
+use crate::token::data_array::LlamaTokenDataArray;
+
+/// The XTC sampler stage. Add it with [`LlamaSampler::with_xtc`](super::LlamaSampler::with_xtc).
+#[derive(Debug, Clone)]
+pub struct Xtc {
+    probability: f32,
+    threshold: f32,
+    min_keep: usize,
+    seed: u64,
+    rng: SplitMix64,
+}
+
+impl Xtc {
+    /// Create an XTC stage.
+    ///
+    /// * `probability` - the chance that the top choices are removed, `0.0` disables XTC
+    /// * `threshold` - the minimum probability of a top choice, values above `0.5` disable XTC
+    /// * `min_keep` - never leave fewer candidates than this
+    /// * `seed` - the seed of the random number generator, [`LLAMA_DEFAULT_SEED`] picks a random
+    ///   seed
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn new(probability: f32, threshold: f32, min_keep: usize, seed: u32) -> Self {
+        let seed = if seed == LLAMA_DEFAULT_SEED {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+        } else {
+            u64::from(seed)
+        };
+        Self {
+            probability,
+            threshold,
+            min_keep,
+            seed,
+            rng: SplitMix64(seed),
+        }
+    }
+}
+
+impl SamplerStage for Xtc {
+    fn name(&self) -> &'static str {
+        "xtc"
+    }
+
+    fn apply(&mut self, candidates: &mut LlamaTokenDataArray) {
+        if self.probability <= 0.0 || self.threshold > 0.5 || candidates.data.len() < 2 {
+            return;
+        }
+        if self.rng.next_f32() > self.probability {
+            return;
+        }
+
+        candidates.softmax();
+        let pos_last = candidates
+            .data
+            .iter()
+            .take_while(|candidate| candidate.p() >= self.threshold)
+            .count()
+            .saturating_sub(1);
+        if pos_last > 0 && candidates.data.len() - pos_last >= self.min_keep {
+            candidates.data.drain(..pos_last);
+            candidates.selected = None;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.rng = SplitMix64(self.seed);
+    }
+}
+
+/// `SplitMix64`, a tiny generator that is good enough to decide whether to apply XTC.
+#[derive(Debug, Clone)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A uniformly distributed number in `[0, 1)`.
+    #[allow(clippy::cast_precision_loss)]
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1_u64 << 24) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::data::LlamaTokenData;
+    use crate::token::LlamaToken;
+
+    fn candidates() -> LlamaTokenDataArray {
+        // probabilities 0.4, 0.3, 0.2, 0.1
+        LlamaTokenDataArray::from_iter(
+            [4.0_f32, 3.0, 2.0, 1.0]
+                .into_iter()
+                .zip(0..)
+                .map(|(weight, id)| LlamaTokenData::new(LlamaToken(id), weight.ln(), 0.0)),
+            false,
+        )
+    }
+
+    fn ids(candidates: &LlamaTokenDataArray) -> Vec<i32> {
+        candidates.data.iter().map(|c| c.id().0).collect()
+    }
+
+    #[test]
+    fn xtc_removes_all_but_the_least_likely_top_choice() {
+        let mut xtc = Xtc::new(1.0, 0.25, 1, 42);
+        let mut cur = candidates();
+        xtc.apply(&mut cur);
+        assert_eq!(ids(&cur), [1, 2, 3]);
+
+        // min_keep prevents the removal
+        let mut xtc = Xtc::new(1.0, 0.15, 4, 42);
+        let mut cur = candidates();
+        xtc.apply(&mut cur);
+        assert_eq!(ids(&cur), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn xtc_is_deterministic_for_a_fixed_seed() {
+        let run = |xtc: &mut Xtc| {
+            (0..32)
+                .map(|_| {
+                    let mut cur = candidates();
+                    xtc.apply(&mut cur);
+                    cur.data.len()
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut xtc = Xtc::new(0.5, 0.25, 1, 1234);
+        let first = run(&mut xtc);
+        assert!(first.contains(&3) && first.contains(&4));
+        assert_eq!(run(&mut xtc.clone()), run(&mut xtc));
+        xtc.reset();
+        assert_eq!(run(&mut xtc), first);
+    }
+}
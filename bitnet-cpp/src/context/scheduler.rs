@@ -0,0 +1,674 @@
+//! Continuous batching: requests join and leave a running decode loop.
+//!
+//! A [`Scheduler`] owns the decode loop of one [`LlamaContext`]. Requests are submitted from any
+//! thread through a [`SchedulerHandle`] and stream their tokens back through a
+//! [`RequestStream`]. Every [`Scheduler::step`] decodes one batch that holds the next token of
+//! every generating request and, in the remaining space, chunks of the prompts of newly admitted
+//! requests. Generation is therefore never blocked by a long prompt, and prompts are prefilled
+//! round-robin so one long prompt does not delay the others.
+//!
+//! Each request gets its own sequence in the kv cache, so at most
+//! [`LlamaContext::n_seq_max`] requests are active at once (see
+//! [`SchedulerParams::with_max_concurrency`]); the rest wait in submission order. The sequences
+//! share the kv cache, so a request may use at most `n_ctx / n_seq_max` tokens for its prompt and
+//! its generated tokens, like a slot of the llama.cpp server.
+//!
+//! ```no_run
+//! # use bitnet_cpp::context::LlamaContext;
+//! # use bitnet_cpp::context::sampler::LlamaSampler;
+//! # use bitnet_cpp::context::scheduler::{Scheduler, SchedulerEvent, SchedulerParams, SchedulerRequest};
+//! # fn example(ctx: &mut LlamaContext) -> Result<(), Box<dyn std::error::Error>> {
+//! let (mut scheduler, handle) = Scheduler::new(ctx, SchedulerParams::default());
+//! std::thread::scope(|s| {
+//!     s.spawn(move || {
+//!         let sampler = LlamaSampler::new(None);
+//!         sampler.with_temp(0.7).with_seed(1234);
+//!         let request = SchedulerRequest::text("Hello! how are you?", sampler.config())
+//!             .with_max_tokens(Some(64));
+//!         for event in handle.submit(request).unwrap() {
+//!             match event {
+//!                 SchedulerEvent::Token(token) => print!("{}", token.text),
+//!                 SchedulerEvent::Finished(reason) => println!("\n{reason:?}"),
+//!                 SchedulerEvent::Failed(error) => eprintln!("{error}"),
+//!             }
+//!         }
+//!         // dropping the last handle stops the scheduler once it is idle
+//!     });
+//!     scheduler.run();
+//! });
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+use std::num::NonZeroUsize;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+
+use crate::context::generate::{token_to_bytes, GeneratedToken, StopReason, Utf8Decoder};
+use crate::context::sampler::config::{SamplerConfig, SamplerConfigError};
+use crate::context::sampler::LlamaSampler;
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch, Pos, SeqId};
+use crate::model::{AddBos, Special};
+use crate::token::LlamaToken;
+use crate::{DecodeError, StringToTokenError, TokenToStringError};
+
+/// Errors that end a request of a [`Scheduler`].
+#[derive(Debug, thiserror::Error)]
+pub enum SchedulerError {
+    /// The prompt could not be tokenized.
+    #[error("{0}")]
+    Tokenize(#[from] StringToTokenError),
+    /// The sampler could not be built from its config.
+    #[error("{0}")]
+    Sampler(#[from] SamplerConfigError),
+    /// The prompt did not contain any tokens.
+    #[error("the prompt is empty")]
+    EmptyPrompt,
+    /// The prompt does not fit into the part of the context of one request.
+    #[error(
+        "the prompt ({n_prompt} tokens) does not fit into the context of {n_ctx_slot} tokens per request"
+    )]
+    PromptTooLong {
+        /// The number of prompt tokens.
+        n_prompt: usize,
+        /// The context size of one request, `n_ctx / n_seq_max`.
+        n_ctx_slot: u32,
+    },
+    /// Adding a token to the batch failed.
+    #[error("{0}")]
+    BatchAdd(#[from] BatchAddError),
+    /// Decoding the batch that contained the request failed.
+    #[error("{0}")]
+    Decode(#[from] DecodeError),
+    /// A sampled token could not be converted to text.
+    #[error("{0}")]
+    TokenToString(#[from] TokenToStringError),
+}
+
+/// The scheduler was dropped, so no more requests can be submitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("the scheduler has stopped")]
+pub struct SchedulerStoppedError;
+
+/// What a [`RequestStream`] yields.
+#[derive(Debug)]
+pub enum SchedulerEvent {
+    /// A generated token and the text it completes.
+    Token(GeneratedToken),
+    /// The request finished. This is the last event of the stream.
+    Finished(StopReason),
+    /// The request failed. This is the last event of the stream.
+    Failed(SchedulerError),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Prompt {
+    Text(String, AddBos),
+    Tokens(Vec<LlamaToken>),
+}
+
+/// A request to generate a continuation of a prompt, see [`SchedulerHandle::submit`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchedulerRequest {
+    prompt: Prompt,
+    sampler: SamplerConfig,
+    max_tokens: Option<u32>,
+    special: Special,
+}
+
+impl SchedulerRequest {
+    /// A request for a text prompt, which is tokenized with a beginning of stream token. The
+    /// sampler is built from `sampler` on the thread that runs the scheduler.
+    #[must_use]
+    pub fn text(prompt: impl Into<String>, sampler: SamplerConfig) -> Self {
+        Self::new(Prompt::Text(prompt.into(), AddBos::Always), sampler)
+    }
+
+    /// A request for an already tokenized prompt.
+    #[must_use]
+    pub fn tokens(tokens: Vec<LlamaToken>, sampler: SamplerConfig) -> Self {
+        Self::new(Prompt::Tokens(tokens), sampler)
+    }
+
+    fn new(prompt: Prompt, sampler: SamplerConfig) -> Self {
+        Self {
+            prompt,
+            sampler,
+            max_tokens: None,
+            special: Special::Tokenize,
+        }
+    }
+
+    /// Set the maximum number of tokens to generate. `None` generates until the model ends the
+    /// generation or the context is full.
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: Option<u32>) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// Set how special tokens are rendered in the generated text.
+    #[must_use]
+    pub fn with_special(mut self, special: Special) -> Self {
+        self.special = special;
+        self
+    }
+}
+
+/// Parameters for a [`Scheduler`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SchedulerParams {
+    max_concurrency: Option<NonZeroUsize>,
+    prefill_chunk: Option<usize>,
+}
+
+impl SchedulerParams {
+    /// Set the maximum number of requests that are decoded at the same time. `None` uses
+    /// [`LlamaContext::n_seq_max`]. It is limited to [`LlamaContext::n_seq_max`] and
+    /// [`LlamaContext::n_batch`], as every generating request adds a token to each batch.
+    ///
+    /// ```rust
+    /// # use std::num::NonZeroUsize;
+    /// use bitnet_cpp::context::scheduler::SchedulerParams;
+    /// let params = SchedulerParams::default().with_max_concurrency(NonZeroUsize::new(4));
+    /// assert_eq!(params.max_concurrency(), NonZeroUsize::new(4));
+    /// ```
+    #[must_use]
+    pub fn with_max_concurrency(mut self, max_concurrency: Option<NonZeroUsize>) -> Self {
+        self.max_concurrency = max_concurrency;
+        self
+    }
+
+    /// Get the maximum number of requests that are decoded at the same time.
+    #[must_use]
+    pub fn max_concurrency(&self) -> Option<NonZeroUsize> {
+        self.max_concurrency
+    }
+
+    /// Set the maximum number of prompt tokens of one request in a batch. `None` uses
+    /// [`LlamaContext::n_ubatch`]. A batch never holds more than [`LlamaContext::n_batch`] tokens.
+    #[must_use]
+    pub fn with_prefill_chunk(mut self, prefill_chunk: Option<usize>) -> Self {
+        self.prefill_chunk = prefill_chunk;
+        self
+    }
+
+    /// Get the maximum number of prompt tokens of one request in a batch.
+    #[must_use]
+    pub fn prefill_chunk(&self) -> Option<usize> {
+        self.prefill_chunk
+    }
+}
+
+#[derive(Debug)]
+struct Submission {
+    request: SchedulerRequest,
+    events: Sender<SchedulerEvent>,
+}
+
+/// Submits requests to a [`Scheduler`]. It can be cloned and sent to other threads. Once all
+/// handles are dropped, [`Scheduler::run`] returns after the remaining requests finished.
+#[derive(Debug, Clone)]
+pub struct SchedulerHandle {
+    submissions: Sender<Submission>,
+}
+
+impl SchedulerHandle {
+    /// Queue `request`. The returned stream yields its tokens; dropping the stream cancels the
+    /// request.
+    ///
+    /// # Errors
+    ///
+    /// If the scheduler was dropped.
+    pub fn submit(
+        &self,
+        request: SchedulerRequest,
+    ) -> Result<RequestStream, SchedulerStoppedError> {
+        let (events, receiver) = channel();
+        self.submissions
+            .send(Submission { request, events })
+            .map_err(|_| SchedulerStoppedError)?;
+        Ok(RequestStream { events: receiver })
+    }
+}
+
+/// The events of one request, see [`SchedulerHandle::submit`]. The iterator blocks until the
+/// next event and ends after [`SchedulerEvent::Finished`] or [`SchedulerEvent::Failed`].
+#[derive(Debug)]
+pub struct RequestStream {
+    events: Receiver<SchedulerEvent>,
+}
+
+impl Iterator for RequestStream {
+    type Item = SchedulerEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.events.recv().ok()
+    }
+}
+
+#[derive(Debug)]
+struct Slot {
+    sampler: LlamaSampler,
+    prompt: Vec<LlamaToken>,
+    /// The number of prompt tokens added to a batch so far.
+    n_prefilled: usize,
+    /// The last sampled token, which is decoded in the next step.
+    next: Option<LlamaToken>,
+    n_past: Pos,
+    /// The index of the logits of this request in the current batch.
+    i_batch: Option<i32>,
+    n_generated: u32,
+    max_tokens: Option<u32>,
+    special: Special,
+    decoder: Utf8Decoder,
+    events: Sender<SchedulerEvent>,
+}
+
+/// How many requests a [`Scheduler`] runs at once and how much of the context each may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SlotLimits {
+    /// The number of slots, one per sequence of the context.
+    n_slots: usize,
+    max_concurrency: usize,
+    /// The number of kv cache cells of one slot.
+    n_ctx_slot: u32,
+}
+
+impl SlotLimits {
+    fn new(
+        n_ctx: u32,
+        n_seq_max: u32,
+        n_batch: usize,
+        max_concurrency: Option<NonZeroUsize>,
+    ) -> Self {
+        let n_seq_max = n_seq_max.max(1);
+        let n_slots = usize::try_from(n_seq_max).expect("n_seq_max fits into a usize");
+        let max_concurrency = max_concurrency
+            .map_or(n_slots, NonZeroUsize::get)
+            .min(n_slots)
+            .min(n_batch.max(1));
+        Self {
+            n_slots,
+            max_concurrency,
+            n_ctx_slot: n_ctx / n_seq_max,
+        }
+    }
+
+    /// The free slot for the next queued request, or `None` if all slots are in use or the
+    /// concurrency limit is reached.
+    fn free_slot<T>(&self, slots: &[Option<T>]) -> Option<usize> {
+        if slots.iter().flatten().count() >= self.max_concurrency {
+            return None;
+        }
+        slots.iter().position(Option::is_none)
+    }
+
+    /// Reject prompts that leave no room in the slot for a generated token.
+    fn check_prompt(&self, n_prompt: usize) -> Result<(), SchedulerError> {
+        if u32::try_from(n_prompt).map_or(true, |n_prompt| n_prompt >= self.n_ctx_slot) {
+            return Err(SchedulerError::PromptTooLong {
+                n_prompt,
+                n_ctx_slot: self.n_ctx_slot,
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether a slot that holds `n_past` tokens is full.
+    fn is_full(&self, n_past: Pos) -> bool {
+        u32::try_from(n_past.0).is_ok_and(|n_past| n_past >= self.n_ctx_slot)
+    }
+}
+
+/// Runs the decode loop for the requests of its [`SchedulerHandle`]s, see the
+/// [module documentation](self).
+pub struct Scheduler<'ctx, 'model> {
+    ctx: &'ctx mut LlamaContext<'model>,
+    submissions: Receiver<Submission>,
+    connected: bool,
+    queue: VecDeque<Submission>,
+    /// Indexed by sequence id, `None` for unused ids.
+    slots: Vec<Option<Slot>>,
+    batch: LlamaBatch,
+    n_batch: usize,
+    prefill_chunk: usize,
+    limits: SlotLimits,
+    /// The slot whose prompt is prefilled first in the next step.
+    next_prefill: usize,
+}
+
+impl std::fmt::Debug for Scheduler<'_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Scheduler")
+            .field("n_queued", &self.queue.len())
+            .field("slots", &self.slots)
+            .field("n_batch", &self.n_batch)
+            .field("prefill_chunk", &self.prefill_chunk)
+            .field("limits", &self.limits)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'ctx, 'model> Scheduler<'ctx, 'model> {
+    /// Create a scheduler for `ctx` and the first handle to submit requests with.
+    ///
+    /// # Panics
+    ///
+    /// - `n_batch`, `n_ubatch` or `n_seq_max` does not fit into a usize
+    #[must_use]
+    pub fn new(
+        ctx: &'ctx mut LlamaContext<'model>,
+        params: SchedulerParams,
+    ) -> (Self, SchedulerHandle) {
+        let n_batch = usize::try_from(ctx.n_batch())
+            .expect("n_batch fits into a usize")
+            .max(1);
+        let prefill_chunk = params.prefill_chunk.unwrap_or_else(|| {
+            usize::try_from(ctx.n_ubatch()).expect("n_ubatch fits into a usize")
+        });
+        let limits = SlotLimits::new(
+            ctx.n_ctx(),
+            ctx.n_seq_max(),
+            n_batch,
+            params.max_concurrency,
+        );
+        let (submissions, receiver) = channel();
+        let scheduler = Self {
+            ctx,
+            submissions: receiver,
+            connected: true,
+            queue: VecDeque::new(),
+            slots: (0..limits.n_slots).map(|_| None).collect(),
+            batch: LlamaBatch::new(n_batch, 1),
+            n_batch,
+            prefill_chunk: prefill_chunk.clamp(1, n_batch),
+            limits,
+            next_prefill: 0,
+        };
+        (scheduler, SchedulerHandle { submissions })
+    }
+
+    /// Run until all [`SchedulerHandle`]s are dropped and every request finished, waiting for
+    /// new requests while there is nothing to do.
+    pub fn run(&mut self) {
+        loop {
+            if self.is_idle() {
+                match self.submissions.recv() {
+                    Ok(submission) => self.queue.push_back(submission),
+                    Err(_) => return,
+                }
+            }
+            self.step();
+        }
+    }
+
+    /// Admit queued requests and decode one batch. Returns the number of decoded tokens, which is
+    /// `0` if there is nothing to do.
+    ///
+    /// Errors are reported to the affected requests. If the batch can not be decoded, every
+    /// active request fails with [`SchedulerError::Decode`].
+    pub fn step(&mut self) -> usize {
+        self.receive();
+        self.admit();
+
+        self.batch.clear();
+        let n_tokens = match self.fill_batch() {
+            Ok(n_tokens) => n_tokens,
+            Err(error) => {
+                self.fail_all(|| error.into());
+                return 0;
+            }
+        };
+        if n_tokens == 0 {
+            return 0;
+        }
+        if let Err(error) = self.ctx.decode(&mut self.batch) {
+            self.fail_all(|| error.into());
+            return 0;
+        }
+
+        for index in 0..self.slots.len() {
+            if let Some(event) = self.sample(index) {
+                self.send(index, event);
+            }
+        }
+        n_tokens
+    }
+
+    /// The number of requests that are being decoded.
+    #[must_use]
+    pub fn n_active(&self) -> usize {
+        self.slots.iter().flatten().count()
+    }
+
+    /// The number of requests that wait for a free sequence.
+    #[must_use]
+    pub fn n_queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Whether there are neither active nor queued requests.
+    #[must_use]
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty() && self.n_active() == 0
+    }
+
+    /// Whether there might be more requests, i.e. not all [`SchedulerHandle`]s were dropped.
+    #[must_use]
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn receive(&mut self) {
+        while self.connected {
+            match self.submissions.try_recv() {
+                Ok(submission) => self.queue.push_back(submission),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => self.connected = false,
+            }
+        }
+    }
+
+    /// Move queued requests into free slots, failing those that can not be started.
+    fn admit(&mut self) {
+        while let Some(index) = self.limits.free_slot(&self.slots) {
+            let Some(Submission { request, events }) = self.queue.pop_front() else {
+                return;
+            };
+            match self.start(request, events.clone()) {
+                Ok(slot) => {
+                    let seq_id = seq_id(index);
+                    self.ctx.clear_kv_cache_seq_all(seq_id);
+                    self.slots[index] = Some(slot);
+                }
+                Err(error) => {
+                    let _ = events.send(SchedulerEvent::Failed(error));
+                }
+            }
+        }
+    }
+
+    fn start(
+        &self,
+        request: SchedulerRequest,
+        events: Sender<SchedulerEvent>,
+    ) -> Result<Slot, SchedulerError> {
+        let model = self.ctx.model;
+        let prompt = match request.prompt {
+            Prompt::Text(text, add_bos) => model.str_to_token(&text, add_bos)?,
+            Prompt::Tokens(tokens) => tokens,
+        };
+        if prompt.is_empty() {
+            return Err(SchedulerError::EmptyPrompt);
+        }
+        self.limits.check_prompt(prompt.len())?;
+        Ok(Slot {
+            // SAFETY: the slot is dropped before the scheduler, which borrows the model
+            sampler: unsafe { request.sampler.build(model)? },
+            prompt,
+            n_prefilled: 0,
+            next: None,
+            n_past: Pos(0),
+            i_batch: None,
+            n_generated: 0,
+            max_tokens: request.max_tokens,
+            special: request.special,
+            decoder: Utf8Decoder::default(),
+            events,
+        })
+    }
+
+    /// Add the next token of every generating request, then prompt chunks round-robin.
+    fn fill_batch(&mut self) -> Result<usize, BatchAddError> {
+        let mut n_tokens = 0;
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            let Some(slot) = slot else {
+                continue;
+            };
+            slot.i_batch = None;
+            if let Some(token) = slot.next.take() {
+                slot.i_batch = Some(self.batch.n_tokens());
+                self.batch.add(token, slot.n_past, &[seq_id(index)], true)?;
+                slot.n_past += 1;
+                n_tokens += 1;
+            }
+        }
+
+        let n_slots = self.slots.len();
+        for offset in 0..n_slots {
+            let index = (self.next_prefill + offset) % n_slots;
+            let Some(slot) = &mut self.slots[index] else {
+                continue;
+            };
+            let n_chunk = (slot.prompt.len() - slot.n_prefilled)
+                .min(self.prefill_chunk)
+                .min(self.n_batch - n_tokens);
+            for _ in 0..n_chunk {
+                let token = slot.prompt[slot.n_prefilled];
+                slot.n_prefilled += 1;
+                let is_last = slot.n_prefilled == slot.prompt.len();
+                if is_last {
+                    slot.i_batch = Some(self.batch.n_tokens());
+                }
+                self.batch
+                    .add(token, slot.n_past, &[seq_id(index)], is_last)?;
+                slot.n_past += 1;
+            }
+            n_tokens += n_chunk;
+        }
+        if n_slots > 0 {
+            self.next_prefill = (self.next_prefill + 1) % n_slots;
+        }
+        Ok(n_tokens)
+    }
+
+    /// Sample the next token of the request in slot `index` if its logits are in the batch.
+    fn sample(&mut self, index: usize) -> Option<SchedulerEvent> {
+        let slot = self.slots[index].as_mut()?;
+        let i_batch = slot.i_batch.take()?;
+        let model = self.ctx.model;
+        let token = slot.sampler.sample(self.ctx, i_batch);
+        if model.is_eog_token(token) {
+            return Some(SchedulerEvent::Finished(StopReason::EndOfGeneration(token)));
+        }
+        slot.n_generated += 1;
+        let bytes = match token_to_bytes(model, token, slot.special) {
+            Ok(bytes) => bytes,
+            Err(error) => return Some(SchedulerEvent::Failed(error.into())),
+        };
+        let text = slot.decoder.decode(&bytes);
+        if slot
+            .events
+            .send(SchedulerEvent::Token(GeneratedToken { token, text }))
+            .is_err()
+        {
+            // the stream was dropped
+            self.finish(index);
+            return None;
+        }
+
+        if slot
+            .max_tokens
+            .is_some_and(|max_tokens| slot.n_generated >= max_tokens)
+        {
+            Some(SchedulerEvent::Finished(StopReason::MaxTokens))
+        } else if self.limits.is_full(slot.n_past) {
+            Some(SchedulerEvent::Finished(StopReason::ContextFull))
+        } else {
+            slot.next = Some(token);
+            None
+        }
+    }
+
+    /// Send the final `event` of the request in slot `index` and free its sequence.
+    fn send(&mut self, index: usize, event: SchedulerEvent) {
+        if let Some(slot) = self.finish(index) {
+            let _ = slot.events.send(event);
+        }
+    }
+
+    fn finish(&mut self, index: usize) -> Option<Slot> {
+        let slot = self.slots[index].take()?;
+        self.ctx.clear_kv_cache_seq_all(seq_id(index));
+        Some(slot)
+    }
+
+    /// Fail every active request, as a failed batch leaves their sequences in an unknown state.
+    fn fail_all(&mut self, error: impl Fn() -> SchedulerError) {
+        for index in 0..self.slots.len() {
+            if let Some(slot) = self.finish(index) {
+                let _ = slot.events.send(SchedulerEvent::Failed(error()));
+            }
+        }
+    }
+}
+
+fn seq_id(index: usize) -> SeqId {
+    SeqId(i32::try_from(index).expect("sequence id fits into a i32"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admits_up_to_the_concurrency_limit() {
+        let limits = SlotLimits::new(4096, 4, 512, NonZeroUsize::new(2));
+        assert_eq!(limits.free_slot(&[None, None, None, None::<()>]), Some(0));
+        assert_eq!(limits.free_slot(&[Some(()), None, None, None]), Some(1));
+        assert_eq!(limits.free_slot(&[None, Some(()), None, Some(())]), None);
+
+        let limits = SlotLimits::new(4096, 4, 512, None);
+        assert_eq!(limits.max_concurrency, 4);
+        assert_eq!(limits.free_slot(&[Some(()), None, Some(()), None]), Some(1));
+        assert_eq!(limits.free_slot(&[Some(()); 4]), None);
+    }
+
+    #[test]
+    fn limits_concurrency_to_the_sequences_and_batch() {
+        assert_eq!(
+            SlotLimits::new(4096, 4, 512, NonZeroUsize::new(8)).max_concurrency,
+            4
+        );
+        // every generating request adds a token to each batch
+        assert_eq!(SlotLimits::new(4096, 8, 2, None).max_concurrency, 2);
+    }
+
+    #[test]
+    fn budgets_the_context_per_slot() {
+        let limits = SlotLimits::new(4096, 4, 512, None);
+        assert_eq!(limits.n_ctx_slot, 1024);
+        assert!(limits.check_prompt(1023).is_ok());
+        assert!(matches!(
+            limits.check_prompt(1024),
+            Err(SchedulerError::PromptTooLong {
+                n_prompt: 1024,
+                n_ctx_slot: 1024
+            })
+        ));
+        assert!(!limits.is_full(Pos(1023)));
+        assert!(limits.is_full(Pos(1024)));
+    }
+}
@@ -0,0 +1,200 @@
+//! Self-Extend, see <https://arxiv.org/abs/2401.01325>.
+//!
+//! Self-Extend lets a model attend to sequences longer than it was trained on
+//! ([`LlamaModel::n_ctx_train`](crate::model::LlamaModel::n_ctx_train)) without retraining. Once
+//! a sequence grows past the window of `ga_w` positions, the positions in the window are divided
+//! by the group factor `ga_n` with [`LlamaContext::kv_cache_seq_div`], so `ga_n` neighbouring
+//! tokens share a position. The later tokens are moved back accordingly with
+//! [`LlamaContext::kv_cache_seq_add`]. This is the grouped attention of llama.cpp's `main`
+//! example (`--grp-attn-n` and `--grp-attn-w`).
+//!
+//! The kv cache still holds every token, so the context must be created with an `n_ctx` large
+//! enough for the whole sequence, e.g. `ga_n * n_ctx_train`. `ga_w` is usually set to
+//! `n_ctx_train / 2` or less.
+//!
+//! [`GenerateParams::with_self_extend`](crate::context::generate::GenerateParams::with_self_extend)
+//! enables it for [`LlamaContext::generate`].
+
+use crate::context::LlamaContext;
+use crate::llama_batch::{Pos, SeqId};
+use std::num::NonZeroU8;
+
+/// Invalid parameters for [`SelfExtend::new`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum SelfExtendError {
+    /// The group factor must be at least 2.
+    #[error("the group factor ga_n must be at least 2, got {0}")]
+    GroupFactor(u8),
+    /// The window must be a non-zero multiple of the group factor.
+    #[error("the window ga_w ({ga_w}) must be a non-zero multiple of ga_n ({ga_n})")]
+    Window {
+        /// The group factor.
+        ga_n: u8,
+        /// The window width.
+        ga_w: u32,
+    },
+}
+
+/// The Self-Extend state of one sequence, see the [module documentation](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelfExtend {
+    /// `ga_n`
+    group_factor: NonZeroU8,
+    /// `ga_w`
+    window: i32,
+    /// `ga_i`, the start of the next window.
+    next_window: i32,
+}
+
+/// One compression of the window starting at `ga_i`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Step {
+    ga_i: i32,
+    /// How far the positions before the window already moved back (`ib * bd`).
+    offset: i32,
+    /// How far the tokens after the window move.
+    dd: i32,
+    /// How far `n_past` moves back.
+    bd: i32,
+}
+
+impl SelfExtend {
+    /// Group `ga_n` neighbouring positions in windows of `ga_w` positions.
+    ///
+    /// ```rust
+    /// use bitnet_cpp::context::self_extend::{SelfExtend, SelfExtendError};
+    /// assert!(SelfExtend::new(4, 512).is_ok());
+    /// assert_eq!(SelfExtend::new(4, 510), Err(SelfExtendError::Window { ga_n: 4, ga_w: 510 }));
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// If `ga_n` is less than 2 or `ga_w` is not a non-zero multiple of `ga_n`.
+    pub fn new(ga_n: u8, ga_w: u32) -> Result<Self, SelfExtendError> {
+        let group_factor = NonZeroU8::new(ga_n)
+            .filter(|ga_n| ga_n.get() >= 2)
+            .ok_or(SelfExtendError::GroupFactor(ga_n))?;
+        let window = i32::try_from(ga_w)
+            .ok()
+            .filter(|&window| window > 0 && ga_w.checked_rem(u32::from(ga_n)) == Some(0))
+            .ok_or(SelfExtendError::Window { ga_n, ga_w })?;
+        Ok(Self {
+            group_factor,
+            window,
+            next_window: 0,
+        })
+    }
+
+    /// Get the group factor.
+    #[must_use]
+    pub fn ga_n(&self) -> u8 {
+        self.group_factor.get()
+    }
+
+    /// Get the window width.
+    #[must_use]
+    pub fn ga_w(&self) -> u32 {
+        self.window.unsigned_abs()
+    }
+
+    /// Forget the compressed windows, e.g. after the sequence was cleared.
+    pub fn reset(&mut self) {
+        self.next_window = 0;
+    }
+
+    /// The next compression for a sequence whose next token is placed at `n_past`, if the
+    /// current window is full.
+    fn next_step(&mut self, n_past: i32) -> Option<Step> {
+        let (ga_i, ga_w) = (self.next_window, self.window);
+        if n_past < ga_i + ga_w {
+            return None;
+        }
+        let ga_n = i32::from(self.group_factor.get());
+        let ib = (ga_n * ga_i) / ga_w;
+        let bd = (ga_w / ga_n) * (ga_n - 1);
+        let dd = (ga_w / ga_n) - ib * bd - ga_w;
+        self.next_window += ga_w / ga_n;
+        Some(Step {
+            ga_i,
+            offset: ib * bd,
+            dd,
+            bd,
+        })
+    }
+
+    /// Compress the full windows of the sequence `seq_id`, whose next token would be placed at
+    /// `n_past`. Returns the position the next token should be placed at instead.
+    ///
+    /// Call this before adding tokens to a batch. A batch should hold at most `ga_w` tokens of the
+    /// sequence so that no token is decoded past the end of the window.
+    pub fn apply(&mut self, ctx: &mut LlamaContext, seq_id: SeqId, n_past: Pos) -> Pos {
+        let d = self.group_factor;
+        let Pos(mut n_past) = n_past;
+        while let Some(Step {
+            ga_i,
+            offset,
+            dd,
+            bd,
+        }) = self.next_step(n_past)
+        {
+            let window_start = Pos(ga_i + offset);
+            let window_end = window_start + self.window;
+            ctx.kv_cache_seq_add(seq_id, Pos(ga_i)..Pos(n_past), offset);
+            ctx.kv_cache_seq_div(seq_id, window_start..window_end, d);
+            ctx.kv_cache_seq_add(seq_id, window_end..Pos(n_past + offset), dd);
+            n_past -= bd;
+        }
+        Pos(n_past)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        assert_eq!(
+            SelfExtend::new(1, 512),
+            Err(SelfExtendError::GroupFactor(1))
+        );
+        assert_eq!(
+            SelfExtend::new(4, 0),
+            Err(SelfExtendError::Window { ga_n: 4, ga_w: 0 })
+        );
+    }
+
+    #[test]
+    fn compresses_each_full_window() {
+        let mut self_extend = SelfExtend::new(4, 512).unwrap();
+        assert_eq!(self_extend.next_step(511), None);
+
+        let first = self_extend.next_step(512).unwrap();
+        assert_eq!(
+            first,
+            Step {
+                ga_i: 0,
+                offset: 0,
+                dd: -384,
+                bd: 384,
+            }
+        );
+        // the 512 positions of the window now take 128
+        assert_eq!(self_extend.next_step(512 - first.bd), None);
+
+        let second = self_extend.next_step(640).unwrap();
+        assert_eq!(
+            second,
+            Step {
+                ga_i: 128,
+                offset: 384,
+                dd: -768,
+                bd: 384,
+            }
+        );
+        assert_eq!(self_extend.next_step(640 - second.bd), None);
+
+        self_extend.reset();
+        assert_eq!(self_extend.next_step(512), Some(first));
+    }
+}
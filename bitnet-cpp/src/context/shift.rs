@@ -0,0 +1,129 @@
+//! Make room in a full context by dropping part of the history of a sequence.
+//!
+//! When a sequence reaches the end of the context, a [`ContextShift`] keeps its first `n_keep`
+//! tokens (e.g. the system prompt), removes the oldest of the remaining tokens from the kv cache
+//! and moves the rest back so decoding can continue. The model loses the removed tokens, so the
+//! output may lose track of what was said there.
+//!
+//! [`GenerateParams::with_context_shift`](crate::context::generate::GenerateParams::with_context_shift)
+//! enables it for [`LlamaContext::generate`].
+
+use crate::context::LlamaContext;
+use crate::llama_batch::{Pos, SeqId};
+
+/// A policy for shifting a full context, see the [module documentation](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContextShift {
+    n_keep: u32,
+    n_discard: Option<u32>,
+}
+
+/// Describes a shift done by [`ContextShift::apply`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContextShifted {
+    /// The shifted sequence.
+    pub seq_id: SeqId,
+    /// The number of tokens at the start of the sequence that were kept.
+    pub n_keep: u32,
+    /// The number of tokens that were removed after the first `n_keep`.
+    pub n_discarded: u32,
+    /// The position the next token of the sequence is placed at.
+    pub n_past: Pos,
+}
+
+impl ContextShift {
+    /// Keep the first `n_keep` tokens of a sequence and discard half of the rest.
+    ///
+    /// ```rust
+    /// use bitnet_cpp::context::shift::ContextShift;
+    /// let shift = ContextShift::new(64);
+    /// assert_eq!(shift.n_keep(), 64);
+    /// assert_eq!(shift.n_discard(), None);
+    /// ```
+    #[must_use]
+    pub fn new(n_keep: u32) -> Self {
+        Self {
+            n_keep,
+            n_discard: None,
+        }
+    }
+
+    /// Get the number of tokens at the start of a sequence that are never discarded.
+    #[must_use]
+    pub fn n_keep(&self) -> u32 {
+        self.n_keep
+    }
+
+    /// Set the number of tokens to discard per shift. `None` discards half of the tokens after
+    /// the first `n_keep`.
+    #[must_use]
+    pub fn with_n_discard(mut self, n_discard: Option<u32>) -> Self {
+        self.n_discard = n_discard;
+        self
+    }
+
+    /// Get the number of tokens to discard per shift.
+    #[must_use]
+    pub fn n_discard(&self) -> Option<u32> {
+        self.n_discard
+    }
+
+    /// The number of tokens to discard from a sequence of `n_past` tokens.
+    fn discard_count(&self, n_past: u32) -> u32 {
+        let n_left = n_past.saturating_sub(self.n_keep);
+        self.n_discard.unwrap_or(n_left / 2).min(n_left)
+    }
+
+    /// Shift the sequence `seq_id`, whose next token would be placed at `n_past`. Uses
+    /// [`LlamaContext::clear_kv_cache_seq`] to remove the discarded tokens and
+    /// [`LlamaContext::kv_cache_seq_add`] to move the later ones back.
+    ///
+    /// Returns `None` if there is nothing to discard or the kv cache could not remove the
+    /// discarded tokens.
+    ///
+    /// # Panics
+    ///
+    /// - the number of discarded tokens does not fit into a i32
+    pub fn apply(
+        &self,
+        ctx: &mut LlamaContext,
+        seq_id: SeqId,
+        n_past: Pos,
+    ) -> Option<ContextShifted> {
+        let n_discarded = self.discard_count(u32::try_from(n_past.0).unwrap_or(0));
+        if n_discarded == 0 {
+            return None;
+        }
+        let n_keep = Pos(i32::try_from(self.n_keep).expect("n_keep < n_past fits into a i32"));
+        let n_discard = i32::try_from(n_discarded).expect("n_discard fits into a i32");
+
+        if !ctx.clear_kv_cache_seq(Some(seq_id), n_keep..n_keep + n_discard) {
+            return None;
+        }
+        ctx.kv_cache_seq_add(seq_id, n_keep + n_discard..n_past, -n_discard);
+
+        Some(ContextShifted {
+            seq_id,
+            n_keep: self.n_keep,
+            n_discarded,
+            n_past: n_past - n_discard,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discards_half_of_the_tokens_after_n_keep() {
+        let shift = ContextShift::new(10);
+        assert_eq!(shift.discard_count(110), 50);
+        assert_eq!(shift.discard_count(11), 0);
+        assert_eq!(shift.discard_count(5), 0);
+
+        let shift = shift.with_n_discard(Some(30));
+        assert_eq!(shift.discard_count(110), 30);
+        assert_eq!(shift.discard_count(20), 10);
+    }
+}
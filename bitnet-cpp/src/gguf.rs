@@ -0,0 +1,884 @@
+//! Read GGUF model files without loading them into llama.cpp.
+//!
+//! [`GgufFile`] parses the header, the metadata and the tensor infos of a GGUF file. The tensor
+//! data is not read, so inspecting even a large model is cheap and does not need a
+//! [`LlamaBackend`](crate::llama_backend::LlamaBackend). See [`writer`] to write a copy with
+//! edited metadata.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use bitnet_cpp::gguf::GgufFile;
+//! let gguf = GgufFile::open("models/bitnet_b1_58-large/ggml-model-i2_s.gguf")?;
+//! println!("architecture: {:?}", gguf.architecture());
+//! if let Some(tokens) = gguf.get("tokenizer.ggml.tokens").and_then(|v| v.as_array()) {
+//!     println!("vocab size: {}", tokens.len());
+//! }
+//! for tensor in gguf.tensors() {
+//!     println!("{} {:?} {}", tensor.name(), tensor.dimensions(), tensor.ggml_type());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashSet;
+use std::fmt::{Debug, Display, Formatter};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// The magic number at the start of every GGUF file.
+pub const GGUF_MAGIC: [u8; 4] = *b"GGUF";
+
+/// The GGUF version written by current llama.cpp.
+pub const GGUF_VERSION: u32 = 3;
+
+/// The alignment of the tensor data if the file does not set `general.alignment`.
+pub const GGUF_DEFAULT_ALIGNMENT: u64 = 32;
+
+/// The maximum number of dimensions of a tensor.
+const GGML_MAX_DIMS: u32 = 4;
+
+pub mod writer;
+
+/// Failed to read a GGUF file.
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum GgufError {
+    /// Reading from the underlying reader failed.
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// The file does not start with [`GGUF_MAGIC`].
+    #[error("invalid magic {0:?}, not a GGUF file")]
+    InvalidMagic([u8; 4]),
+    /// Only versions 2 and 3 are supported.
+    #[error("unsupported GGUF version {0}")]
+    UnsupportedVersion(u32),
+    /// The file ended before the end of the header, a value or the tensor data.
+    #[error("the file is truncated at offset {offset}, {needed} more bytes are needed")]
+    Truncated {
+        /// Where the missing data should start.
+        offset: u64,
+        /// The number of missing bytes.
+        needed: u64,
+    },
+    /// A metadata value has an unknown type.
+    #[error("unknown metadata value type {0}")]
+    InvalidValueType(u32),
+    /// A string is not valid utf8.
+    #[error("the string at offset {offset} is not valid utf8")]
+    InvalidUtf8 {
+        /// Where the string starts.
+        offset: u64,
+    },
+    /// A metadata key appears more than once.
+    #[error("duplicate metadata key {0}")]
+    DuplicateKey(String),
+    /// A metadata value has an unexpected type or value, e.g. a `general.alignment` that is not
+    /// a power of two.
+    #[error("invalid metadata value for {key}: {reason}")]
+    InvalidMetadata {
+        /// The key of the value.
+        key: String,
+        /// What is wrong with the value.
+        reason: String,
+    },
+    /// A tensor has an unknown ggml type.
+    #[error("tensor {tensor} has unknown ggml type {ggml_type}")]
+    InvalidGgmlType {
+        /// The name of the tensor.
+        tensor: String,
+        /// The type id.
+        ggml_type: u32,
+    },
+    /// A tensor info is inconsistent, e.g. it has too many dimensions or its data is not aligned.
+    #[error("invalid tensor {tensor}: {reason}")]
+    InvalidTensor {
+        /// The name of the tensor.
+        tensor: String,
+        /// What is wrong with the tensor.
+        reason: String,
+    },
+}
+
+/// The type of a metadata value, `gguf_type` in ggml.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(missing_docs)]
+pub enum GgufValueType {
+    U8 = 0,
+    I8 = 1,
+    U16 = 2,
+    I16 = 3,
+    U32 = 4,
+    I32 = 5,
+    F32 = 6,
+    Bool = 7,
+    String = 8,
+    Array = 9,
+    U64 = 10,
+    I64 = 11,
+    F64 = 12,
+}
+
+impl TryFrom<u32> for GgufValueType {
+    type Error = GgufError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::U8,
+            1 => Self::I8,
+            2 => Self::U16,
+            3 => Self::I16,
+            4 => Self::U32,
+            5 => Self::I32,
+            6 => Self::F32,
+            7 => Self::Bool,
+            8 => Self::String,
+            9 => Self::Array,
+            10 => Self::U64,
+            11 => Self::I64,
+            12 => Self::F64,
+            unknown => return Err(GgufError::InvalidValueType(unknown)),
+        })
+    }
+}
+
+/// A metadata value of a GGUF file.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(missing_docs)]
+pub enum GgufValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+    String(String),
+    /// An array of values of the same type.
+    Array {
+        /// The type of the items, also for an empty array.
+        item_type: GgufValueType,
+        /// The items.
+        items: Vec<GgufValue>,
+    },
+    U64(u64),
+    I64(i64),
+    F64(f64),
+}
+
+impl GgufValue {
+    /// The type of the value.
+    #[must_use]
+    pub fn value_type(&self) -> GgufValueType {
+        match self {
+            Self::U8(_) => GgufValueType::U8,
+            Self::I8(_) => GgufValueType::I8,
+            Self::U16(_) => GgufValueType::U16,
+            Self::I16(_) => GgufValueType::I16,
+            Self::U32(_) => GgufValueType::U32,
+            Self::I32(_) => GgufValueType::I32,
+            Self::F32(_) => GgufValueType::F32,
+            Self::Bool(_) => GgufValueType::Bool,
+            Self::String(_) => GgufValueType::String,
+            Self::Array { .. } => GgufValueType::Array,
+            Self::U64(_) => GgufValueType::U64,
+            Self::I64(_) => GgufValueType::I64,
+            Self::F64(_) => GgufValueType::F64,
+        }
+    }
+
+    /// The value if it is a string.
+    #[must_use]
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The value if it is an integer that fits into an `i64`.
+    #[must_use]
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Self::U8(value) => Some(value.into()),
+            Self::I8(value) => Some(value.into()),
+            Self::U16(value) => Some(value.into()),
+            Self::I16(value) => Some(value.into()),
+            Self::U32(value) => Some(value.into()),
+            Self::I32(value) => Some(value.into()),
+            Self::U64(value) => i64::try_from(value).ok(),
+            Self::I64(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The value if it is a non-negative integer.
+    #[must_use]
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Self::U64(value) => Some(value),
+            _ => self.as_i64().and_then(|value| u64::try_from(value).ok()),
+        }
+    }
+
+    /// The value if it is a number, integers are converted.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Self::F32(value) => Some(value.into()),
+            Self::F64(value) => Some(value),
+            Self::U64(value) => Some(value as f64),
+            _ => self.as_i64().map(|value| value as f64),
+        }
+    }
+
+    /// The value if it is a boolean.
+    #[must_use]
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Self::Bool(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The items if the value is an array.
+    #[must_use]
+    pub fn as_array(&self) -> Option<&[GgufValue]> {
+        match self {
+            Self::Array { items, .. } => Some(items),
+            _ => None,
+        }
+    }
+}
+
+/// The type of the data of a tensor, `ggml_type` in ggml. Includes the ternary types of
+/// bitnet.cpp.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(missing_docs, non_camel_case_types)]
+pub enum GgmlType {
+    F32 = 0,
+    F16 = 1,
+    Q4_0 = 2,
+    Q4_1 = 3,
+    Q5_0 = 6,
+    Q5_1 = 7,
+    Q8_0 = 8,
+    Q8_1 = 9,
+    Q2_K = 10,
+    Q3_K = 11,
+    Q4_K = 12,
+    Q5_K = 13,
+    Q6_K = 14,
+    Q8_K = 15,
+    IQ2_XXS = 16,
+    IQ2_XS = 17,
+    IQ3_XXS = 18,
+    IQ1_S = 19,
+    IQ4_NL = 20,
+    IQ3_S = 21,
+    IQ2_S = 22,
+    IQ4_XS = 23,
+    I8 = 24,
+    I16 = 25,
+    I32 = 26,
+    I64 = 27,
+    F64 = 28,
+    IQ1_M = 29,
+    BF16 = 30,
+    Q4_0_4_4 = 31,
+    Q4_0_4_8 = 32,
+    Q4_0_8_8 = 33,
+    TQ1_0 = 34,
+    TQ2_0 = 35,
+    /// 2 bit ternary weights with a per tensor scale, the portable bitnet.cpp format.
+    I2_S = 36,
+    /// 8 bit activations of the bitnet.cpp kernels.
+    I8_S = 37,
+    /// Ternary weights packed for the ARM lookup table kernel.
+    TL1 = 38,
+    /// Ternary weights packed for the x86 lookup table kernel.
+    TL2 = 39,
+}
+
+impl GgmlType {
+    const ALL: [Self; 38] = [
+        Self::F32,
+        Self::F16,
+        Self::Q4_0,
+        Self::Q4_1,
+        Self::Q5_0,
+        Self::Q5_1,
+        Self::Q8_0,
+        Self::Q8_1,
+        Self::Q2_K,
+        Self::Q3_K,
+        Self::Q4_K,
+        Self::Q5_K,
+        Self::Q6_K,
+        Self::Q8_K,
+        Self::IQ2_XXS,
+        Self::IQ2_XS,
+        Self::IQ3_XXS,
+        Self::IQ1_S,
+        Self::IQ4_NL,
+        Self::IQ3_S,
+        Self::IQ2_S,
+        Self::IQ4_XS,
+        Self::I8,
+        Self::I16,
+        Self::I32,
+        Self::I64,
+        Self::F64,
+        Self::IQ1_M,
+        Self::BF16,
+        Self::Q4_0_4_4,
+        Self::Q4_0_4_8,
+        Self::Q4_0_8_8,
+        Self::TQ1_0,
+        Self::TQ2_0,
+        Self::I2_S,
+        Self::I8_S,
+        Self::TL1,
+        Self::TL2,
+    ];
+
+    /// The number of elements per block and the size of a block in bytes, `None` for the
+    /// bitnet.cpp types, which are not stored in blocks.
+    #[must_use]
+    pub fn block_layout(self) -> Option<(u64, u64)> {
+        Some(match self {
+            Self::F32 | Self::I32 => (1, 4),
+            Self::F16 | Self::BF16 | Self::I16 => (1, 2),
+            Self::I8 => (1, 1),
+            Self::I64 | Self::F64 => (1, 8),
+            Self::Q4_0 | Self::IQ4_NL | Self::Q4_0_4_4 | Self::Q4_0_4_8 | Self::Q4_0_8_8 => {
+                (32, 18)
+            }
+            Self::Q4_1 => (32, 20),
+            Self::Q5_0 => (32, 22),
+            Self::Q5_1 => (32, 24),
+            Self::Q8_0 => (32, 34),
+            Self::Q8_1 => (32, 36),
+            Self::Q2_K => (256, 84),
+            Self::Q3_K | Self::IQ3_S => (256, 110),
+            Self::Q4_K => (256, 144),
+            Self::Q5_K => (256, 176),
+            Self::Q6_K => (256, 210),
+            Self::Q8_K => (256, 292),
+            Self::IQ2_XXS | Self::TQ2_0 => (256, 66),
+            Self::IQ2_XS => (256, 74),
+            Self::IQ3_XXS => (256, 98),
+            Self::IQ1_S => (256, 50),
+            Self::IQ2_S => (256, 82),
+            Self::IQ4_XS => (256, 136),
+            Self::IQ1_M => (256, 56),
+            Self::TQ1_0 => (256, 54),
+            Self::I2_S | Self::I8_S | Self::TL1 | Self::TL2 => return None,
+        })
+    }
+}
+
+impl TryFrom<u32> for GgmlType {
+    type Error = u32;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        Self::ALL
+            .into_iter()
+            .find(|&ggml_type| ggml_type as u32 == value)
+            .ok_or(value)
+    }
+}
+
+impl Display for GgmlType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(self, f)
+    }
+}
+
+/// The description of a tensor in a GGUF file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct GgufTensorInfo {
+    pub(crate) name: String,
+    pub(crate) dimensions: Vec<u64>,
+    pub(crate) ggml_type: GgmlType,
+    pub(crate) offset: u64,
+}
+
+impl GgufTensorInfo {
+    /// The name of the tensor, e.g. `blk.0.attn_q.weight`.
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The number of elements in each dimension, innermost first.
+    #[must_use]
+    pub fn dimensions(&self) -> &[u64] {
+        &self.dimensions
+    }
+
+    /// The type of the tensor data.
+    #[must_use]
+    pub fn ggml_type(&self) -> GgmlType {
+        self.ggml_type
+    }
+
+    /// The offset of the tensor data from the start of the data section.
+    #[must_use]
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The number of elements of the tensor.
+    #[must_use]
+    pub fn n_elements(&self) -> u64 {
+        self.dimensions.iter().product()
+    }
+
+    /// The size of the tensor data in bytes, if it is known for the type, see
+    /// [`GgmlType::block_layout`]. `I2_S` packs four elements into a byte followed by a 32 byte
+    /// scale, the size of the other bitnet.cpp types depends on the kernel layout.
+    #[must_use]
+    pub fn size_in_bytes(&self) -> Option<u64> {
+        if self.ggml_type == GgmlType::I2_S {
+            return Some(self.n_elements() / 4 + 32);
+        }
+        let (block_size, type_size) = self.ggml_type.block_layout()?;
+        Some(self.n_elements() / block_size * type_size)
+    }
+}
+
+/// The header, metadata and tensor infos of a GGUF file, see the [module documentation](self).
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct GgufFile {
+    pub(crate) version: u32,
+    pub(crate) metadata: Vec<(String, GgufValue)>,
+    pub(crate) tensors: Vec<GgufTensorInfo>,
+    pub(crate) alignment: u64,
+    pub(crate) data_offset: u64,
+}
+
+impl GgufFile {
+    /// Read the GGUF file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// See [`GgufError`].
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, GgufError> {
+        Self::read(&mut BufReader::new(File::open(path)?))
+    }
+
+    /// Read a GGUF file starting at the current position of `reader`. Offsets are relative to
+    /// that position.
+    ///
+    /// # Errors
+    ///
+    /// See [`GgufError`].
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Self, GgufError> {
+        let start = reader.stream_position()?;
+        let len = reader.seek(SeekFrom::End(0))?.saturating_sub(start);
+        reader.seek(SeekFrom::Start(start))?;
+        let mut reader = GgufReader {
+            reader,
+            offset: 0,
+            len,
+        };
+
+        let magic: [u8; 4] = reader.array()?;
+        if magic != GGUF_MAGIC {
+            return Err(GgufError::InvalidMagic(magic));
+        }
+        let version = reader.u32()?;
+        if !(2..=GGUF_VERSION).contains(&version) {
+            return Err(GgufError::UnsupportedVersion(version));
+        }
+        let n_tensors = reader.u64()?;
+        let n_kv = reader.u64()?;
+
+        let mut metadata: Vec<(String, GgufValue)> = Vec::new();
+        for _ in 0..n_kv {
+            let key = reader.string()?;
+            if metadata.iter().any(|(existing, _)| *existing == key) {
+                return Err(GgufError::DuplicateKey(key));
+            }
+            let value_type = GgufValueType::try_from(reader.u32()?)?;
+            let value = reader.value(&key, value_type)?;
+            metadata.push((key, value));
+        }
+
+        let alignment = alignment(&metadata)?;
+
+        let mut tensors: Vec<GgufTensorInfo> = Vec::new();
+        let mut names = HashSet::new();
+        for _ in 0..n_tensors {
+            let tensor = reader.tensor_info(alignment)?;
+            if !names.insert(tensor.name.clone()) {
+                return Err(invalid_tensor(&tensor.name, "duplicate name"));
+            }
+            tensors.push(tensor);
+        }
+
+        let data_offset = reader.offset.next_multiple_of(alignment);
+        let data_len = len.saturating_sub(data_offset);
+        for tensor in &tensors {
+            // at least the start of a tensor of unknown size must be within the data
+            let size = tensor.size_in_bytes().unwrap_or(0);
+            let end = tensor.offset.checked_add(size).ok_or_else(|| {
+                invalid_tensor(&tensor.name, "the end of the data overflows a u64")
+            })?;
+            if end > data_len {
+                return Err(GgufError::Truncated {
+                    offset: data_offset + data_len,
+                    needed: end - data_len,
+                });
+            }
+        }
+
+        Ok(Self {
+            version,
+            metadata,
+            tensors,
+            alignment,
+            data_offset,
+        })
+    }
+
+    /// The GGUF version of the file.
+    #[must_use]
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// All metadata entries in the order of the file.
+    #[must_use]
+    pub fn metadata(&self) -> &[(String, GgufValue)] {
+        &self.metadata
+    }
+
+    /// The metadata value of `key`.
+    #[must_use]
+    pub fn get(&self, key: &str) -> Option<&GgufValue> {
+        self.metadata
+            .iter()
+            .find(|(existing, _)| existing == key)
+            .map(|(_, value)| value)
+    }
+
+    /// The architecture of the model (`general.architecture`), which prefixes most other keys.
+    #[must_use]
+    pub fn architecture(&self) -> Option<&str> {
+        self.get("general.architecture")?.as_str()
+    }
+
+    /// The name of the model (`general.name`).
+    #[must_use]
+    pub fn name(&self) -> Option<&str> {
+        self.get("general.name")?.as_str()
+    }
+
+    /// All tensor infos in the order of the file.
+    #[must_use]
+    pub fn tensors(&self) -> &[GgufTensorInfo] {
+        &self.tensors
+    }
+
+    /// The tensor info named `name`.
+    #[must_use]
+    pub fn tensor(&self, name: &str) -> Option<&GgufTensorInfo> {
+        self.tensors.iter().find(|tensor| tensor.name == name)
+    }
+
+    /// The alignment of the tensor data.
+    #[must_use]
+    pub fn alignment(&self) -> u64 {
+        self.alignment
+    }
+
+    /// The offset of the data section from the start of the file. Tensor offsets are relative
+    /// to it.
+    #[must_use]
+    pub fn data_offset(&self) -> u64 {
+        self.data_offset
+    }
+}
+
+/// The alignment set by `general.alignment`, or the default.
+fn alignment(metadata: &[(String, GgufValue)]) -> Result<u64, GgufError> {
+    match metadata.iter().find(|(key, _)| key == "general.alignment") {
+        None => Ok(GGUF_DEFAULT_ALIGNMENT),
+        Some((_, GgufValue::U32(alignment))) if alignment.is_power_of_two() => {
+            Ok(u64::from(*alignment))
+        }
+        Some((key, value)) => Err(GgufError::InvalidMetadata {
+            key: key.clone(),
+            reason: format!("expected a power of two u32, got {value:?}"),
+        }),
+    }
+}
+
+fn invalid_tensor(tensor: &str, reason: impl Into<String>) -> GgufError {
+    GgufError::InvalidTensor {
+        tensor: tensor.to_owned(),
+        reason: reason.into(),
+    }
+}
+
+/// Reads little endian values, checking every length against the remaining bytes before
+/// allocating.
+struct GgufReader<'a, R> {
+    reader: &'a mut R,
+    offset: u64,
+    len: u64,
+}
+
+impl<R: Read> GgufReader<'_, R> {
+    fn ensure(&self, needed: u64) -> Result<(), GgufError> {
+        let remaining = self.len.saturating_sub(self.offset);
+        if needed > remaining {
+            return Err(GgufError::Truncated {
+                offset: self.offset,
+                needed: needed - remaining,
+            });
+        }
+        Ok(())
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], GgufError> {
+        self.ensure(N as u64)?;
+        let mut buf = [0; N];
+        self.reader.read_exact(&mut buf)?;
+        self.offset += N as u64;
+        Ok(buf)
+    }
+
+    fn u32(&mut self) -> Result<u32, GgufError> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64, GgufError> {
+        self.array().map(u64::from_le_bytes)
+    }
+
+    fn string(&mut self) -> Result<String, GgufError> {
+        let len = self.u64()?;
+        self.ensure(len)?;
+        let offset = self.offset;
+        let mut buf = vec![0; usize::try_from(len).expect("a length within the file fits a usize")];
+        self.reader.read_exact(&mut buf)?;
+        self.offset += len;
+        String::from_utf8(buf).map_err(|_| GgufError::InvalidUtf8 { offset })
+    }
+
+    fn value(&mut self, key: &str, value_type: GgufValueType) -> Result<GgufValue, GgufError> {
+        Ok(match value_type {
+            GgufValueType::U8 => GgufValue::U8(u8::from_le_bytes(self.array()?)),
+            GgufValueType::I8 => GgufValue::I8(i8::from_le_bytes(self.array()?)),
+            GgufValueType::U16 => GgufValue::U16(u16::from_le_bytes(self.array()?)),
+            GgufValueType::I16 => GgufValue::I16(i16::from_le_bytes(self.array()?)),
+            GgufValueType::U32 => GgufValue::U32(self.u32()?),
+            GgufValueType::I32 => GgufValue::I32(i32::from_le_bytes(self.array()?)),
+            GgufValueType::F32 => GgufValue::F32(f32::from_le_bytes(self.array()?)),
+            GgufValueType::Bool => match self.array::<1>()? {
+                [0] => GgufValue::Bool(false),
+                [1] => GgufValue::Bool(true),
+                [other] => {
+                    return Err(GgufError::InvalidMetadata {
+                        key: key.to_owned(),
+                        reason: format!(
+                            "expected a bool of 0 or 1 at offset {}, got {other}",
+                            self.offset - 1
+                        ),
+                    })
+                }
+            },
+            GgufValueType::String => GgufValue::String(self.string()?),
+            GgufValueType::Array => {
+                let item_type = GgufValueType::try_from(self.u32()?)?;
+                if item_type == GgufValueType::Array {
+                    return Err(GgufError::InvalidMetadata {
+                        key: key.to_owned(),
+                        reason: "nested arrays are not supported".to_owned(),
+                    });
+                }
+                let n_items = self.u64()?;
+                // every item takes at least one byte
+                self.ensure(n_items)?;
+                let items = (0..n_items)
+                    .map(|_| self.value(key, item_type))
+                    .collect::<Result<_, _>>()?;
+                GgufValue::Array { item_type, items }
+            }
+            GgufValueType::U64 => GgufValue::U64(self.u64()?),
+            GgufValueType::I64 => GgufValue::I64(i64::from_le_bytes(self.array()?)),
+            GgufValueType::F64 => GgufValue::F64(f64::from_le_bytes(self.array()?)),
+        })
+    }
+
+    fn tensor_info(&mut self, alignment: u64) -> Result<GgufTensorInfo, GgufError> {
+        let name = self.string()?;
+        let n_dims = self.u32()?;
+        if n_dims > GGML_MAX_DIMS {
+            return Err(invalid_tensor(
+                &name,
+                format!("{n_dims} dimensions, at most {GGML_MAX_DIMS} are supported"),
+            ));
+        }
+        let dimensions = (0..n_dims)
+            .map(|_| self.u64())
+            .collect::<Result<Vec<_>, _>>()?;
+        if dimensions
+            .iter()
+            .try_fold(1u64, |n, &dim| n.checked_mul(dim))
+            .is_none_or(|n| i64::try_from(n).is_err())
+        {
+            return Err(invalid_tensor(&name, "the number of elements overflows"));
+        }
+        let ggml_type =
+            GgmlType::try_from(self.u32()?).map_err(|ggml_type| GgufError::InvalidGgmlType {
+                tensor: name.clone(),
+                ggml_type,
+            })?;
+        if let (Some((block_size, _)), Some(&ne0)) = (ggml_type.block_layout(), dimensions.first())
+        {
+            if ne0.checked_rem(block_size) != Some(0) {
+                return Err(invalid_tensor(
+                    &name,
+                    format!("{ne0} elements per row is not a multiple of the {ggml_type} block size {block_size}"),
+                ));
+            }
+        }
+        let offset = self.u64()?;
+        if offset.checked_rem(alignment) != Some(0) {
+            return Err(invalid_tensor(
+                &name,
+                format!("the data offset {offset} is not aligned to {alignment} bytes"),
+            ));
+        }
+        Ok(GgufTensorInfo {
+            name,
+            dimensions,
+            ggml_type,
+            offset,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn string(buf: &mut Vec<u8>, value: &str) {
+        buf.extend((value.len() as u64).to_le_bytes());
+        buf.extend(value.as_bytes());
+    }
+
+    /// A file with two metadata entries and an `F32` and an `I2_S` tensor.
+    pub(super) fn sample(i2_s_type: u32) -> Vec<u8> {
+        let mut buf = b"GGUF".to_vec();
+        buf.extend(3u32.to_le_bytes());
+        buf.extend(2u64.to_le_bytes());
+        buf.extend(2u64.to_le_bytes());
+        string(&mut buf, "general.architecture");
+        buf.extend(8u32.to_le_bytes());
+        string(&mut buf, "bitnet");
+        string(&mut buf, "tokenizer.ggml.tokens");
+        buf.extend(9u32.to_le_bytes());
+        buf.extend(8u32.to_le_bytes());
+        buf.extend(2u64.to_le_bytes());
+        string(&mut buf, "<s>");
+        string(&mut buf, "</s>");
+        for (name, dims, ggml_type, offset) in [
+            ("output_norm.weight", &[4u64][..], 0u32, 0u64),
+            ("output.weight", &[4, 2], i2_s_type, 32),
+        ] {
+            string(&mut buf, name);
+            buf.extend(u32::try_from(dims.len()).unwrap().to_le_bytes());
+            for dim in dims {
+                buf.extend(dim.to_le_bytes());
+            }
+            buf.extend(ggml_type.to_le_bytes());
+            buf.extend(offset.to_le_bytes());
+        }
+        buf.resize(buf.len().next_multiple_of(32), 0);
+        buf.extend((0..96).map(|i: u8| i + 1));
+        buf
+    }
+
+    #[test]
+    fn reads_metadata_and_tensor_infos() {
+        let bytes = sample(GgmlType::I2_S as u32);
+        let gguf = GgufFile::read(&mut Cursor::new(&bytes)).unwrap();
+
+        assert_eq!(gguf.version(), 3);
+        assert_eq!(gguf.architecture(), Some("bitnet"));
+        let tokens: Vec<_> = gguf
+            .get("tokenizer.ggml.tokens")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|token| token.as_str().unwrap())
+            .collect();
+        assert_eq!(tokens, ["<s>", "</s>"]);
+
+        assert_eq!(gguf.data_offset(), bytes.len() as u64 - 96);
+        let norm = gguf.tensor("output_norm.weight").unwrap();
+        assert_eq!(norm.size_in_bytes(), Some(16));
+        let output = gguf.tensor("output.weight").unwrap();
+        assert_eq!(output.dimensions(), [4, 2]);
+        assert_eq!(output.ggml_type(), GgmlType::I2_S);
+        assert_eq!(output.offset(), 32);
+        assert_eq!(output.size_in_bytes(), Some(34));
+    }
+
+    #[test]
+    fn rejects_corrupt_files() {
+        let bytes = sample(GgmlType::I2_S as u32);
+        let read = |bytes: &[u8]| GgufFile::read(&mut Cursor::new(bytes));
+
+        assert!(matches!(
+            read(&bytes[..40]),
+            Err(GgufError::Truncated { offset: 32, .. })
+        ));
+        // the data of output_norm.weight is cut off
+        assert!(matches!(
+            read(&bytes[..bytes.len() - 92]),
+            Err(GgufError::Truncated { needed: 12, .. })
+        ));
+        // the data of an I2_S tensor includes its scale
+        assert!(matches!(
+            read(&bytes[..bytes.len() - 32]),
+            Err(GgufError::Truncated { needed: 2, .. })
+        ));
+        // a tensor of unknown size must start within the data
+        let tl1 = sample(GgmlType::TL1 as u32);
+        assert!(matches!(
+            read(&tl1[..tl1.len() - 80]),
+            Err(GgufError::Truncated { needed: 16, .. })
+        ));
+        let mut bool_value = bytes.clone();
+        // turn `general.architecture` into a bool of 2
+        let type_offset = 24 + 8 + "general.architecture".len();
+        bool_value[type_offset..type_offset + 4].copy_from_slice(&7u32.to_le_bytes());
+        bool_value[type_offset + 4] = 2;
+        assert!(matches!(
+            read(&bool_value),
+            Err(GgufError::InvalidMetadata { key, .. }) if key == "general.architecture"
+        ));
+        assert!(matches!(
+            read(b"GGML\x03\0\0\0"),
+            Err(GgufError::InvalidMagic(magic)) if magic == *b"GGML"
+        ));
+        assert!(matches!(
+            read(&sample(99)),
+            Err(GgufError::InvalidGgmlType { ggml_type: 99, .. })
+        ));
+    }
+}
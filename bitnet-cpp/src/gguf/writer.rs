@@ -0,0 +1,365 @@
+//! Write a GGUF file with edited metadata.
+//!
+//! The tensor data is copied unchanged from the file the [`GgufFile`] was read from, so a model
+//! can be patched without the Python `gguf` package:
+//!
+//! ```no_run
+//! # fn main() -> Result<(), bitnet_cpp::gguf::GgufError> {
+//! use bitnet_cpp::gguf::{GgufFile, GgufValue};
+//! GgufFile::edit_file("ggml-model-i2_s.gguf", "patched.gguf", |gguf| {
+//!     gguf.set("general.name", GgufValue::String("BitNet b1.58 2B".to_owned()));
+//!     gguf.set("tokenizer.ggml.eos_token_id", GgufValue::U32(128_009));
+//!     gguf.remove("tokenizer.chat_template");
+//! })?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::gguf::{
+    alignment, GgufError, GgufFile, GgufValue, GgufValueType, GGUF_MAGIC, GGUF_VERSION,
+};
+
+impl GgufFile {
+    /// Set the metadata value of `key`, keeping its position if it exists and appending it
+    /// otherwise. Returns the previous value.
+    pub fn set(&mut self, key: impl Into<String>, value: GgufValue) -> Option<GgufValue> {
+        let key = key.into();
+        if let Some((_, existing)) = self
+            .metadata
+            .iter_mut()
+            .find(|(existing, _)| *existing == key)
+        {
+            return Some(std::mem::replace(existing, value));
+        }
+        self.metadata.push((key, value));
+        None
+    }
+
+    /// Remove the metadata value of `key`. Returns the removed value.
+    pub fn remove(&mut self, key: &str) -> Option<GgufValue> {
+        let index = self
+            .metadata
+            .iter()
+            .position(|(existing, _)| existing == key)?;
+        Some(self.metadata.remove(index).1)
+    }
+
+    /// Write the file to `dest`, copying the tensor data from `source`, the file this was read
+    /// from. `source` must be positioned at the start of the file, as for [`GgufFile::read`].
+    ///
+    /// The tensors keep their order. They are aligned to `general.alignment`, so changing it
+    /// moves the tensor data. Read the written file again to get the new offsets.
+    ///
+    /// The metadata is checked before anything is written, so invalid metadata leaves `dest`
+    /// untouched.
+    ///
+    /// # Errors
+    ///
+    /// - reading `source` or writing `dest` failed
+    /// - `source` is shorter than the tensor data
+    /// - `general.alignment` is not a power of two u32
+    /// - an array has items of a different type than its `item_type`, or nested arrays
+    #[allow(clippy::missing_panics_doc)] // the reader rejects tensors with more than 4 dimensions
+    pub fn write<R: Read + Seek, W: Write>(
+        &self,
+        source: &mut R,
+        dest: &mut W,
+    ) -> Result<(), GgufError> {
+        let start = source.stream_position()?;
+        let data_len = source
+            .seek(SeekFrom::End(0))?
+            .saturating_sub(start + self.data_offset);
+        let alignment = alignment(&self.metadata)?;
+        for (key, value) in &self.metadata {
+            check_value(key, value)?;
+        }
+        let mut dest = CountingWriter {
+            writer: dest,
+            written: 0,
+        };
+
+        // the region of each tensor in the source data section, including padding if the size
+        // of the type is unknown
+        let mut by_offset: Vec<_> = self.tensors.iter().collect();
+        by_offset.sort_by_key(|tensor| tensor.offset);
+        let mut regions = Vec::with_capacity(by_offset.len());
+        let mut new_offset = 0;
+        for (i, tensor) in by_offset.iter().enumerate() {
+            let next = by_offset.get(i + 1).map_or(data_len, |next| next.offset);
+            let len = tensor
+                .size_in_bytes()
+                .unwrap_or_else(|| next.saturating_sub(tensor.offset));
+            regions.push((tensor.name.as_str(), tensor.offset, len, new_offset));
+            new_offset = (new_offset + len).next_multiple_of(alignment);
+        }
+        let new_offset_of = |name: &str| {
+            regions
+                .iter()
+                .find(|(region, ..)| *region == name)
+                .map_or(0, |&(.., new_offset)| new_offset)
+        };
+
+        dest.write_all(&GGUF_MAGIC)?;
+        dest.write_all(&GGUF_VERSION.to_le_bytes())?;
+        dest.write_all(&(self.tensors.len() as u64).to_le_bytes())?;
+        dest.write_all(&(self.metadata.len() as u64).to_le_bytes())?;
+        for (key, value) in &self.metadata {
+            dest.write_string(key)?;
+            dest.write_all(&(value.value_type() as u32).to_le_bytes())?;
+            dest.write_value(value)?;
+        }
+        for tensor in &self.tensors {
+            dest.write_string(&tensor.name)?;
+            let n_dims = u32::try_from(tensor.dimensions.len()).expect("at most 4 dimensions");
+            dest.write_all(&n_dims.to_le_bytes())?;
+            for dim in &tensor.dimensions {
+                dest.write_all(&dim.to_le_bytes())?;
+            }
+            dest.write_all(&(tensor.ggml_type as u32).to_le_bytes())?;
+            dest.write_all(&new_offset_of(&tensor.name).to_le_bytes())?;
+        }
+        dest.pad(alignment)?;
+
+        let data_start = dest.written;
+        for (_, offset, len, new_offset) in regions {
+            debug_assert_eq!(dest.written, data_start + new_offset);
+            source.seek(SeekFrom::Start(start + self.data_offset + offset))?;
+            let copied = std::io::copy(&mut source.by_ref().take(len), &mut dest)?;
+            if copied < len {
+                return Err(GgufError::Truncated {
+                    offset: self.data_offset + offset + copied,
+                    needed: len - copied,
+                });
+            }
+            dest.pad(alignment)?;
+        }
+        dest.flush()?;
+        Ok(())
+    }
+
+    /// Read the GGUF file at `source`, apply `edit` and write the result to `dest`.
+    ///
+    /// The file is written next to `dest` first and renamed once it is complete, so `dest` is
+    /// never left half written.
+    ///
+    /// # Errors
+    ///
+    /// - `source` and `dest` are the same file
+    /// - see [`GgufFile::open`] and [`GgufFile::write`]
+    pub fn edit_file(
+        source: impl AsRef<Path>,
+        dest: impl AsRef<Path>,
+        edit: impl FnOnce(&mut GgufFile),
+    ) -> Result<(), GgufError> {
+        let (source, dest) = (source.as_ref(), dest.as_ref());
+        if dest.exists() && std::fs::canonicalize(source)? == std::fs::canonicalize(dest)? {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "can not write a GGUF file over the file it is read from",
+            )
+            .into());
+        }
+        let mut reader = BufReader::new(File::open(source)?);
+        let mut gguf = Self::read(&mut reader)?;
+        edit(&mut gguf);
+        reader.seek(SeekFrom::Start(0))?;
+
+        let mut temp_name = dest.file_name().unwrap_or_default().to_owned();
+        temp_name.push(".tmp");
+        let temp = dest.with_file_name(temp_name);
+        let written = File::create(&temp)
+            .map_err(GgufError::from)
+            .and_then(|file| {
+                let mut writer = BufWriter::new(file);
+                gguf.write(&mut reader, &mut writer)?;
+                writer
+                    .into_inner()
+                    .map_err(std::io::IntoInnerError::into_error)?
+                    .sync_all()?;
+                Ok(())
+            })
+            .and_then(|()| Ok(std::fs::rename(&temp, dest)?));
+        if written.is_err() {
+            let _ = std::fs::remove_file(&temp);
+        }
+        written
+    }
+}
+
+/// Tracks the number of written bytes for the alignment padding.
+struct CountingWriter<'a, W> {
+    writer: &'a mut W,
+    written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> CountingWriter<'_, W> {
+    fn pad(&mut self, alignment: u64) -> std::io::Result<()> {
+        let padding = self.written.next_multiple_of(alignment) - self.written;
+        std::io::copy(&mut std::io::repeat(0).take(padding), self).map(drop)
+    }
+
+    fn write_string(&mut self, value: &str) -> std::io::Result<()> {
+        self.write_all(&(value.len() as u64).to_le_bytes())?;
+        self.write_all(value.as_bytes())
+    }
+
+    fn write_value(&mut self, value: &GgufValue) -> Result<(), GgufError> {
+        match value {
+            GgufValue::U8(value) => self.write_all(&value.to_le_bytes())?,
+            GgufValue::I8(value) => self.write_all(&value.to_le_bytes())?,
+            GgufValue::U16(value) => self.write_all(&value.to_le_bytes())?,
+            GgufValue::I16(value) => self.write_all(&value.to_le_bytes())?,
+            GgufValue::U32(value) => self.write_all(&value.to_le_bytes())?,
+            GgufValue::I32(value) => self.write_all(&value.to_le_bytes())?,
+            GgufValue::F32(value) => self.write_all(&value.to_le_bytes())?,
+            GgufValue::Bool(value) => self.write_all(&[u8::from(*value)])?,
+            GgufValue::String(value) => self.write_string(value)?,
+            GgufValue::Array { item_type, items } => {
+                self.write_all(&(*item_type as u32).to_le_bytes())?;
+                self.write_all(&(items.len() as u64).to_le_bytes())?;
+                for item in items {
+                    self.write_value(item)?;
+                }
+            }
+            GgufValue::U64(value) => self.write_all(&value.to_le_bytes())?,
+            GgufValue::I64(value) => self.write_all(&value.to_le_bytes())?,
+            GgufValue::F64(value) => self.write_all(&value.to_le_bytes())?,
+        }
+        Ok(())
+    }
+}
+
+/// Check that the arrays of a value can be written, see [`GgufFile::write`].
+fn check_value(key: &str, value: &GgufValue) -> Result<(), GgufError> {
+    let GgufValue::Array { item_type, items } = value else {
+        return Ok(());
+    };
+    if *item_type == GgufValueType::Array {
+        return Err(invalid_array(key, "nested arrays are not supported"));
+    }
+    if items.iter().any(|item| item.value_type() != *item_type) {
+        return Err(invalid_array(
+            key,
+            &format!("all items must be of type {item_type:?}"),
+        ));
+    }
+    Ok(())
+}
+
+fn invalid_array(key: &str, reason: &str) -> GgufError {
+    GgufError::InvalidMetadata {
+        key: key.to_owned(),
+        reason: reason.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gguf::tests::sample;
+    use crate::gguf::GgmlType;
+    use std::io::Cursor;
+
+    fn tensor_data(bytes: &[u8], gguf: &GgufFile, name: &str, len: usize) -> Vec<u8> {
+        let start =
+            usize::try_from(gguf.data_offset() + gguf.tensor(name).unwrap().offset()).unwrap();
+        bytes[start..start + len].to_vec()
+    }
+
+    #[test]
+    fn copies_tensor_data_with_edited_metadata() {
+        let bytes = sample(GgmlType::I2_S as u32);
+        let mut gguf = GgufFile::read(&mut Cursor::new(&bytes)).unwrap();
+        gguf.set("general.name", GgufValue::String("patched".to_owned()));
+        assert!(gguf.remove("tokenizer.ggml.tokens").is_some());
+        gguf.set("general.alignment", GgufValue::U32(64));
+
+        let mut written = Vec::new();
+        gguf.write(&mut Cursor::new(&bytes), &mut written).unwrap();
+        let patched = GgufFile::read(&mut Cursor::new(&written)).unwrap();
+
+        let keys: Vec<_> = patched
+            .metadata()
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .collect();
+        assert_eq!(
+            keys,
+            ["general.architecture", "general.name", "general.alignment"]
+        );
+        assert_eq!(patched.name(), Some("patched"));
+        assert_eq!(patched.alignment(), 64);
+        assert_eq!(patched.data_offset() % 64, 0);
+        assert_eq!(patched.tensor("output.weight").unwrap().offset(), 64);
+        for (name, len) in [("output_norm.weight", 16), ("output.weight", 32)] {
+            assert_eq!(
+                tensor_data(&written, &patched, name, len),
+                tensor_data(&bytes, &gguf, name, len)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_mixed_arrays() {
+        let bytes = sample(GgmlType::I2_S as u32);
+        let mut gguf = GgufFile::read(&mut Cursor::new(&bytes)).unwrap();
+        gguf.set(
+            "tokenizer.ggml.scores",
+            GgufValue::Array {
+                item_type: GgufValueType::F32,
+                items: vec![GgufValue::F32(0.0), GgufValue::I32(1)],
+            },
+        );
+        let mut written = Vec::new();
+        assert!(matches!(
+            gguf.write(&mut Cursor::new(&bytes), &mut written),
+            Err(GgufError::InvalidMetadata { key, .. }) if key == "tokenizer.ggml.scores"
+        ));
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn edit_file_leaves_no_partial_output() {
+        let dir = std::env::temp_dir().join(format!("gguf-writer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (source, dest) = (dir.join("source.gguf"), dir.join("dest.gguf"));
+        std::fs::write(&source, sample(GgmlType::I2_S as u32)).unwrap();
+
+        let result = GgufFile::edit_file(&source, &dest, |gguf| {
+            gguf.set(
+                "tokenizer.ggml.scores",
+                GgufValue::Array {
+                    item_type: GgufValueType::Array,
+                    items: Vec::new(),
+                },
+            );
+        });
+        assert!(matches!(result, Err(GgufError::InvalidMetadata { .. })));
+        assert!(!dest.exists());
+        assert!(!dir.join("dest.gguf.tmp").exists());
+
+        GgufFile::edit_file(&source, &dest, |gguf| {
+            gguf.set("general.name", GgufValue::String("patched".to_owned()));
+        })
+        .unwrap();
+        assert_eq!(GgufFile::open(&dest).unwrap().name(), Some("patched"));
+        assert!(!dir.join("dest.gguf.tmp").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
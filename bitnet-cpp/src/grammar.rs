@@ -0,0 +1,587 @@
+//! A native Rust parser for GBNF grammars.
+//!
+//! This is a translation of the grammar parser in `llama.cpp/common/grammar-parser.cpp` to rust. It
+//! turns a GBNF grammar into the same flat `llama_grammar_element` rules llama.cpp uses internally,
+//! which makes it possible to validate a grammar (with line and column information on errors)
+//! before handing it to llama.cpp.
+//!
+//! ```
+//! # use std::str::FromStr;
+//! use bitnet_cpp::grammar::LlamaGrammar;
+//!
+//! let grammar = LlamaGrammar::from_str(r#"root ::= "yes" | "no""#).unwrap();
+//! assert!(grammar.contains_rule("root"));
+//!
+//! let err = LlamaGrammar::from_str("root ::= \"yes\" |\nanswer").unwrap_err();
+//! assert_eq!((err.line, err.column), (2, 1));
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use bitnet_cpp_sys::grammar::{
+    llama_grammar_element, llama_gretype, LLAMA_GRETYPE_ALT, LLAMA_GRETYPE_CHAR,
+    LLAMA_GRETYPE_CHAR_ALT, LLAMA_GRETYPE_CHAR_ANY, LLAMA_GRETYPE_CHAR_NOT,
+    LLAMA_GRETYPE_CHAR_RNG_UPPER, LLAMA_GRETYPE_END, LLAMA_GRETYPE_RULE_REF,
+};
+
+#[cfg(feature = "json-schema")]
+pub mod json_schema;
+#[cfg(test)]
+mod tests;
+
+/// A parsed GBNF grammar.
+///
+/// Keeps the original source around, as that is what llama.cpp consumes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct LlamaGrammar {
+    parse: ParseState,
+    grammar: String,
+}
+
+/// The result of parsing a grammar: the symbol table and the rules, indexed by symbol id.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ParseState {
+    symbol_ids: BTreeMap<String, u32>,
+    rules: Vec<Vec<llama_grammar_element>>,
+}
+
+/// An error that occurred while parsing a grammar, along with where it occurred.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("{kind} at line {line}, column {column}")]
+pub struct GrammarParseError {
+    /// What went wrong.
+    pub kind: GrammarParseErrorKind,
+    /// The 1-based line the error occurred on.
+    pub line: usize,
+    /// The 1-based column (in characters) the error occurred on.
+    pub column: usize,
+}
+
+/// The different kinds of [`GrammarParseError`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum GrammarParseErrorKind {
+    /// A rule name was expected.
+    #[error("expecting name")]
+    ExpectingName,
+    /// A rule name was not followed by `::=`.
+    #[error("expecting ::=")]
+    ExpectingAssignment,
+    /// A rule was not terminated by a newline or the end of the input.
+    #[error("expecting newline or end")]
+    ExpectingNewline,
+    /// The input ended in the middle of a literal or a character class.
+    #[error("unexpected end of input")]
+    UnexpectedEndOfInput,
+    /// An unknown escape sequence was used.
+    #[error("unknown escape \\{0}")]
+    UnknownEscape(char),
+    /// A `\x`, `\u` or `\U` escape did not have enough hex digits, or was not a valid code point.
+    #[error("expecting {0} hex chars")]
+    InvalidHexEscape(usize),
+    /// A group was not closed.
+    #[error("expecting ')'")]
+    ExpectingCloseParen,
+    /// A repetition operator (`*`, `+`, `?` or `{m,n}`) had nothing to repeat.
+    #[error("expecting preceding item to */+/?/{{")]
+    ExpectingRepetitionItem,
+    /// A repetition bound was not an integer (or did not fit into one).
+    #[error("expecting an int")]
+    ExpectingInt,
+    /// A repetition bound was not followed by `,` or `}`.
+    #[error("expecting ','")]
+    ExpectingComma,
+    /// A repetition was not closed.
+    #[error("expecting '}}'")]
+    ExpectingCloseBrace,
+    /// A rule was referenced but never defined.
+    #[error("undefined rule identifier '{0}'")]
+    UndefinedRule(String),
+}
+
+impl ParseState {
+    /// The ids of all symbols (named and generated rules) in the grammar.
+    #[must_use]
+    pub fn symbol_ids(&self) -> &BTreeMap<String, u32> {
+        &self.symbol_ids
+    }
+
+    /// The rules of the grammar, indexed by symbol id. Every rule ends with `LLAMA_GRETYPE_END`.
+    #[must_use]
+    pub fn rules(&self) -> &[Vec<llama_grammar_element>] {
+        &self.rules
+    }
+}
+
+impl FromStr for ParseState {
+    type Err = GrammarParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Parser::new(s).parse()
+    }
+}
+
+impl LlamaGrammar {
+    /// The GBNF source this grammar was parsed from.
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.grammar
+    }
+
+    /// The parsed rules of this grammar.
+    #[must_use]
+    pub fn parse_state(&self) -> &ParseState {
+        &self.parse
+    }
+
+    /// The symbol id of the rule `name`, if the grammar defines it.
+    #[must_use]
+    pub fn symbol_id(&self, name: &str) -> Option<u32> {
+        self.parse.symbol_ids.get(name).copied()
+    }
+
+    /// Check if the grammar defines the rule `name` (e.g. the root rule).
+    #[must_use]
+    pub fn contains_rule(&self, name: &str) -> bool {
+        self.symbol_id(name).is_some()
+    }
+}
+
+impl FromStr for LlamaGrammar {
+    type Err = GrammarParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            parse: ParseState::from_str(s)?,
+            grammar: s.to_owned(),
+        })
+    }
+}
+
+impl Display for LlamaGrammar {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.grammar)
+    }
+}
+
+fn element(type_: llama_gretype, value: u32) -> llama_grammar_element {
+    llama_grammar_element { type_, value }
+}
+
+fn is_word_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'-'
+}
+
+/// A recursive descent parser over the grammar source. All positions are byte offsets into `src`.
+struct Parser<'a> {
+    src: &'a str,
+    state: ParseState,
+    /// The position each symbol was first referenced at, used to report undefined rules.
+    references: BTreeMap<u32, usize>,
+}
+
+type ParseResult<T> = Result<T, GrammarParseError>;
+
+impl<'a> Parser<'a> {
+    fn new(src: &'a str) -> Self {
+        Self {
+            src,
+            state: ParseState::default(),
+            references: BTreeMap::new(),
+        }
+    }
+
+    fn parse(mut self) -> ParseResult<ParseState> {
+        let mut pos = self.parse_space(0, true);
+        while pos < self.src.len() {
+            pos = self.parse_rule(pos)?;
+        }
+
+        // Validate that every referenced rule was defined.
+        let undefined = self
+            .state
+            .symbol_ids
+            .iter()
+            .filter(|(_, &id)| self.state.rules.get(id as usize).is_none_or(Vec::is_empty))
+            .map(|(name, id)| (self.references.get(id).copied().unwrap_or(0), name))
+            .min();
+        if let Some((pos, name)) = undefined {
+            return Err(self.error(GrammarParseErrorKind::UndefinedRule(name.clone()), pos));
+        }
+
+        Ok(self.state)
+    }
+
+    fn error(&self, kind: GrammarParseErrorKind, pos: usize) -> GrammarParseError {
+        let before = &self.src[..pos];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        GrammarParseError {
+            kind,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    fn byte(&self, pos: usize) -> Option<u8> {
+        self.src.as_bytes().get(pos).copied()
+    }
+
+    fn get_symbol_id(&mut self, name: &str) -> u32 {
+        let next_id = u32::try_from(self.state.symbol_ids.len()).expect("too many symbols");
+        *self
+            .state
+            .symbol_ids
+            .entry(name.to_owned())
+            .or_insert(next_id)
+    }
+
+    fn generate_symbol_id(&mut self, base_name: &str) -> u32 {
+        let next_id = u32::try_from(self.state.symbol_ids.len()).expect("too many symbols");
+        self.state
+            .symbol_ids
+            .insert(format!("{base_name}_{next_id}"), next_id);
+        next_id
+    }
+
+    fn add_rule(&mut self, rule_id: u32, rule: Vec<llama_grammar_element>) {
+        let rule_id = rule_id as usize;
+        if self.state.rules.len() <= rule_id {
+            self.state.rules.resize(rule_id + 1, Vec::new());
+        }
+        self.state.rules[rule_id] = rule;
+    }
+
+    fn parse_space(&self, mut pos: usize, newline_ok: bool) -> usize {
+        loop {
+            match self.byte(pos) {
+                Some(b' ' | b'\t') => pos += 1,
+                Some(b'\r' | b'\n') if newline_ok => pos += 1,
+                Some(b'#') => {
+                    while !matches!(self.byte(pos), None | Some(b'\r' | b'\n')) {
+                        pos += 1;
+                    }
+                }
+                _ => return pos,
+            }
+        }
+    }
+
+    fn parse_name(&self, pos: usize) -> ParseResult<usize> {
+        let end = pos
+            + self.src.as_bytes()[pos..]
+                .iter()
+                .take_while(|&&c| is_word_char(c))
+                .count();
+        if end == pos {
+            return Err(self.error(GrammarParseErrorKind::ExpectingName, pos));
+        }
+        Ok(end)
+    }
+
+    fn parse_int(&self, pos: usize) -> ParseResult<(usize, usize)> {
+        let end = pos
+            + self.src.as_bytes()[pos..]
+                .iter()
+                .take_while(|c| c.is_ascii_digit())
+                .count();
+        self.src[pos..end]
+            .parse()
+            .map(|value| (value, end))
+            .map_err(|_| self.error(GrammarParseErrorKind::ExpectingInt, pos))
+    }
+
+    fn parse_hex(&self, pos: usize, size: usize) -> ParseResult<(u32, usize)> {
+        let digits = self.src.as_bytes()[pos..]
+            .iter()
+            .take(size)
+            .take_while(|c| c.is_ascii_hexdigit())
+            .count();
+        if digits != size {
+            return Err(self.error(GrammarParseErrorKind::InvalidHexEscape(size), pos));
+        }
+        let value = u32::from_str_radix(&self.src[pos..pos + size], 16)
+            .map_err(|_| self.error(GrammarParseErrorKind::InvalidHexEscape(size), pos))?;
+        Ok((value, pos + size))
+    }
+
+    /// Parse a single (possibly escaped) character, returning its code point and the position after it.
+    fn parse_char(&self, pos: usize) -> ParseResult<(u32, usize)> {
+        let mut chars = self.src[pos..].chars();
+        match chars.next() {
+            Some('\\') => match chars.next() {
+                Some('x') => self.parse_hex(pos + 2, 2),
+                Some('u') => self.parse_hex(pos + 2, 4),
+                Some('U') => self.parse_hex(pos + 2, 8),
+                Some('t') => Ok(('\t' as u32, pos + 2)),
+                Some('r') => Ok(('\r' as u32, pos + 2)),
+                Some('n') => Ok(('\n' as u32, pos + 2)),
+                Some(c @ ('\\' | '"' | '[' | ']')) => Ok((c as u32, pos + 2)),
+                Some(c) => Err(self.error(GrammarParseErrorKind::UnknownEscape(c), pos)),
+                None => Err(self.error(GrammarParseErrorKind::UnexpectedEndOfInput, pos)),
+            },
+            Some(c) => Ok((c as u32, pos + c.len_utf8())),
+            None => Err(self.error(GrammarParseErrorKind::UnexpectedEndOfInput, pos)),
+        }
+    }
+
+    /// Rewrite the last symbol of `out` (starting at `last_sym_start`) to be repeated between
+    /// `min_times` and `max_times` (unbounded if `None`) times:
+    ///
+    /// ```text
+    /// S{m,n} --> S S S (m times) S'(n-m)
+    ///            S'(n-m) ::= S S'(n-m-1) |
+    ///            (... n-m definitions of these S' rules ...)
+    ///            S'(1) ::= S |
+    /// S{m,}  --> S S S (m times) S'
+    ///            S' ::= S S' |
+    /// S*     --> S{0,}
+    /// S+     --> S{1,}
+    /// S?     --> S{0,1}
+    /// ```
+    fn handle_repetitions(
+        &mut self,
+        out: &mut Vec<llama_grammar_element>,
+        last_sym_start: usize,
+        rule_name: &str,
+        min_times: usize,
+        max_times: Option<usize>,
+        pos: usize,
+    ) -> ParseResult<()> {
+        if last_sym_start == out.len() {
+            return Err(self.error(GrammarParseErrorKind::ExpectingRepetitionItem, pos));
+        }
+
+        let prev_rule = out[last_sym_start..].to_vec();
+        if min_times == 0 {
+            out.truncate(last_sym_start);
+        } else {
+            for _ in 1..min_times {
+                out.extend_from_slice(&prev_rule);
+            }
+        }
+
+        let n_opt = max_times.map_or(1, |max_times| max_times.saturating_sub(min_times));
+        let mut last_rec_rule_id = 0;
+        for i in 0..n_opt {
+            let mut rec_rule = prev_rule.clone();
+            let rec_rule_id = self.generate_symbol_id(rule_name);
+            if i > 0 || max_times.is_none() {
+                let next = if max_times.is_none() {
+                    rec_rule_id
+                } else {
+                    last_rec_rule_id
+                };
+                rec_rule.push(element(LLAMA_GRETYPE_RULE_REF, next));
+            }
+            rec_rule.push(element(LLAMA_GRETYPE_ALT, 0));
+            rec_rule.push(element(LLAMA_GRETYPE_END, 0));
+            self.add_rule(rec_rule_id, rec_rule);
+            last_rec_rule_id = rec_rule_id;
+        }
+        if n_opt > 0 {
+            out.push(element(LLAMA_GRETYPE_RULE_REF, last_rec_rule_id));
+        }
+        Ok(())
+    }
+
+    /// Parse the inside of a character class starting after the `[`, returning the position of the
+    /// closing `]`.
+    fn parse_char_class(
+        &self,
+        mut pos: usize,
+        out: &mut Vec<llama_grammar_element>,
+    ) -> ParseResult<usize> {
+        let mut start_type = LLAMA_GRETYPE_CHAR;
+        if self.byte(pos) == Some(b'^') {
+            pos += 1;
+            start_type = LLAMA_GRETYPE_CHAR_NOT;
+        }
+        let class_start = out.len();
+        while self.byte(pos) != Some(b']') {
+            let (value, next) = self.parse_char(pos)?;
+            pos = next;
+            let type_ = if class_start < out.len() {
+                LLAMA_GRETYPE_CHAR_ALT
+            } else {
+                start_type
+            };
+            out.push(element(type_, value));
+            if self.byte(pos) == Some(b'-') && self.byte(pos + 1) != Some(b']') {
+                let (end, next) = self.parse_char(pos + 1)?;
+                pos = next;
+                out.push(element(LLAMA_GRETYPE_CHAR_RNG_UPPER, end));
+            }
+        }
+        Ok(pos)
+    }
+
+    /// Parse `{m}`, `{m,}` or `{m,n}` starting at the `{`, returning the bounds and the position
+    /// after the closing `}`.
+    fn parse_repetition_bounds(
+        &self,
+        pos: usize,
+        is_nested: bool,
+    ) -> ParseResult<(usize, Option<usize>, usize)> {
+        let pos = self.parse_space(pos + 1, is_nested);
+        let (min_times, int_end) = self.parse_int(pos)?;
+        let mut pos = self.parse_space(int_end, is_nested);
+
+        let max_times = match self.byte(pos) {
+            Some(b'}') => Some(min_times),
+            Some(b',') => {
+                pos = self.parse_space(pos + 1, is_nested);
+                let mut max_times = None;
+                if self.byte(pos).is_some_and(|c| c.is_ascii_digit()) {
+                    let (value, int_end) = self.parse_int(pos)?;
+                    max_times = Some(value);
+                    pos = self.parse_space(int_end, is_nested);
+                }
+                if self.byte(pos) != Some(b'}') {
+                    return Err(self.error(GrammarParseErrorKind::ExpectingCloseBrace, pos));
+                }
+                max_times
+            }
+            _ => return Err(self.error(GrammarParseErrorKind::ExpectingComma, pos)),
+        };
+        Ok((min_times, max_times, pos + 1))
+    }
+
+    fn parse_sequence(
+        &mut self,
+        mut pos: usize,
+        rule_name: &str,
+        out: &mut Vec<llama_grammar_element>,
+        is_nested: bool,
+    ) -> ParseResult<usize> {
+        let mut last_sym_start = out.len();
+        while let Some(c) = self.byte(pos) {
+            match c {
+                // literal string
+                b'"' => {
+                    pos += 1;
+                    last_sym_start = out.len();
+                    while self.byte(pos) != Some(b'"') {
+                        let (value, next) = self.parse_char(pos)?;
+                        out.push(element(LLAMA_GRETYPE_CHAR, value));
+                        pos = next;
+                    }
+                    pos = self.parse_space(pos + 1, is_nested);
+                }
+                // char range(s)
+                b'[' => {
+                    last_sym_start = out.len();
+                    pos = self.parse_char_class(pos + 1, out)?;
+                    pos = self.parse_space(pos + 1, is_nested);
+                }
+                // rule reference
+                c if is_word_char(c) => {
+                    let name_end = self.parse_name(pos)?;
+                    let src = self.src;
+                    let ref_rule_id = self.get_symbol_id(&src[pos..name_end]);
+                    self.references.entry(ref_rule_id).or_insert(pos);
+                    pos = self.parse_space(name_end, is_nested);
+                    last_sym_start = out.len();
+                    out.push(element(LLAMA_GRETYPE_RULE_REF, ref_rule_id));
+                }
+                // grouping: parse nested alternates into synthesized rule
+                b'(' => {
+                    pos = self.parse_space(pos + 1, true);
+                    let sub_rule_id = self.generate_symbol_id(rule_name);
+                    pos = self.parse_alternates(pos, rule_name, sub_rule_id, true)?;
+                    last_sym_start = out.len();
+                    out.push(element(LLAMA_GRETYPE_RULE_REF, sub_rule_id));
+                    if self.byte(pos) != Some(b')') {
+                        return Err(self.error(GrammarParseErrorKind::ExpectingCloseParen, pos));
+                    }
+                    pos = self.parse_space(pos + 1, is_nested);
+                }
+                // any char
+                b'.' => {
+                    last_sym_start = out.len();
+                    out.push(element(LLAMA_GRETYPE_CHAR_ANY, 0));
+                    pos = self.parse_space(pos + 1, is_nested);
+                }
+                b'*' | b'+' | b'?' => {
+                    let (min_times, max_times) = match c {
+                        b'*' => (0, None),
+                        b'+' => (1, None),
+                        _ => (0, Some(1)),
+                    };
+                    self.handle_repetitions(
+                        out,
+                        last_sym_start,
+                        rule_name,
+                        min_times,
+                        max_times,
+                        pos,
+                    )?;
+                    pos = self.parse_space(pos + 1, is_nested);
+                }
+                b'{' => {
+                    let (min_times, max_times, end) =
+                        self.parse_repetition_bounds(pos, is_nested)?;
+                    self.handle_repetitions(
+                        out,
+                        last_sym_start,
+                        rule_name,
+                        min_times,
+                        max_times,
+                        pos,
+                    )?;
+                    pos = self.parse_space(end, is_nested);
+                }
+                _ => break,
+            }
+        }
+        Ok(pos)
+    }
+
+    fn parse_alternates(
+        &mut self,
+        pos: usize,
+        rule_name: &str,
+        rule_id: u32,
+        is_nested: bool,
+    ) -> ParseResult<usize> {
+        let mut rule = Vec::new();
+        let mut pos = self.parse_sequence(pos, rule_name, &mut rule, is_nested)?;
+        while self.byte(pos) == Some(b'|') {
+            rule.push(element(LLAMA_GRETYPE_ALT, 0));
+            pos = self.parse_space(pos + 1, true);
+            pos = self.parse_sequence(pos, rule_name, &mut rule, is_nested)?;
+        }
+        rule.push(element(LLAMA_GRETYPE_END, 0));
+        self.add_rule(rule_id, rule);
+        Ok(pos)
+    }
+
+    fn parse_rule(&mut self, pos: usize) -> ParseResult<usize> {
+        let src = self.src;
+        let name_end = self.parse_name(pos)?;
+        let name = &src[pos..name_end];
+        let rule_id = self.get_symbol_id(name);
+
+        let pos = self.parse_space(name_end, false);
+        if !self.src[pos..].starts_with("::=") {
+            return Err(self.error(GrammarParseErrorKind::ExpectingAssignment, pos));
+        }
+        let pos = self.parse_space(pos + 3, true);
+        let mut pos = self.parse_alternates(pos, name, rule_id, false)?;
+
+        match self.byte(pos) {
+            Some(b'\r') => {
+                pos += if self.byte(pos + 1) == Some(b'\n') {
+                    2
+                } else {
+                    1
+                }
+            }
+            Some(b'\n') => pos += 1,
+            Some(_) => return Err(self.error(GrammarParseErrorKind::ExpectingNewline, pos)),
+            None => {}
+        }
+        Ok(self.parse_space(pos, true))
+    }
+}
@@ -0,0 +1,1133 @@
+//! Convert a JSON Schema into a GBNF grammar.
+//!
+//! This is a translation of the `SchemaConverter` in `llama.cpp/common/json-schema-to-grammar.cpp`
+//! to rust. The generated grammar only accepts JSON documents matching the schema and can be handed
+//! to [`LlamaSampler::with_grammar`](crate::context::sampler::LlamaSampler::with_grammar) with a
+//! root of `"root"`.
+//!
+//! Supported are objects (`properties`, `required`, `additionalProperties`, `allOf`), `enum`,
+//! `const`, `oneOf`/`anyOf`, arrays (`items`, `prefixItems`, `minItems`, `maxItems`), strings
+//! (`pattern`, `format`, `minLength`, `maxLength`), the primitive types and local `$ref`s such as
+//! `#/$defs/...`. Remote references are not fetched.
+//!
+//! ```
+//! use bitnet_cpp::grammar::json_schema::json_schema_to_grammar;
+//! use serde_json::json;
+//!
+//! let gbnf = json_schema_to_grammar(&json!({
+//!     "type": "object",
+//!     "properties": { "answer": { "enum": ["yes", "no"] } },
+//!     "required": ["answer"]
+//! }))
+//! .unwrap();
+//! assert!(gbnf.contains(r#"answer ::= ("\"yes\"" | "\"no\"") space"#));
+//! ```
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+use std::str::FromStr;
+
+use serde_json::{Map, Value};
+
+use super::{GrammarParseError, LlamaGrammar};
+
+/// An error that occurred while converting a JSON Schema into a grammar.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum JsonSchemaToGrammarError {
+    /// A `$ref` that is not local to the schema (e.g. `https://...`) was used.
+    #[error("unsupported ref {0}")]
+    UnsupportedRef(String),
+    /// A local `$ref` pointed to something that does not exist in the schema.
+    #[error("error resolving ref {0}")]
+    UnresolvedRef(String),
+    /// A string `pattern` could not be translated.
+    #[error("invalid pattern {pattern:?}: {reason}")]
+    InvalidPattern {
+        /// The offending pattern.
+        pattern: String,
+        /// Why it could not be translated.
+        reason: &'static str,
+    },
+    /// A (sub-)schema uses a type or combination of keywords that has no grammar equivalent.
+    #[error("unrecognized schema: {0}")]
+    UnrecognizedSchema(String),
+    /// The generated grammar was rejected by the grammar parser. This happens when a `pattern` uses
+    /// regex syntax that passes through the translation but has no GBNF equivalent.
+    #[error("{0}")]
+    InvalidGrammar(#[from] GrammarParseError),
+}
+
+type Result<T> = std::result::Result<T, JsonSchemaToGrammarError>;
+
+/// Convert `schema` into a GBNF grammar whose root rule is `root`.
+///
+/// # Errors
+///
+/// See [`JsonSchemaToGrammarError`].
+pub fn json_schema_to_grammar(schema: &Value) -> Result<String> {
+    let mut converter = SchemaConverter {
+        root: schema.clone(),
+        ..SchemaConverter::default()
+    };
+    converter.visit(schema, "")?;
+    converter.add_rule("space", SPACE_RULE);
+    Ok(converter.format_grammar())
+}
+
+impl LlamaGrammar {
+    /// Build a grammar accepting JSON documents that match `schema`. The root rule is `root`.
+    ///
+    /// # Errors
+    ///
+    /// See [`JsonSchemaToGrammarError`].
+    pub fn from_json_schema(schema: &Value) -> Result<Self> {
+        Ok(Self::from_str(&json_schema_to_grammar(schema)?)?)
+    }
+}
+
+const SPACE_RULE: &str = r#"| " " | "\n" [ \t]{0,20}"#;
+
+struct BuiltinRule {
+    content: &'static str,
+    deps: &'static [&'static str],
+}
+
+const PRIMITIVE_RULES: &[(&str, BuiltinRule)] = &[
+    (
+        "boolean",
+        BuiltinRule {
+            content: r#"("true" | "false") space"#,
+            deps: &[],
+        },
+    ),
+    (
+        "decimal-part",
+        BuiltinRule {
+            content: "[0-9]{1,16}",
+            deps: &[],
+        },
+    ),
+    (
+        "integral-part",
+        BuiltinRule {
+            content: "[0] | [1-9] [0-9]{0,15}",
+            deps: &[],
+        },
+    ),
+    (
+        "number",
+        BuiltinRule {
+            content: r#"("-"? integral-part) ("." decimal-part)? ([eE] [-+]? integral-part)? space"#,
+            deps: &["integral-part", "decimal-part"],
+        },
+    ),
+    (
+        "integer",
+        BuiltinRule {
+            content: r#"("-"? integral-part) space"#,
+            deps: &["integral-part"],
+        },
+    ),
+    (
+        "value",
+        BuiltinRule {
+            content: "object | array | string | number | boolean | null",
+            deps: &["object", "array", "string", "number", "boolean", "null"],
+        },
+    ),
+    (
+        "object",
+        BuiltinRule {
+            content: r#""{" space ( string ":" space value ("," space string ":" space value)* )? "}" space"#,
+            deps: &["string", "value"],
+        },
+    ),
+    (
+        "array",
+        BuiltinRule {
+            content: r#""[" space ( value ("," space value)* )? "]" space"#,
+            deps: &["value"],
+        },
+    ),
+    (
+        "uuid",
+        BuiltinRule {
+            content: r#""\"" [0-9a-fA-F]{8} "-" [0-9a-fA-F]{4} "-" [0-9a-fA-F]{4} "-" [0-9a-fA-F]{4} "-" [0-9a-fA-F]{12} "\"" space"#,
+            deps: &[],
+        },
+    ),
+    (
+        "char",
+        BuiltinRule {
+            content: r#"[^"\\\x7F\x00-\x1F] | [\\] (["\\bfnrt] | "u" [0-9a-fA-F]{4})"#,
+            deps: &[],
+        },
+    ),
+    (
+        "string",
+        BuiltinRule {
+            content: r#""\"" char* "\"" space"#,
+            deps: &["char"],
+        },
+    ),
+    (
+        "null",
+        BuiltinRule {
+            content: r#""null" space"#,
+            deps: &[],
+        },
+    ),
+];
+
+const STRING_FORMAT_RULES: &[(&str, BuiltinRule)] = &[
+    (
+        "date",
+        BuiltinRule {
+            content: r#"[0-9]{4} "-" ( "0" [1-9] | "1" [0-2] ) "-" ( "0" [1-9] | [1-2] [0-9] | "3" [0-1] )"#,
+            deps: &[],
+        },
+    ),
+    (
+        "time",
+        BuiltinRule {
+            content: r#"([01] [0-9] | "2" [0-3]) ":" [0-5] [0-9] ":" [0-5] [0-9] ( "." [0-9]{3} )? ( "Z" | ( "+" | "-" ) ( [01] [0-9] | "2" [0-3] ) ":" [0-5] [0-9] )"#,
+            deps: &[],
+        },
+    ),
+    (
+        "date-time",
+        BuiltinRule {
+            content: r#"date "T" time"#,
+            deps: &["date", "time"],
+        },
+    ),
+    (
+        "date-string",
+        BuiltinRule {
+            content: r#""\"" date "\"" space"#,
+            deps: &["date"],
+        },
+    ),
+    (
+        "time-string",
+        BuiltinRule {
+            content: r#""\"" time "\"" space"#,
+            deps: &["time"],
+        },
+    ),
+    (
+        "date-time-string",
+        BuiltinRule {
+            content: r#""\"" date-time "\"" space"#,
+            deps: &["date-time"],
+        },
+    ),
+];
+
+fn primitive_rule(name: &str) -> Option<&'static BuiltinRule> {
+    PRIMITIVE_RULES
+        .iter()
+        .find_map(|(n, rule)| (*n == name).then_some(rule))
+}
+
+fn string_format_rule(name: &str) -> Option<&'static BuiltinRule> {
+    STRING_FORMAT_RULES
+        .iter()
+        .find_map(|(n, rule)| (*n == name).then_some(rule))
+}
+
+fn is_reserved_name(name: &str) -> bool {
+    name == "root" || primitive_rule(name).is_some() || string_format_rule(name).is_some()
+}
+
+/// Replace every run of characters that are not allowed in rule names with a single `-`.
+fn escape_rule_name(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    let mut in_invalid_run = false;
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() || c == '-' {
+            escaped.push(c);
+            in_invalid_run = false;
+        } else if !in_invalid_run {
+            escaped.push('-');
+            in_invalid_run = true;
+        }
+    }
+    escaped
+}
+
+/// Quote `literal` as a GBNF string literal.
+fn format_literal(literal: &str) -> String {
+    let mut out = String::with_capacity(literal.len() + 2);
+    out.push('"');
+    for c in literal.chars() {
+        out.push_str(&escape_literal(c));
+    }
+    out.push('"');
+    out
+}
+
+/// Escape `c` for use inside a GBNF string literal.
+fn escape_literal(c: char) -> String {
+    match c {
+        '\r' => r"\r".to_owned(),
+        '\n' => r"\n".to_owned(),
+        '"' => r#"\""#.to_owned(),
+        '\\' => r"\\".to_owned(),
+        c => c.to_string(),
+    }
+}
+
+/// Escape `c` for use inside a GBNF character class.
+fn escape_char_class(c: char) -> String {
+    match c {
+        '\r' => r"\r".to_owned(),
+        '\n' => r"\n".to_owned(),
+        '"' => r#"\""#.to_owned(),
+        '\\' => r"\\".to_owned(),
+        '[' => r"\[".to_owned(),
+        ']' => r"\]".to_owned(),
+        '-' => r"\x2D".to_owned(),
+        c => c.to_string(),
+    }
+}
+
+/// Repeat `item_rule` between `min_items` and `max_items` (unbounded if `None`) times, with
+/// `separator_rule` in between if it is not empty.
+fn build_repetition(
+    item_rule: &str,
+    min_items: usize,
+    max_items: Option<usize>,
+    separator_rule: &str,
+) -> String {
+    if max_items == Some(0) {
+        // the separator must not be emitted either, match nothing
+        return r#""""#.to_owned();
+    }
+    if min_items == 0 && max_items == Some(1) {
+        return format!("{item_rule}?");
+    }
+
+    if separator_rule.is_empty() {
+        return match (min_items, max_items) {
+            (1, None) => format!("{item_rule}+"),
+            (0, None) => format!("{item_rule}*"),
+            (min, None) => format!("{item_rule}{{{min},}}"),
+            (min, Some(max)) => format!("{item_rule}{{{min},{max}}}"),
+        };
+    }
+
+    let result = format!(
+        "{item_rule} {}",
+        build_repetition(
+            &format!("({separator_rule} {item_rule})"),
+            min_items.saturating_sub(1),
+            max_items.map(|max| max.saturating_sub(1)),
+            "",
+        )
+    );
+    if min_items == 0 {
+        format!("({result})?")
+    } else {
+        result
+    }
+}
+
+fn as_usize(value: Option<&Value>) -> Option<usize> {
+    value
+        .and_then(Value::as_u64)
+        .and_then(|n| usize::try_from(n).ok())
+}
+
+#[derive(Debug, Default)]
+struct SchemaConverter {
+    rules: BTreeMap<String, String>,
+    /// The whole schema, local `$ref`s point into it.
+    root: Value,
+    /// The rule generated for each resolved `$ref`, registered before the target is visited so
+    /// recursive references terminate.
+    ref_rules: HashMap<String, String>,
+}
+
+impl SchemaConverter {
+    /// Add a rule, returning the (possibly suffixed) name it was stored under. Rules with the same
+    /// name and body are deduplicated.
+    fn add_rule(&mut self, name: &str, rule: &str) -> String {
+        let esc_name = escape_rule_name(name);
+        let mut key = esc_name.clone();
+        let mut i = 0;
+        while self
+            .rules
+            .get(&key)
+            .is_some_and(|existing| existing != rule)
+        {
+            key = format!("{esc_name}{i}");
+            i += 1;
+        }
+        self.rules.insert(key.clone(), rule.to_owned());
+        key
+    }
+
+    /// Add a builtin rule along with everything it depends on.
+    fn add_primitive(&mut self, name: &str, rule: &BuiltinRule) -> String {
+        let n = self.add_rule(name, rule.content);
+        for dep in rule.deps {
+            if !self.rules.contains_key(*dep) {
+                let dep_rule = primitive_rule(dep)
+                    .or_else(|| string_format_rule(dep))
+                    .expect("builtin rules only depend on builtin rules");
+                self.add_primitive(dep, dep_rule);
+            }
+        }
+        n
+    }
+
+    /// The schema a local `$ref` such as `#/$defs/point` points to.
+    fn ref_target(&self, reference: &str) -> Result<Value> {
+        let pointer = reference
+            .strip_prefix('#')
+            .ok_or_else(|| JsonSchemaToGrammarError::UnsupportedRef(reference.to_owned()))?;
+        self.root
+            .pointer(pointer)
+            .cloned()
+            .ok_or_else(|| JsonSchemaToGrammarError::UnresolvedRef(reference.to_owned()))
+    }
+
+    /// The rule matching the target of `reference`, named after its last path segment. Each
+    /// target gets one rule, which is generated the first time it is referenced.
+    fn resolve_ref(&mut self, reference: &str) -> Result<String> {
+        if let Some(rule_name) = self.ref_rules.get(reference) {
+            return Ok(rule_name.clone());
+        }
+        let target = self.ref_target(reference)?;
+
+        let name = reference
+            .rsplit_once('/')
+            .map_or(reference, |(_, name)| name);
+        let name = if is_reserved_name(name) {
+            format!("{name}-")
+        } else {
+            name.to_owned()
+        };
+        let esc_name = escape_rule_name(&name);
+        let mut rule_name = esc_name.clone();
+        let mut i = 0;
+        while self.rules.contains_key(&rule_name)
+            || self.ref_rules.values().any(|taken| *taken == rule_name)
+        {
+            rule_name = format!("{esc_name}{i}");
+            i += 1;
+        }
+        self.ref_rules
+            .insert(reference.to_owned(), rule_name.clone());
+
+        let rule = self.visit(&target, &rule_name)?;
+        if rule != rule_name {
+            self.rules.entry(rule_name.clone()).or_insert(rule);
+        }
+        Ok(rule_name)
+    }
+
+    fn generate_union_rule(&mut self, name: &str, alt_schemas: &[Value]) -> Result<String> {
+        let mut rules = Vec::with_capacity(alt_schemas.len());
+        for (i, alt_schema) in alt_schemas.iter().enumerate() {
+            let sep = if name.is_empty() { "alternative-" } else { "-" };
+            rules.push(self.visit(alt_schema, &format!("{name}{sep}{i}"))?);
+        }
+        Ok(rules.join(" | "))
+    }
+
+    /// Convert `schema` into rules, returning the name of the rule matching it.
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String> {
+        let rule_name = if is_reserved_name(name) {
+            format!("{name}-")
+        } else if name.is_empty() {
+            "root".to_owned()
+        } else {
+            name.to_owned()
+        };
+
+        let empty = Map::new();
+        let obj = match schema {
+            Value::Object(obj) => obj,
+            Value::Bool(true) => &empty,
+            _ => {
+                return Err(JsonSchemaToGrammarError::UnrecognizedSchema(
+                    schema.to_string(),
+                ))
+            }
+        };
+        let schema_type = obj.get("type");
+        let is_type = |t: &str| schema_type.is_none_or(|st| st == t);
+        let format = obj.get("format").and_then(Value::as_str).unwrap_or("");
+
+        if let Some(reference) = obj.get("$ref").and_then(Value::as_str) {
+            let target = self.resolve_ref(reference)?;
+            Ok(self.add_rule(&rule_name, &target))
+        } else if let Some(Value::Array(alt_schemas)) =
+            obj.get("oneOf").or_else(|| obj.get("anyOf"))
+        {
+            let rule = self.generate_union_rule(name, alt_schemas)?;
+            Ok(self.add_rule(&rule_name, &rule))
+        } else if let Some(Value::Array(types)) = schema_type {
+            let rule = self.visit_type_union(obj, types, name)?;
+            Ok(self.add_rule(&rule_name, &rule))
+        } else if let Some(value) = obj.get("const") {
+            let rule = format!("{} space", format_literal(&value.to_string()));
+            Ok(self.add_rule(&rule_name, &rule))
+        } else if let Some(Value::Array(values)) = obj.get("enum") {
+            let values = values
+                .iter()
+                .map(|v| format_literal(&v.to_string()))
+                .collect::<Vec<_>>();
+            Ok(self.add_rule(&rule_name, &format!("({}) space", values.join(" | "))))
+        } else if is_type("object")
+            && (obj.contains_key("properties")
+                || obj
+                    .get("additionalProperties")
+                    .is_some_and(|a| a != &Value::Bool(true)))
+        {
+            let rule = self.visit_object(obj, name)?;
+            Ok(self.add_rule(&rule_name, &rule))
+        } else if let (true, Some(Value::Array(components))) = (is_type("object"), obj.get("allOf"))
+        {
+            let rule = self.visit_all_of(components, name)?;
+            Ok(self.add_rule(&rule_name, &rule))
+        } else if let (true, Some(items)) = (
+            is_type("array"),
+            obj.get("items").or_else(|| obj.get("prefixItems")),
+        ) {
+            let rule = self.visit_array(obj, items, name)?;
+            Ok(self.add_rule(&rule_name, &rule))
+        } else if let (true, Some(pattern)) = (
+            is_type("string"),
+            obj.get("pattern").and_then(Value::as_str),
+        ) {
+            self.visit_pattern(pattern, &rule_name)
+        } else if is_type("string") && is_uuid_format(format) {
+            let name = if rule_name == "root" { "root" } else { format };
+            Ok(self.add_primitive(name, primitive_rule("uuid").expect("uuid is builtin")))
+        } else if let (true, Some(rule)) = (
+            is_type("string"),
+            string_format_rule(&format!("{format}-string")),
+        ) {
+            let primitive = self.add_primitive(&format!("{format}-string"), rule);
+            Ok(self.add_rule(&rule_name, &primitive))
+        } else if schema_type.is_some_and(|t| t == "string")
+            && (obj.contains_key("minLength") || obj.contains_key("maxLength"))
+        {
+            let rule = self.visit_string_length(obj);
+            Ok(self.add_rule(&rule_name, &rule))
+        } else if obj.is_empty() || schema_type.is_some_and(|t| t == "object") {
+            let object = self.add_primitive("object", primitive_rule("object").expect("builtin"));
+            Ok(self.add_rule(&rule_name, &object))
+        } else {
+            let Some((type_name, rule)) = schema_type
+                .and_then(Value::as_str)
+                .and_then(|t| Some((t, primitive_rule(t)?)))
+            else {
+                return Err(JsonSchemaToGrammarError::UnrecognizedSchema(
+                    schema.to_string(),
+                ));
+            };
+            let name = if rule_name == "root" {
+                "root"
+            } else {
+                type_name
+            };
+            Ok(self.add_primitive(name, rule))
+        }
+    }
+
+    /// Treat `"type": [...]` as a union of the schema with each of the types.
+    fn visit_type_union(
+        &mut self,
+        obj: &Map<String, Value>,
+        types: &[Value],
+        name: &str,
+    ) -> Result<String> {
+        let alt_schemas = types
+            .iter()
+            .map(|t| {
+                let mut alt = obj.clone();
+                alt.insert("type".to_owned(), t.clone());
+                Value::Object(alt)
+            })
+            .collect::<Vec<_>>();
+        self.generate_union_rule(name, &alt_schemas)
+    }
+
+    fn visit_object(&mut self, obj: &Map<String, Value>, name: &str) -> Result<String> {
+        let required = obj
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .map(ToOwned::to_owned)
+            .collect::<HashSet<_>>();
+        let properties = obj
+            .get("properties")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<Vec<_>>();
+        self.build_object_rule(
+            &properties,
+            &required,
+            name,
+            obj.get("additionalProperties"),
+        )
+    }
+
+    fn visit_string_length(&mut self, obj: &Map<String, Value>) -> String {
+        let char_rule = self.add_primitive("char", primitive_rule("char").expect("builtin"));
+        let min_len = as_usize(obj.get("minLength")).unwrap_or(0);
+        let max_len = as_usize(obj.get("maxLength"));
+        format!(
+            r#""\"" {} "\"" space"#,
+            build_repetition(&char_rule, min_len, max_len, "")
+        )
+    }
+
+    /// Merge the properties of all `allOf` components into a single object. Properties of `anyOf`
+    /// components are optional.
+    fn visit_all_of(&mut self, components: &[Value], name: &str) -> Result<String> {
+        let mut required = HashSet::new();
+        let mut properties = Vec::new();
+        for component in components {
+            if let Some(Value::Array(alternatives)) = component.get("anyOf") {
+                for alternative in alternatives {
+                    self.add_all_of_component(alternative, false, &mut properties, &mut required)?;
+                }
+            } else {
+                self.add_all_of_component(component, true, &mut properties, &mut required)?;
+            }
+        }
+        self.build_object_rule(&properties, &required, name, None)
+    }
+
+    fn add_all_of_component(
+        &mut self,
+        component: &Value,
+        is_required: bool,
+        properties: &mut Vec<(String, Value)>,
+        required: &mut HashSet<String>,
+    ) -> Result<()> {
+        if let Some(reference) = component.get("$ref").and_then(Value::as_str) {
+            let resolved = self.ref_target(reference)?;
+            return self.add_all_of_component(&resolved, is_required, properties, required);
+        }
+        if let Some(Value::Object(props)) = component.get("properties") {
+            for (key, value) in props {
+                properties.push((key.clone(), value.clone()));
+                if is_required {
+                    required.insert(key.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_array(
+        &mut self,
+        obj: &Map<String, Value>,
+        items: &Value,
+        name: &str,
+    ) -> Result<String> {
+        let prefix = if name.is_empty() {
+            String::new()
+        } else {
+            format!("{name}-")
+        };
+        if let Value::Array(items) = items {
+            let mut rule = r#""[" space "#.to_owned();
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    rule.push_str(r#" "," space "#);
+                }
+                rule.push_str(&self.visit(item, &format!("{prefix}tuple-{i}"))?);
+            }
+            rule.push_str(r#" "]" space"#);
+            Ok(rule)
+        } else {
+            let item_rule_name = self.visit(items, &format!("{prefix}item"))?;
+            let min_items = as_usize(obj.get("minItems")).unwrap_or(0);
+            let max_items = as_usize(obj.get("maxItems"));
+            Ok(format!(
+                r#""[" space {} "]" space"#,
+                build_repetition(&item_rule_name, min_items, max_items, r#""," space"#)
+            ))
+        }
+    }
+
+    fn build_object_rule(
+        &mut self,
+        properties: &[(String, Value)],
+        required: &HashSet<String>,
+        name: &str,
+        additional_properties: Option<&Value>,
+    ) -> Result<String> {
+        let prefix = if name.is_empty() {
+            String::new()
+        } else {
+            format!("{name}-")
+        };
+        let mut required_props = Vec::new();
+        let mut optional_props = Vec::new();
+        let mut prop_kv_rule_names = HashMap::new();
+        for (prop_name, prop_schema) in properties {
+            let prop_rule_name = self.visit(prop_schema, &format!("{prefix}{prop_name}"))?;
+            let kv_rule = format!(
+                r#"{} space ":" space {prop_rule_name}"#,
+                format_literal(&Value::from(prop_name.as_str()).to_string())
+            );
+            let kv_rule_name = self.add_rule(&format!("{prefix}{prop_name}-kv"), &kv_rule);
+            prop_kv_rule_names.insert(prop_name.as_str(), kv_rule_name);
+            if required.contains(prop_name) {
+                required_props.push(prop_name.as_str());
+            } else {
+                optional_props.push(prop_name.as_str());
+            }
+        }
+
+        if let Some(additional) =
+            additional_properties.filter(|a| a.is_object() || *a == &Value::Bool(true))
+        {
+            let sub_name = format!("{prefix}additional");
+            let value_rule = if additional.is_object() {
+                self.visit(additional, &format!("{sub_name}-value"))?
+            } else {
+                self.add_primitive("value", primitive_rule("value").expect("builtin"))
+            };
+            let key_rule = if properties.is_empty() {
+                self.add_primitive("string", primitive_rule("string").expect("builtin"))
+            } else {
+                let names = properties
+                    .iter()
+                    .map(|(k, _)| k.as_str())
+                    .collect::<Vec<_>>();
+                let rule = self.not_strings(&names);
+                self.add_rule(&format!("{sub_name}-k"), &rule)
+            };
+            let kv_rule = self.add_rule(
+                &format!("{sub_name}-kv"),
+                &format!(r#"{key_rule} ":" space {value_rule}"#),
+            );
+            prop_kv_rule_names.insert("*", kv_rule);
+            optional_props.push("*");
+        }
+
+        let mut rule = r#""{" space "#.to_owned();
+        let required_kvs = required_props
+            .iter()
+            .map(|k| prop_kv_rule_names[k].as_str())
+            .collect::<Vec<_>>();
+        rule.push_str(&required_kvs.join(r#" "," space "#));
+
+        if !optional_props.is_empty() {
+            rule.push_str(" (");
+            if !required_props.is_empty() {
+                rule.push_str(r#" "," space ( "#);
+            }
+            let mut alternatives = Vec::with_capacity(optional_props.len());
+            for i in 0..optional_props.len() {
+                alternatives.push(self.optional_refs(
+                    &prop_kv_rule_names,
+                    &prefix,
+                    &optional_props[i..],
+                    false,
+                ));
+            }
+            rule.push_str(&alternatives.join(" | "));
+            if !required_props.is_empty() {
+                rule.push_str(" )");
+            }
+            rule.push_str(" )?");
+        }
+
+        rule.push_str(r#" "}" space"#);
+        Ok(rule)
+    }
+
+    /// Build the rule for the optional properties `keys`, each of which may be left out.
+    fn optional_refs(
+        &mut self,
+        kv_rule_names: &HashMap<&str, String>,
+        prefix: &str,
+        keys: &[&str],
+        first_is_optional: bool,
+    ) -> String {
+        let Some((key, rest)) = keys.split_first() else {
+            return String::new();
+        };
+        let kv_rule_name = &kv_rule_names[key];
+        let comma_ref = format!(r#"( "," space {kv_rule_name} )"#);
+        let mut res = match (first_is_optional, *key == "*") {
+            (true, true) => format!("{comma_ref}*"),
+            (true, false) => format!("{comma_ref}?"),
+            (false, true) => format!("{kv_rule_name} {comma_ref}*"),
+            (false, false) => kv_rule_name.clone(),
+        };
+        if !rest.is_empty() {
+            let rest_rule = self.optional_refs(kv_rule_names, prefix, rest, true);
+            let rest_name = self.add_rule(&format!("{prefix}{key}-rest"), &rest_rule);
+            res.push(' ');
+            res.push_str(&rest_name);
+        }
+        res
+    }
+
+    /// Build a rule matching any JSON string except the given ones.
+    fn not_strings(&mut self, strings: &[&str]) -> String {
+        #[derive(Default)]
+        struct TrieNode {
+            children: BTreeMap<char, TrieNode>,
+            is_end_of_string: bool,
+        }
+
+        fn visit(node: &TrieNode, char_rule: &str, out: &mut String) {
+            let mut rejects = String::new();
+            for (i, (c, child)) in node.children.iter().enumerate() {
+                let c = escape_char_class(*c);
+                if i > 0 {
+                    out.push_str(" | ");
+                }
+                let _ = write!(out, "[{c}]");
+                rejects.push_str(&c);
+                if !child.children.is_empty() {
+                    out.push_str(" (");
+                    visit(child, char_rule, out);
+                    out.push(')');
+                } else if child.is_end_of_string {
+                    let _ = write!(out, " {char_rule}+");
+                }
+            }
+            if !node.children.is_empty() {
+                let _ = write!(out, r#" | [^"{rejects}] {char_rule}*"#);
+            }
+        }
+
+        let mut trie = TrieNode::default();
+        for s in strings {
+            let mut node = &mut trie;
+            for c in s.chars() {
+                node = node.children.entry(c).or_default();
+            }
+            node.is_end_of_string = true;
+        }
+
+        let char_rule = self.add_primitive("char", primitive_rule("char").expect("builtin"));
+        let mut out = r#"["] ( "#.to_owned();
+        visit(&trie, &char_rule, &mut out);
+        out.push_str(" )");
+        if !trie.is_end_of_string {
+            out.push('?');
+        }
+        out.push_str(r#" ["] space"#);
+        out
+    }
+
+    /// Translate a (`^...$` anchored) regular expression into a rule matching JSON strings.
+    fn visit_pattern(&mut self, pattern: &str, name: &str) -> Result<String> {
+        let Some(sub_pattern) = pattern.strip_prefix('^').and_then(|p| p.strip_suffix('$')) else {
+            return Err(JsonSchemaToGrammarError::InvalidPattern {
+                pattern: pattern.to_owned(),
+                reason: "pattern must start with '^' and end with '$'",
+            });
+        };
+        let mut translator = PatternTranslator {
+            converter: self,
+            pattern,
+            chars: sub_pattern.chars().collect(),
+            pos: 0,
+            name,
+            sub_rule_ids: HashMap::new(),
+        };
+        let rule = translator.transform(0)?;
+        Ok(self.add_rule(name, &format!(r#""\"" ({rule}) "\"" space"#)))
+    }
+
+    fn format_grammar(&self) -> String {
+        let mut out = String::new();
+        for (name, rule) in &self.rules {
+            let _ = writeln!(out, "{name} ::= {rule}");
+        }
+        out
+    }
+}
+
+fn is_uuid_format(format: &str) -> bool {
+    format
+        .strip_prefix("uuid")
+        .is_some_and(|v| v.is_empty() || matches!(v, "1" | "2" | "3" | "4" | "5"))
+}
+
+/// The GBNF character class equivalent of a regex class escape such as `\d`, and whether it is
+/// negated (`\D`).
+fn class_escape(c: char) -> Option<(&'static str, bool)> {
+    let class = match c.to_ascii_lowercase() {
+        'd' => "0-9",
+        'w' => "0-9A-Za-z_",
+        's' => r" \t\n\r",
+        _ => return None,
+    };
+    Some((class, c.is_ascii_uppercase()))
+}
+
+/// A piece of a translated pattern.
+enum Piece {
+    /// Text to be matched verbatim, already escaped for use inside a GBNF string literal.
+    Literal(String),
+    /// A GBNF expression.
+    Rule(String),
+}
+
+impl Piece {
+    fn into_rule(self) -> String {
+        match self {
+            Piece::Literal(literal) => format!("\"{literal}\""),
+            Piece::Rule(rule) => rule,
+        }
+    }
+}
+
+/// A recursive descent translator from a regular expression into GBNF.
+struct PatternTranslator<'c, 'p> {
+    converter: &'c mut SchemaConverter,
+    pattern: &'p str,
+    chars: Vec<char>,
+    pos: usize,
+    name: &'p str,
+    sub_rule_ids: HashMap<String, String>,
+}
+
+impl PatternTranslator<'_, '_> {
+    fn error(&self, reason: &'static str) -> JsonSchemaToGrammarError {
+        JsonSchemaToGrammarError::InvalidPattern {
+            pattern: self.pattern.to_owned(),
+            reason,
+        }
+    }
+
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    /// Translate until the end of the pattern, or the `)` closing the current group.
+    fn transform(&mut self, depth: usize) -> Result<String> {
+        let mut seq = Vec::new();
+        while let Some(c) = self.peek(0) {
+            match c {
+                '.' => {
+                    let dot = self.converter.add_rule("dot", r"[^\x0A\x0D]");
+                    seq.push(Piece::Rule(dot));
+                    self.pos += 1;
+                }
+                '(' => {
+                    self.pos += 1;
+                    if self.peek(0) == Some('?') {
+                        if self.peek(1) != Some(':') {
+                            return Err(self.error("lookarounds are not supported"));
+                        }
+                        self.pos += 2;
+                    }
+                    let group = self.transform(depth + 1)?;
+                    seq.push(Piece::Rule(format!("({group})")));
+                }
+                ')' => {
+                    if depth == 0 {
+                        return Err(self.error("unbalanced parentheses"));
+                    }
+                    self.pos += 1;
+                    return Ok(join_seq(seq));
+                }
+                '[' => seq.push(Piece::Rule(self.char_class()?)),
+                '|' => {
+                    seq.push(Piece::Rule("|".to_owned()));
+                    self.pos += 1;
+                }
+                '*' | '+' | '?' => {
+                    let last = seq.pop().ok_or_else(|| self.error("nothing to repeat"))?;
+                    seq.push(Piece::Rule(format!("{}{c}", last.into_rule())));
+                    self.pos += 1;
+                }
+                '{' => {
+                    let last = seq.pop().ok_or_else(|| self.error("nothing to repeat"))?;
+                    let (min_times, max_times) = self.repetition_bounds()?;
+                    let sub = match last {
+                        Piece::Literal(literal) => format!("\"{literal}\""),
+                        Piece::Rule(rule) => {
+                            let next_id = self.sub_rule_ids.len() + 1;
+                            if let Some(id) = self.sub_rule_ids.get(&rule) {
+                                id.clone()
+                            } else {
+                                let id = self
+                                    .converter
+                                    .add_rule(&format!("{}-{next_id}", self.name), &rule);
+                                self.sub_rule_ids.insert(rule, id.clone());
+                                id
+                            }
+                        }
+                    };
+                    seq.push(Piece::Rule(build_repetition(
+                        &sub, min_times, max_times, "",
+                    )));
+                }
+                '\\' if self.peek(1).and_then(class_escape).is_some() => {
+                    let (class, negated) = self.peek(1).and_then(class_escape).unwrap_or_default();
+                    let caret = if negated { "^" } else { "" };
+                    seq.push(Piece::Rule(format!("[{caret}{class}]")));
+                    self.pos += 2;
+                }
+                _ => {
+                    let literal = self.literal()?;
+                    if literal.is_empty() {
+                        // every other character is handled above, this keeps the cursor moving
+                        return Err(self.error("unexpected character"));
+                    }
+                    seq.push(Piece::Literal(literal));
+                }
+            }
+        }
+        if depth > 0 {
+            return Err(self.error("unbalanced parentheses"));
+        }
+        Ok(join_seq(seq))
+    }
+
+    /// Read a `[...]` character class.
+    fn char_class(&mut self) -> Result<String> {
+        let mut class = String::from('[');
+        self.pos += 1;
+        if self.peek(0) == Some('^') {
+            class.push('^');
+            self.pos += 1;
+        }
+        loop {
+            match self.peek(0) {
+                None => return Err(self.error("unbalanced square brackets")),
+                Some(']') => break,
+                Some('\\') => {
+                    let escaped = self
+                        .peek(1)
+                        .ok_or_else(|| self.error("trailing backslash"))?;
+                    if let Some((class_range, negated)) = class_escape(escaped) {
+                        if negated {
+                            return Err(
+                                self.error("negated class escapes in classes are not supported")
+                            );
+                        }
+                        class.push_str(class_range);
+                    } else if matches!(escaped, 't' | 'n' | 'r' | 'x' | 'u' | 'U') {
+                        class.push('\\');
+                        class.push(escaped);
+                    } else if escaped.is_ascii_alphanumeric() {
+                        return Err(self.error("unsupported escape"));
+                    } else {
+                        class.push_str(&escape_char_class(escaped));
+                    }
+                    self.pos += 2;
+                }
+                Some(c) => {
+                    if c == '-' {
+                        class.push('-');
+                    } else {
+                        class.push_str(&escape_char_class(c));
+                    }
+                    self.pos += 1;
+                }
+            }
+        }
+        self.pos += 1;
+        class.push(']');
+        Ok(class)
+    }
+
+    /// Read `{m}`, `{m,}`, `{,n}` or `{m,n}`.
+    fn repetition_bounds(&mut self) -> Result<(usize, Option<usize>)> {
+        self.pos += 1;
+        let mut body = String::new();
+        loop {
+            match self.peek(0) {
+                None => return Err(self.error("unbalanced curly brackets")),
+                Some('}') => break,
+                Some(c) => body.push(c),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+
+        let parse = |s: &str| {
+            s.trim()
+                .parse::<usize>()
+                .map_err(|_| self.error("invalid number in curly brackets"))
+        };
+        match body.split_once(',') {
+            None => {
+                let times = parse(&body)?;
+                Ok((times, Some(times)))
+            }
+            Some((min, max)) => {
+                let min_times = if min.is_empty() { 0 } else { parse(min)? };
+                let max_times = if max.is_empty() {
+                    None
+                } else {
+                    Some(parse(max)?)
+                };
+                Ok((min_times, max_times))
+            }
+        }
+    }
+
+    /// Read as many literal characters as possible. The last character is left alone if it is
+    /// followed by a quantifier, so the quantifier only applies to it. An unmatched `]` or `}` is
+    /// a literal character, as in JavaScript regular expressions.
+    fn literal(&mut self) -> Result<String> {
+        let mut literal = String::new();
+        while let Some(c) = self.peek(0) {
+            let (text, width) = match c {
+                '.' | '(' | ')' | '[' | '{' | '|' | '*' | '+' | '?' => break,
+                '\\' => match self.peek(1) {
+                    None => return Err(self.error("trailing backslash")),
+                    Some(escaped) if class_escape(escaped).is_some() => break,
+                    Some(escaped @ ('t' | 'n' | 'r' | 'x' | 'u' | 'U' | '\\' | '"')) => {
+                        (format!("\\{escaped}"), 2)
+                    }
+                    Some(escaped) if escaped.is_ascii_alphanumeric() => {
+                        return Err(self.error("unsupported escape"))
+                    }
+                    Some(escaped) => (escape_literal(escaped), 2),
+                },
+                c => (escape_literal(c), 1),
+            };
+            let quantified = matches!(self.peek(width), Some('*' | '+' | '?' | '{'));
+            if quantified && !literal.is_empty() {
+                break;
+            }
+            literal.push_str(&text);
+            self.pos += width;
+            if quantified {
+                break;
+            }
+        }
+        Ok(literal)
+    }
+}
+
+/// Join a sequence of pieces, merging consecutive literals together.
+fn join_seq(seq: Vec<Piece>) -> String {
+    let mut merged: Vec<Piece> = Vec::with_capacity(seq.len());
+    for piece in seq {
+        match (merged.last_mut(), piece) {
+            (Some(Piece::Literal(last)), Piece::Literal(literal)) => last.push_str(&literal),
+            (_, piece) => merged.push(piece),
+        }
+    }
+    merged
+        .into_iter()
+        .map(Piece::into_rule)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
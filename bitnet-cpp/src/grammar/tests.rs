@@ -2,89 +2,301 @@ use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
 
+use bitnet_cpp_sys::grammar::{
+    LLAMA_GRETYPE_ALT, LLAMA_GRETYPE_CHAR_ANY, LLAMA_GRETYPE_CHAR_NOT, LLAMA_GRETYPE_RULE_REF,
+};
+
 use super::*;
 
-// #[test]
-// fn check_parse() {
-//     let dir = Path::new("src/grammar");
-//     let files = std::fs::read_dir(dir)
-//         .expect("Failed to read grammar directory")
-//         .filter_map(Result::ok)
-//         .map(|os_str| os_str.path())
-//         .filter(|p| p.is_file())
-//         .filter(|f| f.extension().unwrap_or_default() == "gbnf")
-//         .map(File::open)
-//         .collect::<Vec<_>>();
-//     assert!(
-//         !files.is_empty(),
-//         "No grammar files found in {}",
-//         dir.canonicalize().unwrap().display()
-//     );
-//     for file in files {
-//         let reader = BufReader::new(file.unwrap());
-//         let file = std::io::read_to_string(reader).unwrap();
-//         LlamaGrammar::from_str(&file).unwrap();
-//     }
-// }
+#[test]
+fn check_parse() {
+    let dir = Path::new("src/grammar");
+    let files = std::fs::read_dir(dir)
+        .expect("Failed to read grammar directory")
+        .filter_map(Result::ok)
+        .map(|os_str| os_str.path())
+        .filter(|p| p.is_file())
+        .filter(|f| f.extension().unwrap_or_default() == "gbnf")
+        .map(File::open)
+        .collect::<Vec<_>>();
+    assert!(
+        !files.is_empty(),
+        "No grammar files found in {}",
+        dir.canonicalize().unwrap().display()
+    );
+    for file in files {
+        let reader = BufReader::new(file.unwrap());
+        let file = std::io::read_to_string(reader).unwrap();
+        LlamaGrammar::from_str(&file).unwrap();
+    }
+}
+
+#[test]
+fn check_parse_simple() {
+    let parse_state = ParseState::from_str(r#"root ::= "cat""#).unwrap();
+    assert_eq!(
+        ParseState {
+            symbol_ids: BTreeMap::from([("root".to_string(), 0),]),
+            rules: vec![vec![
+                llama_grammar_element {
+                    type_: bitnet_cpp_sys::grammar::LLAMA_GRETYPE_CHAR,
+                    value: 'c' as u32,
+                },
+                llama_grammar_element {
+                    type_: bitnet_cpp_sys::grammar::LLAMA_GRETYPE_CHAR,
+                    value: 'a' as u32,
+                },
+                llama_grammar_element {
+                    type_: bitnet_cpp_sys::grammar::LLAMA_GRETYPE_CHAR,
+                    value: 't' as u32,
+                },
+                llama_grammar_element {
+                    type_: bitnet_cpp_sys::grammar::LLAMA_GRETYPE_END,
+                    value: 0,
+                }
+            ]],
+        },
+        parse_state
+    );
+}
+
+#[test]
+fn check_parse_char_range() {
+    let parse_state = ParseState::from_str(r"root ::= [a-zA-Z]").unwrap();
+    assert_eq!(
+        ParseState {
+            symbol_ids: BTreeMap::from([("root".to_string(), 0),]),
+            rules: vec![vec![
+                llama_grammar_element {
+                    type_: bitnet_cpp_sys::grammar::LLAMA_GRETYPE_CHAR,
+                    value: 'a' as u32
+                },
+                llama_grammar_element {
+                    type_: bitnet_cpp_sys::grammar::LLAMA_GRETYPE_CHAR_RNG_UPPER,
+                    value: 'z' as u32
+                },
+                llama_grammar_element {
+                    type_: bitnet_cpp_sys::grammar::LLAMA_GRETYPE_CHAR_ALT,
+                    value: 'A' as u32
+                },
+                llama_grammar_element {
+                    type_: bitnet_cpp_sys::grammar::LLAMA_GRETYPE_CHAR_RNG_UPPER,
+                    value: 'Z' as u32
+                },
+                llama_grammar_element {
+                    type_: bitnet_cpp_sys::grammar::LLAMA_GRETYPE_END,
+                    value: 0
+                }
+            ]]
+        },
+        parse_state
+    );
+}
+
+#[test]
+fn check_parse_repetition() {
+    let parse_state = ParseState::from_str(r"root ::= [^x] .{1,2}").unwrap();
+    assert_eq!(
+        parse_state.symbol_ids(),
+        &BTreeMap::from([("root".to_string(), 0), ("root_1".to_string(), 1)])
+    );
+    assert_eq!(
+        parse_state.rules(),
+        [
+            vec![
+                element(LLAMA_GRETYPE_CHAR_NOT, 'x' as u32),
+                element(LLAMA_GRETYPE_CHAR_ANY, 0),
+                element(LLAMA_GRETYPE_RULE_REF, 1),
+                element(LLAMA_GRETYPE_END, 0),
+            ],
+            vec![
+                element(LLAMA_GRETYPE_CHAR_ANY, 0),
+                element(LLAMA_GRETYPE_ALT, 0),
+                element(LLAMA_GRETYPE_END, 0),
+            ],
+        ]
+    );
+}
+
+#[test]
+fn check_parse_errors() {
+    let cases = [
+        (
+            "root ::= \"cat",
+            GrammarParseErrorKind::UnexpectedEndOfInput,
+            1,
+            14,
+        ),
+        (
+            "root ::= (\"a\"\n",
+            GrammarParseErrorKind::ExpectingCloseParen,
+            2,
+            1,
+        ),
+        (
+            "root ::= \"a\"\n\nroot = \"b\"",
+            GrammarParseErrorKind::ExpectingAssignment,
+            3,
+            6,
+        ),
+        (
+            "root ::= * \"a\"",
+            GrammarParseErrorKind::ExpectingRepetitionItem,
+            1,
+            10,
+        ),
+        (
+            "root ::= \"\\q\"",
+            GrammarParseErrorKind::UnknownEscape('q'),
+            1,
+            11,
+        ),
+        (
+            "root ::= \"a\"{1;2}",
+            GrammarParseErrorKind::ExpectingComma,
+            1,
+            15,
+        ),
+        (
+            "root ::= \"ü\" item\n",
+            GrammarParseErrorKind::UndefinedRule("item".to_string()),
+            1,
+            14,
+        ),
+    ];
+    for (grammar, kind, line, column) in cases {
+        assert_eq!(
+            LlamaGrammar::from_str(grammar),
+            Err(GrammarParseError { kind, line, column }),
+            "{grammar:?}"
+        );
+    }
+}
+
+#[cfg(feature = "json-schema")]
+#[test]
+fn check_json_schema_object() {
+    let schema = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "name": { "type": "string", "minLength": 1, "maxLength": 3 },
+            "tags": { "type": "array", "items": { "enum": ["a", "b"] }, "minItems": 1, "maxItems": 3 },
+            "origin": { "$ref": "#/$defs/point" }
+        },
+        "required": ["name", "origin"],
+        "$defs": {
+            "point": {
+                "type": "object",
+                "properties": { "x": { "type": "number" }, "y": { "type": "number" } },
+                "required": ["x", "y"],
+                "additionalProperties": false
+            }
+        }
+    });
+    let grammar = LlamaGrammar::from_json_schema(&schema).unwrap();
+    let rules = grammar.as_str().lines().collect::<Vec<_>>();
+    for rule in [
+        r#"root ::= "{" space name-kv "," space origin-kv ( "," space ( tags-kv ) )? "}" space"#,
+        r#"name ::= "\"" char{1,3} "\"" space"#,
+        r#"point ::= "{" space point-x-kv "," space point-y-kv "}" space"#,
+        r"origin ::= point",
+        r#"tags ::= "[" space tags-item ("," space tags-item){0,2} "]" space"#,
+        r#"tags-item ::= ("\"a\"" | "\"b\"") space"#,
+    ] {
+        assert!(rules.contains(&rule), "{rule} not in\n{grammar}");
+    }
+
+    // properties keep the order of the schema, not the alphabetical one
+    let ordered = serde_json::json!({
+        "type": "object",
+        "properties": { "reasoning": { "type": "string" }, "answer": { "type": "integer" } },
+        "required": ["reasoning", "answer"]
+    });
+    let grammar = LlamaGrammar::from_json_schema(&ordered).unwrap();
+    assert!(
+        grammar
+            .as_str()
+            .contains(r#"root ::= "{" space reasoning-kv "," space answer-kv "}" space"#),
+        "{grammar}"
+    );
+
+    let empty =
+        serde_json::json!({ "type": "array", "items": { "type": "integer" }, "maxItems": 0 });
+    let grammar = LlamaGrammar::from_json_schema(&empty).unwrap();
+    assert!(
+        grammar
+            .as_str()
+            .contains(r#"root ::= "[" space "" "]" space"#),
+        "{grammar}"
+    );
+}
+
+#[cfg(feature = "json-schema")]
+#[test]
+fn check_json_schema_pattern() {
+    let schema = serde_json::json!({ "type": "string", "pattern": "^[A-Z]{2}-\\d+(\\.[a-z]+)?$" });
+    let grammar = LlamaGrammar::from_json_schema(&schema).unwrap();
+    assert!(grammar
+        .as_str()
+        .contains(r#"root ::= "\"" (root-1{2,2} "-" [0-9]+ ("." [a-z]+)?) "\"" space"#));
+
+    // an unmatched closing bracket is a literal character
+    for (pattern, rule) in [
+        ("^a}$", r#"root ::= "\"" ("a}") "\"" space"#),
+        ("^a]$", r#"root ::= "\"" ("a]") "\"" space"#),
+    ] {
+        let schema = serde_json::json!({ "type": "string", "pattern": pattern });
+        let grammar = LlamaGrammar::from_json_schema(&schema).unwrap();
+        assert!(grammar.as_str().contains(rule), "{rule} not in\n{grammar}");
+    }
+
+    let unanchored = serde_json::json!({ "type": "string", "pattern": "[a-z]+" });
+    assert!(matches!(
+        LlamaGrammar::from_json_schema(&unanchored),
+        Err(json_schema::JsonSchemaToGrammarError::InvalidPattern { .. })
+    ));
+}
 
-// #[test]
-// fn check_parse_simple() {
-//     let parse_state = ParseState::from_str(r#"root ::= "cat""#).unwrap();
-//     assert_eq!(
-//         ParseState {
-//             symbol_ids: BTreeMap::from([("root".to_string(), 0),]),
-//             rules: vec![vec![
-//                 llama_grammar_element {
-//                     type_: bitnet_cpp_sys::LLAMA_GRETYPE_CHAR,
-//                     value: 'c' as u32,
-//                 },
-//                 llama_grammar_element {
-//                     type_: bitnet_cpp_sys::LLAMA_GRETYPE_CHAR,
-//                     value: 'a' as u32,
-//                 },
-//                 llama_grammar_element {
-//                     type_: bitnet_cpp_sys::LLAMA_GRETYPE_CHAR,
-//                     value: 't' as u32,
-//                 },
-//                 llama_grammar_element {
-//                     type_: bitnet_cpp_sys::LLAMA_GRETYPE_END,
-//                     value: 0,
-//                 }
-//             ]],
-//         },
-//         parse_state
-//     );
-// }
+#[cfg(feature = "json-schema")]
+#[test]
+fn check_json_schema_refs() {
+    // a def referencing another def, a recursive def and a `$ref` at the root
+    let schema = serde_json::json!({
+        "$ref": "#/$defs/list",
+        "$defs": {
+            "list": {
+                "type": "object",
+                "properties": { "head": { "$ref": "#/$defs/node" } },
+                "required": ["head"],
+                "additionalProperties": false
+            },
+            "node": {
+                "type": "object",
+                "properties": {
+                    "value": { "type": "integer" },
+                    "next": { "$ref": "#/$defs/node" }
+                },
+                "required": ["value"],
+                "additionalProperties": false
+            }
+        }
+    });
+    let grammar = LlamaGrammar::from_json_schema(&schema).unwrap();
+    let rules = grammar.as_str().lines().collect::<Vec<_>>();
+    for rule in [
+        r"root ::= list",
+        r#"list ::= "{" space list-head-kv "}" space"#,
+        r"list-head ::= node",
+        r#"node ::= "{" space node-value-kv ( "," space ( node-next-kv ) )? "}" space"#,
+        r"node-next ::= node",
+    ] {
+        assert!(rules.contains(&rule), "{rule} not in\n{grammar}");
+    }
 
-// #[test]
-// fn check_parse_char_range() {
-//     let parse_state = ParseState::from_str(r#"root ::= [a-zA-Z]"#).unwrap();
-//     assert_eq!(
-//         ParseState {
-//             symbol_ids: BTreeMap::from([("root".to_string(), 0),]),
-//             rules: vec![vec![
-//                 llama_grammar_element {
-//                     type_: bitnet_cpp_sys::LLAMA_GRETYPE_CHAR,
-//                     value: 'a' as u32
-//                 },
-//                 llama_grammar_element {
-//                     type_: bitnet_cpp_sys::LLAMA_GRETYPE_CHAR_RNG_UPPER,
-//                     value: 'z' as u32
-//                 },
-//                 llama_grammar_element {
-//                     type_: bitnet_cpp_sys::LLAMA_GRETYPE_CHAR_ALT,
-//                     value: 'A' as u32
-//                 },
-//                 llama_grammar_element {
-//                     type_: bitnet_cpp_sys::LLAMA_GRETYPE_CHAR_RNG_UPPER,
-//                     value: 'Z' as u32
-//                 },
-//                 llama_grammar_element {
-//                     type_: bitnet_cpp_sys::LLAMA_GRETYPE_END,
-//                     value: 0
-//                 }
-//             ]]
-//         },
-//         parse_state
-//     );
-// }
+    let missing = serde_json::json!({ "$ref": "#/$defs/missing" });
+    assert!(matches!(
+        LlamaGrammar::from_json_schema(&missing),
+        Err(json_schema::JsonSchemaToGrammarError::UnresolvedRef(reference))
+            if reference == "#/$defs/missing"
+    ));
+}
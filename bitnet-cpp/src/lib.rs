@@ -13,6 +13,9 @@
 //!
 //! - `cuda` enables CUDA gpu support.
 //! - `sampler` adds the [`context::sample::sampler`] struct for a more rusty way of sampling.
+//! - `json-schema` adds [`grammar::json_schema`] to build grammars from JSON Schemas.
+//! - `serde` derives `Serialize` and `Deserialize` for [`context::sampler::config::SamplerConfig`]
+//!   and [`model::params::config::LlamaModelConfig`].
 use std::ffi::NulError;
 use std::fmt::Debug;
 use std::num::NonZeroI32;
@@ -23,6 +26,8 @@ use std::path::PathBuf;
 use std::string::FromUtf8Error;
 
 pub mod context;
+pub mod gguf;
+pub mod grammar;
 pub mod llama_backend;
 pub mod llama_batch;
 pub mod model;
@@ -85,7 +90,7 @@ pub enum LlamaContextLoadError {
 }
 
 /// Failed to decode a batch.
-#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, thiserror::Error)]
 pub enum DecodeError {
     /// No kv cache slot was available.
     #[error("Decode Error 1: NoKvCacheSlot")]
@@ -160,6 +165,9 @@ pub enum LlamaModelLoadError {
     /// Failed to convert the path to a rust str. This means the path was not valid unicode
     #[error("failed to convert path {0} to str")]
     PathToStrError(PathBuf),
+    /// The progress callback returned `false`.
+    #[error("model loading was cancelled by the progress callback")]
+    Cancelled,
 }
 
 /// An error that can occur when loading a model.
@@ -1,8 +1,89 @@
 //! Safe wrapper around `llama_batch`.
 
+use std::fmt::Display;
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+
 use crate::token::LlamaToken;
 use bitnet_cpp_sys::{llama_batch, llama_batch_free, llama_batch_init, llama_pos, llama_seq_id};
 
+/// A safe wrapper for `llama_seq_id`, the id of a sequence in a batch and in the kv cache.
+#[repr(transparent)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct SeqId(pub llama_seq_id);
+
+impl Display for SeqId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl SeqId {
+    /// Create a new `SeqId` from a i32.
+    ///
+    /// ```
+    /// # use bitnet_cpp::llama_batch::SeqId;
+    /// let seq_id = SeqId::new(1);
+    /// assert_eq!(seq_id, SeqId(1));
+    /// ```
+    #[must_use]
+    pub fn new(seq_id: i32) -> Self {
+        Self(seq_id)
+    }
+}
+
+/// A safe wrapper for `llama_pos`, the position of a token in its sequence.
+#[repr(transparent)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Pos(pub llama_pos);
+
+impl Display for Pos {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Pos {
+    /// Create a new `Pos` from a i32.
+    ///
+    /// ```
+    /// # use bitnet_cpp::llama_batch::Pos;
+    /// let pos = Pos::new(3) + 2;
+    /// assert_eq!(pos, Pos(5));
+    /// ```
+    #[must_use]
+    pub fn new(pos: i32) -> Self {
+        Self(pos)
+    }
+}
+
+impl Add<i32> for Pos {
+    type Output = Pos;
+
+    fn add(self, delta: i32) -> Pos {
+        Pos(self.0 + delta)
+    }
+}
+
+impl AddAssign<i32> for Pos {
+    fn add_assign(&mut self, delta: i32) {
+        self.0 += delta;
+    }
+}
+
+impl Sub<i32> for Pos {
+    type Output = Pos;
+
+    fn sub(self, delta: i32) -> Pos {
+        Pos(self.0 - delta)
+    }
+}
+
+impl SubAssign<i32> for Pos {
+    fn sub_assign(&mut self, delta: i32) {
+        self.0 -= delta;
+    }
+}
+
 /// A safe wrapper around `llama_batch`.
 #[derive(Debug)]
 pub struct LlamaBatch {
@@ -15,7 +96,7 @@ pub struct LlamaBatch {
 }
 
 /// Errors that can occur when adding a token to a batch.
-#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BatchAddError {
     /// There was not enough space in the batch to add the token.
     #[error("Insufficient Space of {0}")]
@@ -44,8 +125,8 @@ impl LlamaBatch {
     pub fn add(
         &mut self,
         LlamaToken(id): LlamaToken,
-        pos: llama_pos,
-        seq_ids: &[i32],
+        Pos(pos): Pos,
+        seq_ids: &[SeqId],
         logits: bool,
     ) -> Result<(), BatchAddError> {
         if self.allocated
@@ -68,7 +149,7 @@ impl LlamaBatch {
             // for (size_t i = 0; i < seq_ids.size(); ++i) {
             //     batch.seq_id[batch.n_tokens][i] = seq_ids[i];
             // }
-            for (i, seq_id) in seq_ids.iter().enumerate() {
+            for (i, SeqId(seq_id)) in seq_ids.iter().enumerate() {
                 let tmp = *self.llama_batch.seq_id.add(offset_usize);
                 tmp.add(i).write(*seq_id);
             }
@@ -103,11 +184,11 @@ impl LlamaBatch {
     /// # Panics
     ///
     /// - [`self.llama_batch.n_tokens`] does not fit into a [`usize`]
-    /// - [`n_tokens - 1`] does not fit into a [`llama_pos`]
+    /// - [`n_tokens - 1`] does not fit into a [`Pos`]
     pub fn add_sequence(
         &mut self,
         tokens: &[LlamaToken],
-        seq_id: i32,
+        seq_id: SeqId,
         logits_all: bool,
     ) -> Result<(), BatchAddError> {
         let n_tokens_0 =
@@ -121,7 +202,7 @@ impl LlamaBatch {
         let last_index = llama_pos::try_from(n_tokens.saturating_sub(1))
             .expect("cannot fit n_tokens into a llama_pos");
         for (i, token) in (0..).zip(tokens.iter()) {
-            self.add(*token, i, &[seq_id], logits_all || i == last_index)?;
+            self.add(*token, Pos(i), &[seq_id], logits_all || i == last_index)?;
         }
 
         Ok(())
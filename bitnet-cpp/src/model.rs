@@ -17,6 +17,7 @@ use crate::{
     LlamaModelLoadError, NewLlamaChatMessageError, StringToTokenError, TokenToStringError,
 };
 
+pub mod metadata;
 pub mod params;
 
 /// A safe wrapper around `llama_model`.
@@ -389,6 +390,41 @@ impl LlamaModel {
         unsafe { bitnet_cpp_sys::llama_n_embd(self.model.as_ptr()) }
     }
 
+    /// The number of layers of the model.
+    ///
+    /// # Panics
+    ///
+    /// If llama.cpp returns a negative layer count.
+    #[must_use]
+    pub fn n_layer(&self) -> u32 {
+        let n_layer = unsafe { bitnet_cpp_sys::llama_n_layer(self.model.as_ptr()) };
+        u32::try_from(n_layer).expect("n_layer fits into an u32")
+    }
+
+    /// The number of attention heads of the model. See
+    /// [`LlamaModel::n_head_kv`] for the number of key-value heads.
+    ///
+    /// # Panics
+    ///
+    /// If llama.cpp returns a negative head count.
+    #[must_use]
+    pub fn n_head(&self) -> u32 {
+        let n_head = unsafe { bitnet_cpp_sys::llama_n_head(self.model.as_ptr()) };
+        u32::try_from(n_head).expect("n_head fits into an u32")
+    }
+
+    /// The total size of all tensors of the model in bytes.
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        unsafe { bitnet_cpp_sys::llama_model_size(self.model.as_ptr()) }
+    }
+
+    /// The total number of parameters of the model.
+    #[must_use]
+    pub fn n_params(&self) -> u64 {
+        unsafe { bitnet_cpp_sys::llama_model_n_params(self.model.as_ptr()) }
+    }
+
     /// Get chat template from model.
     ///
     /// # Errors
@@ -431,6 +467,10 @@ impl LlamaModel {
     /// # Errors
     ///
     /// See [`LlamaModelLoadError`] for more information.
+    ///
+    /// # Panics
+    ///
+    /// If the progress callback of `params` panicked.
     #[tracing::instrument(skip_all, fields(params))]
     pub fn load_from_file(
         _: &LlamaBackend,
@@ -444,10 +484,16 @@ impl LlamaModel {
             .ok_or(LlamaModelLoadError::PathToStrError(path.to_path_buf()))?;
 
         let cstr = CString::new(path)?;
+        params.start_progress();
         let llama_model =
             unsafe { bitnet_cpp_sys::llama_load_model_from_file(cstr.as_ptr(), params.params) };
+        let cancelled = params.finish_progress();
 
-        let model = NonNull::new(llama_model).ok_or(LlamaModelLoadError::NullResult)?;
+        let model = NonNull::new(llama_model).ok_or(if cancelled {
+            LlamaModelLoadError::Cancelled
+        } else {
+            LlamaModelLoadError::NullResult
+        })?;
 
         tracing::debug!(?path, "Loaded model");
         Ok(LlamaModel { model })
@@ -0,0 +1,212 @@
+//! Read the GGUF metadata of a loaded model.
+//!
+//! llama.cpp keeps the metadata of a model as strings, the typed getters on [`LlamaModel`] parse
+//! them back.
+//!
+//! ```no_run
+//! # use bitnet_cpp::model::LlamaModel;
+//! # fn example(model: &LlamaModel) -> Result<(), Box<dyn std::error::Error>> {
+//! println!("{} ({} parameters)", model.description()?, model.n_params());
+//! for entry in model.metadata() {
+//!     let (key, value) = entry?;
+//!     println!("{key} = {value}");
+//! }
+//! let rope_freq_base = model.meta_val_float("llama.rope.freq_base")?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ffi::{c_char, CString};
+
+use crate::model::LlamaModel;
+
+/// The first buffer size tried when reading a metadata string.
+const INITIAL_BUF_SIZE: usize = 256;
+
+/// Failed to read a metadata value of a model.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum MetaValError {
+    /// The key contained a null byte and can not be passed to llama.cpp.
+    #[error("the key contains a null byte: {0}")]
+    NulInKey(#[from] std::ffi::NulError),
+    /// The model has no value for the key, or the index is out of range.
+    #[error("the model has no metadata value for {0}")]
+    Missing(String),
+    /// The key or value is not valid utf8.
+    #[error(transparent)]
+    Utf8Error(#[from] std::string::FromUtf8Error),
+    /// The value can not be parsed as the requested type.
+    #[error("the metadata value {value:?} of {key} is not a valid {expected}")]
+    InvalidValue {
+        /// The key of the value.
+        key: String,
+        /// The value as stored by llama.cpp.
+        value: String,
+        /// The requested type.
+        expected: &'static str,
+    },
+}
+
+impl LlamaModel {
+    /// The number of metadata entries of the model.
+    ///
+    /// llama.cpp only keeps scalar values, array values such as `tokenizer.ggml.tokens` are not
+    /// included. Use [`GgufFile`](crate::gguf::GgufFile) to read those from the model file.
+    #[must_use]
+    pub fn meta_count(&self) -> usize {
+        let count = unsafe { bitnet_cpp_sys::llama_model_meta_count(self.model.as_ptr()) };
+        usize::try_from(count).unwrap_or(0)
+    }
+
+    /// Get the key of the metadata entry at `index`.
+    ///
+    /// # Errors
+    ///
+    /// - `index` is not less than [`LlamaModel::meta_count`]
+    /// - the key is not valid utf8
+    pub fn meta_key_by_index(&self, index: usize) -> Result<String, MetaValError> {
+        let i = index_to_c_int(index)?;
+        read_string(&format!("index {index}"), |buf, buf_size| unsafe {
+            bitnet_cpp_sys::llama_model_meta_key_by_index(self.model.as_ptr(), i, buf, buf_size)
+        })
+    }
+
+    /// Get the value of the metadata entry at `index` as a string.
+    ///
+    /// # Errors
+    ///
+    /// - `index` is not less than [`LlamaModel::meta_count`]
+    /// - the value is not valid utf8
+    pub fn meta_val_str_by_index(&self, index: usize) -> Result<String, MetaValError> {
+        let i = index_to_c_int(index)?;
+        read_string(&format!("index {index}"), |buf, buf_size| unsafe {
+            bitnet_cpp_sys::llama_model_meta_val_str_by_index(self.model.as_ptr(), i, buf, buf_size)
+        })
+    }
+
+    /// Iterate over all metadata entries of the model as `(key, value)` pairs.
+    pub fn metadata(&self) -> impl Iterator<Item = Result<(String, String), MetaValError>> + '_ {
+        (0..self.meta_count()).map(|index| {
+            Ok((
+                self.meta_key_by_index(index)?,
+                self.meta_val_str_by_index(index)?,
+            ))
+        })
+    }
+
+    /// Get the metadata value of `key` as a string, e.g. `general.name`.
+    ///
+    /// # Errors
+    ///
+    /// - the model has no value for `key`
+    /// - `key` contains a null byte or the value is not valid utf8
+    pub fn meta_val_str(&self, key: &str) -> Result<String, MetaValError> {
+        let c_key = CString::new(key)?;
+        read_string(key, |buf, buf_size| unsafe {
+            bitnet_cpp_sys::llama_model_meta_val_str(
+                self.model.as_ptr(),
+                c_key.as_ptr(),
+                buf,
+                buf_size,
+            )
+        })
+    }
+
+    /// Get the metadata value of `key` as an integer, e.g. `general.file_type`. Booleans are not
+    /// integers.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModel::meta_val_str`], or the value is not an integer.
+    pub fn meta_val_int(&self, key: &str) -> Result<i64, MetaValError> {
+        self.meta_val_parsed(key, "integer")
+    }
+
+    /// Get the metadata value of `key` as a float, e.g. `llama.rope.freq_base`. Integers are
+    /// converted.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModel::meta_val_str`], or the value is not a number.
+    pub fn meta_val_float(&self, key: &str) -> Result<f64, MetaValError> {
+        self.meta_val_parsed(key, "float")
+    }
+
+    /// Get the metadata value of `key` as a boolean.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModel::meta_val_str`], or the value is not `true` or `false`.
+    pub fn meta_val_bool(&self, key: &str) -> Result<bool, MetaValError> {
+        self.meta_val_parsed(key, "boolean")
+    }
+
+    /// A short description of the model: its architecture, size and file type.
+    ///
+    /// # Errors
+    ///
+    /// If the description is not valid utf8.
+    pub fn description(&self) -> Result<String, MetaValError> {
+        read_string("the description", |buf, buf_size| unsafe {
+            bitnet_cpp_sys::llama_model_desc(self.model.as_ptr(), buf, buf_size)
+        })
+    }
+
+    /// The architecture of the model (`general.architecture`), which prefixes most other keys.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModel::meta_val_str`].
+    pub fn architecture(&self) -> Result<String, MetaValError> {
+        self.meta_val_str("general.architecture")
+    }
+
+    /// The number of key-value heads (`<arch>.attention.head_count_kv`). This is less than
+    /// [`LlamaModel::n_head`] for models using grouped-query attention.
+    ///
+    /// # Errors
+    ///
+    /// If the architecture or the head count can not be read.
+    pub fn n_head_kv(&self) -> Result<u32, MetaValError> {
+        let key = format!("{}.attention.head_count_kv", self.architecture()?);
+        match self.meta_val_parsed(&key, "head count") {
+            // llama.cpp defaults to one key-value head per attention head
+            Err(MetaValError::Missing(_)) => Ok(self.n_head()),
+            result => result,
+        }
+    }
+
+    fn meta_val_parsed<T: std::str::FromStr>(
+        &self,
+        key: &str,
+        expected: &'static str,
+    ) -> Result<T, MetaValError> {
+        let value = self.meta_val_str(key)?;
+        value.parse().map_err(|_| MetaValError::InvalidValue {
+            key: key.to_owned(),
+            value,
+            expected,
+        })
+    }
+}
+
+fn index_to_c_int(index: usize) -> Result<i32, MetaValError> {
+    i32::try_from(index).map_err(|_| MetaValError::Missing(format!("index {index}")))
+}
+
+/// Call a `snprintf` like llama.cpp function, growing the buffer until the result fits.
+fn read_string(
+    what: &str,
+    read: impl Fn(*mut c_char, usize) -> i32,
+) -> Result<String, MetaValError> {
+    let mut buf = vec![0u8; INITIAL_BUF_SIZE];
+    loop {
+        let len = read(buf.as_mut_ptr().cast::<c_char>(), buf.len());
+        let len = usize::try_from(len).map_err(|_| MetaValError::Missing(what.to_owned()))?;
+        if len < buf.len() {
+            buf.truncate(len);
+            return Ok(String::from_utf8(buf)?);
+        }
+        buf.resize(len + 1, 0);
+    }
+}
@@ -1,29 +1,144 @@
 //! A safe wrapper around `llama_model_params`.
 
 use crate::model::params::kv_overrides::KvOverrides;
-use std::ffi::{c_char, CStr};
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::ffi::{c_char, c_void, CStr, CString};
 use std::fmt::{Debug, Formatter};
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
 use std::pin::Pin;
 use std::ptr::null;
 
+pub mod config;
 pub mod kv_overrides;
 
+/// A rusty wrapper around `llama_split_mode`: how to split the model across several GPUs.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum LlamaSplitMode {
+    /// Use a single GPU, [`LlamaModelParams::main_gpu`].
+    None = bitnet_cpp_sys::LLAMA_SPLIT_MODE_NONE as _,
+    /// Split layers and kv cache across GPUs.
+    Layer = bitnet_cpp_sys::LLAMA_SPLIT_MODE_LAYER as _,
+    /// Split rows across GPUs.
+    Row = bitnet_cpp_sys::LLAMA_SPLIT_MODE_ROW as _,
+}
+
+/// Create a `LlamaSplitMode` from a `llama_split_mode` - returns the unknown value as the error.
+impl TryFrom<bitnet_cpp_sys::llama_split_mode> for LlamaSplitMode {
+    type Error = bitnet_cpp_sys::llama_split_mode;
+
+    fn try_from(value: bitnet_cpp_sys::llama_split_mode) -> Result<Self, Self::Error> {
+        match value {
+            bitnet_cpp_sys::LLAMA_SPLIT_MODE_NONE => Ok(Self::None),
+            bitnet_cpp_sys::LLAMA_SPLIT_MODE_LAYER => Ok(Self::Layer),
+            bitnet_cpp_sys::LLAMA_SPLIT_MODE_ROW => Ok(Self::Row),
+            unknown => Err(unknown),
+        }
+    }
+}
+
+/// Invalid model parameters.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum LlamaModelParamsError {
+    /// More tensor split proportions than llama.cpp supports devices.
+    #[error(
+        "got {len} tensor split proportions, but llama.cpp supports at most {max_devices} devices"
+    )]
+    TensorSplitTooLong {
+        /// The number of proportions.
+        len: usize,
+        /// See `llama_max_devices`.
+        max_devices: usize,
+    },
+    /// A tensor split proportion is negative or not finite.
+    #[error("invalid tensor split proportion {0}")]
+    InvalidTensorSplit(f32),
+    /// An RPC server is empty, or contains a comma or a null byte.
+    #[error("invalid RPC server {0:?}")]
+    InvalidRpcServer(String),
+    /// A key-value override key or string value does not fit into the 128 bytes of llama.cpp,
+    /// or contains a null byte.
+    #[error("invalid key-value override {0:?}")]
+    InvalidKvOverride(String),
+}
+
+/// The size of the key and string value buffers of `llama_model_kv_override`.
+pub(crate) const KV_OVERRIDE_BUF_SIZE: usize = 128;
+
+/// Copy `key` and its null terminator into the key buffer of a `llama_model_kv_override`.
+fn kv_override_key(key: &CStr) -> Result<[c_char; KV_OVERRIDE_BUF_SIZE], LlamaModelParamsError> {
+    let bytes = key.to_bytes_with_nul();
+    if bytes.len() > KV_OVERRIDE_BUF_SIZE {
+        return Err(LlamaModelParamsError::InvalidKvOverride(
+            key.to_string_lossy().into_owned(),
+        ));
+    }
+    let mut buf = [0; KV_OVERRIDE_BUF_SIZE];
+    for (c, &byte) in buf.iter_mut().zip(bytes) {
+        #[allow(clippy::cast_possible_wrap)] // c_char is i8 on some platforms
+        let byte = byte as c_char;
+        *c = byte;
+    }
+    Ok(buf)
+}
+
 /// A safe wrapper around `llama_model_params`.
 #[allow(clippy::module_name_repetitions)]
 pub struct LlamaModelParams {
     pub(crate) params: bitnet_cpp_sys::llama_model_params,
     kv_overrides: Vec<bitnet_cpp_sys::llama_model_kv_override>,
+    progress_callback: Option<Box<ProgressCallback>>,
+    /// Padded to `llama_max_devices`, llama.cpp reads one proportion per device.
+    tensor_split: Vec<f32>,
+    n_tensor_split: usize,
+    rpc_servers: Option<CString>,
+}
+
+/// The state behind `progress_callback_user_data`. It is boxed so its address stays the same
+/// when the params move.
+struct ProgressCallback {
+    callback: RefCell<Box<dyn FnMut(f32) -> bool>>,
+    cancelled: Cell<bool>,
+    panic: Cell<Option<Box<dyn Any + Send>>>,
+}
+
+/// Forwards to the closure of a [`ProgressCallback`]. A panic cancels the load and is resumed
+/// once llama.cpp returns.
+unsafe extern "C" fn progress_callback(progress: f32, user_data: *mut c_void) -> bool {
+    let state = &*user_data.cast::<ProgressCallback>();
+    let result = catch_unwind(AssertUnwindSafe(|| (state.callback.borrow_mut())(progress)));
+    let proceed = result.unwrap_or_else(|panic| {
+        state.panic.set(Some(panic));
+        false
+    });
+    if !proceed {
+        state.cancelled.set(true);
+    }
+    proceed
 }
 
 impl Debug for LlamaModelParams {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("LlamaModelParams")
             .field("n_gpu_layers", &self.params.n_gpu_layers)
+            .field("split_mode", &self.params.split_mode)
             .field("main_gpu", &self.params.main_gpu)
+            .field("tensor_split", &self.tensor_split())
+            .field("n_tensor_split", &self.n_tensor_split)
+            .field("rpc_servers", &self.rpc_servers)
             .field("vocab_only", &self.params.vocab_only)
             .field("use_mmap", &self.params.use_mmap)
             .field("use_mlock", &self.params.use_mlock)
+            .field("check_tensors", &self.params.check_tensors)
             .field("kv_overrides", &"vec of kv_overrides")
+            .field("progress_callback", &self.progress_callback.is_some())
             .finish()
     }
 }
@@ -47,16 +162,24 @@ impl LlamaModelParams {
 
     /// Appends a key-value override to the model parameters. It must be pinned as this creates a self-referential struct.
     ///
+    /// # Errors
+    ///
+    /// [`LlamaModelParamsError::InvalidKvOverride`] if `key` does not fit into the 128 bytes
+    /// llama.cpp has for it, including the null terminator.
+    ///
     /// # Examples
     ///
     /// ```rust
     /// # use std::ffi::{CStr, CString};
     /// use std::pin::pin;
-    /// # use llama_cpp_2::model::params::LlamaModelParams;
-    /// # use llama_cpp_2::model::params::kv_overrides::ParamOverrideValue;
+    /// # use bitnet_cpp::model::params::LlamaModelParams;
+    /// # use bitnet_cpp::model::params::kv_overrides::ParamOverrideValue;
     /// let mut params = pin!(LlamaModelParams::default());
     /// let key = CString::new("key").expect("CString::new failed");
-    /// params.as_mut().append_kv_override(&key, ParamOverrideValue::Int(50));
+    /// params
+    ///     .as_mut()
+    ///     .append_kv_override(&key, ParamOverrideValue::Int(50))
+    ///     .unwrap();
     ///
     /// let kv_overrides = params.kv_overrides().into_iter().collect::<Vec<_>>();
     /// assert_eq!(kv_overrides.len(), 1);
@@ -71,19 +194,16 @@ impl LlamaModelParams {
         mut self: Pin<&mut Self>,
         key: &CStr,
         value: kv_overrides::ParamOverrideValue,
-    ) {
+    ) -> Result<(), LlamaModelParamsError> {
+        let key = kv_override_key(key)?;
         let kv_override = self
             .kv_overrides
-            .get_mut(0)
+            .last_mut()
             .expect("kv_overrides did not have a next allocated");
 
         assert_eq!(kv_override.key[0], 0, "last kv_override was not empty");
 
-        // There should be some way to do this without iterating over everything.
-        for (i, &c) in key.to_bytes_with_nul().iter().enumerate() {
-            kv_override.key[i] = c_char::try_from(c).expect("invalid character in key");
-        }
-
+        kv_override.key = key;
         kv_override.tag = value.tag();
         kv_override.__bindgen_anon_1 = value.value();
 
@@ -102,8 +222,7 @@ impl LlamaModelParams {
 
         // set the pointer to the (potentially) new vector
         self.params.kv_overrides = self.kv_overrides.as_ptr();
-
-        eprintln!("saved ptr: {:?}", self.params.kv_overrides);
+        Ok(())
     }
 }
 
@@ -138,6 +257,43 @@ impl LlamaModelParams {
         self.params.use_mlock
     }
 
+    /// Get how the model is split across several GPUs.
+    ///
+    /// # Panics
+    ///
+    /// If llama.cpp uses a split mode that is not known to this library.
+    #[must_use]
+    pub fn split_mode(&self) -> LlamaSplitMode {
+        LlamaSplitMode::try_from(self.params.split_mode).expect("invalid split mode")
+    }
+
+    /// Get the proportions of the model offloaded to each GPU. Empty for the default split.
+    #[must_use]
+    pub fn tensor_split(&self) -> &[f32] {
+        &self.tensor_split[..self.n_tensor_split]
+    }
+
+    /// Get the RPC servers to offload to.
+    #[must_use]
+    #[allow(clippy::missing_panics_doc)] // the servers are set from a str
+    pub fn rpc_servers(&self) -> Vec<&str> {
+        self.rpc_servers
+            .as_deref()
+            .map_or_else(Vec::new, |servers| {
+                servers
+                    .to_str()
+                    .expect("rpc servers are set from a str")
+                    .split(',')
+                    .collect()
+            })
+    }
+
+    /// validate model tensor data
+    #[must_use]
+    pub fn check_tensors(&self) -> bool {
+        self.params.check_tensors
+    }
+
     /// sets the number of gpu layers to offload to the GPU.
     /// ```
     /// # use llama_cpp_2::model::params::LlamaModelParams;
@@ -174,6 +330,138 @@ impl LlamaModelParams {
         self.params.use_mlock = use_mlock;
         self
     }
+
+    /// sets `use_mmap`
+    #[must_use]
+    pub fn with_use_mmap(mut self, use_mmap: bool) -> Self {
+        self.params.use_mmap = use_mmap;
+        self
+    }
+
+    /// sets `check_tensors`
+    #[must_use]
+    pub fn with_check_tensors(mut self, check_tensors: bool) -> Self {
+        self.params.check_tensors = check_tensors;
+        self
+    }
+
+    /// sets how the model is split across several GPUs
+    #[must_use]
+    pub fn with_split_mode(mut self, split_mode: LlamaSplitMode) -> Self {
+        self.params.split_mode = split_mode as bitnet_cpp_sys::llama_split_mode;
+        self
+    }
+
+    /// Sets the proportion of the model (layers or rows, see [`LlamaSplitMode`]) to offload to
+    /// each GPU, e.g. `[3.0, 1.0]` puts three quarters on the first GPU. An empty slice restores
+    /// the default split.
+    ///
+    /// # Errors
+    ///
+    /// - there are more proportions than `llama_max_devices`
+    /// - a proportion is negative or not finite
+    pub fn with_tensor_split(
+        mut self,
+        tensor_split: &[f32],
+    ) -> Result<Self, LlamaModelParamsError> {
+        if let Some(&invalid) = tensor_split
+            .iter()
+            .find(|proportion| !proportion.is_finite() || proportion.is_sign_negative())
+        {
+            return Err(LlamaModelParamsError::InvalidTensorSplit(invalid));
+        }
+        if tensor_split.is_empty() {
+            self.params.tensor_split = null();
+            self.tensor_split = Vec::new();
+            self.n_tensor_split = 0;
+            return Ok(self);
+        }
+        let max_devices = unsafe { bitnet_cpp_sys::llama_max_devices() };
+        if tensor_split.len() > max_devices {
+            return Err(LlamaModelParamsError::TensorSplitTooLong {
+                len: tensor_split.len(),
+                max_devices,
+            });
+        }
+        let mut padded = tensor_split.to_vec();
+        padded.resize(max_devices, 0.0);
+        self.tensor_split = padded;
+        self.n_tensor_split = tensor_split.len();
+        self.params.tensor_split = self.tensor_split.as_ptr();
+        Ok(self)
+    }
+
+    /// Sets the RPC servers to offload to, e.g. `["192.168.1.2:50052"]`. llama.cpp must be
+    /// built with RPC support. No servers disables RPC.
+    ///
+    /// # Errors
+    ///
+    /// If a server is empty, or contains a comma or a null byte.
+    pub fn with_rpc_servers<S: AsRef<str>>(
+        mut self,
+        servers: impl IntoIterator<Item = S>,
+    ) -> Result<Self, LlamaModelParamsError> {
+        let mut joined = String::new();
+        for server in servers {
+            let server = server.as_ref();
+            if server.is_empty() || server.contains([',', '\0']) {
+                return Err(LlamaModelParamsError::InvalidRpcServer(server.to_owned()));
+            }
+            if !joined.is_empty() {
+                joined.push(',');
+            }
+            joined.push_str(server);
+        }
+        // the servers were checked for null bytes
+        self.rpc_servers = CString::new(joined)
+            .ok()
+            .filter(|joined| !joined.is_empty());
+        self.params.rpc_servers = self.rpc_servers.as_deref().map_or(null(), CStr::as_ptr);
+        Ok(self)
+    }
+
+    /// Call `callback` with the loading progress between 0 and 1 while a model is loaded.
+    /// Returning `false` cancels the load, which then fails with
+    /// [`LlamaModelLoadError::Cancelled`](crate::LlamaModelLoadError::Cancelled). Without a
+    /// callback llama.cpp prints dots to stderr.
+    ///
+    /// ```
+    /// # use bitnet_cpp::model::params::LlamaModelParams;
+    /// let params = LlamaModelParams::default().with_progress_callback(|progress| {
+    ///     eprint!("\rloading {:3.0}%", progress * 100.0);
+    ///     true
+    /// });
+    /// ```
+    #[must_use]
+    pub fn with_progress_callback(mut self, callback: impl FnMut(f32) -> bool + 'static) -> Self {
+        let state = Box::new(ProgressCallback {
+            callback: RefCell::new(Box::new(callback)),
+            cancelled: Cell::new(false),
+            panic: Cell::new(None),
+        });
+        self.params.progress_callback = Some(progress_callback);
+        self.params.progress_callback_user_data = std::ptr::addr_of!(*state).cast_mut().cast();
+        self.progress_callback = Some(state);
+        self
+    }
+
+    /// Reset the state of the progress callback before a load.
+    pub(crate) fn start_progress(&self) {
+        if let Some(state) = &self.progress_callback {
+            state.cancelled.set(false);
+        }
+    }
+
+    /// Whether the progress callback cancelled the last load. Resumes a panic of the callback.
+    pub(crate) fn finish_progress(&self) -> bool {
+        let Some(state) = &self.progress_callback else {
+            return false;
+        };
+        if let Some(panic) = state.panic.take() {
+            resume_unwind(panic);
+        }
+        state.cancelled.get()
+    }
 }
 
 /// Default parameters for `LlamaModel`. (as defined in llama.cpp by `llama_model_default_params`)
@@ -202,6 +490,50 @@ impl Default for LlamaModelParams {
                     val_i64: 0,
                 },
             }],
+            progress_callback: None,
+            tensor_split: Vec::new(),
+            n_tensor_split: 0,
+            rpc_servers: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_callback_records_cancellation_and_panics() {
+        let state = ProgressCallback {
+            callback: RefCell::new(Box::new(|progress| {
+                assert!(progress < 1.0, "done");
+                progress < 0.5
+            })),
+            cancelled: Cell::new(false),
+            panic: Cell::new(None),
+        };
+        let user_data = std::ptr::addr_of!(state).cast_mut().cast();
+
+        assert!(unsafe { progress_callback(0.25, user_data) });
+        assert!(!state.cancelled.get());
+        assert!(!unsafe { progress_callback(0.75, user_data) });
+        assert!(state.cancelled.get());
+        assert!(!unsafe { progress_callback(1.0, user_data) });
+        assert!(state.panic.take().is_some());
+    }
+
+    #[test]
+    fn checks_kv_override_key_length() {
+        let key = CString::new("é".repeat(63)).unwrap();
+        let buf = kv_override_key(&key).unwrap();
+        assert_eq!(unsafe { CStr::from_ptr(buf.as_ptr()) }, key.as_c_str());
+
+        let key = CString::new("x".repeat(KV_OVERRIDE_BUF_SIZE)).unwrap();
+        assert_eq!(
+            kv_override_key(&key),
+            Err(LlamaModelParamsError::InvalidKvOverride(
+                "x".repeat(KV_OVERRIDE_BUF_SIZE)
+            ))
+        );
+    }
+}
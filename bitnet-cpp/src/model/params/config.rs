@@ -0,0 +1,201 @@
+//! A plain description of [`LlamaModelParams`], e.g. to read them from a config file.
+//!
+//! With the `serde` feature a [`LlamaModelConfig`] can be deserialized. Fields that are not set
+//! keep the defaults of llama.cpp.
+//!
+//! ```no_run
+//! # use bitnet_cpp::llama_backend::LlamaBackend;
+//! # use bitnet_cpp::model::LlamaModel;
+//! # use bitnet_cpp::model::params::LlamaSplitMode;
+//! # use bitnet_cpp::model::params::config::{KvOverrideConfig, LlamaModelConfig};
+//! # fn example(backend: &LlamaBackend) -> Result<(), Box<dyn std::error::Error>> {
+//! let config = LlamaModelConfig {
+//!     n_gpu_layers: Some(99),
+//!     split_mode: Some(LlamaSplitMode::Layer),
+//!     tensor_split: vec![3.0, 1.0],
+//!     use_mmap: Some(false),
+//!     kv_overrides: vec![(
+//!         "tokenizer.ggml.add_bos_token".to_owned(),
+//!         KvOverrideConfig::Bool(false),
+//!     )],
+//!     ..LlamaModelConfig::default()
+//! };
+//! let params = config.build()?;
+//! let model = LlamaModel::load_from_file(backend, "ggml-model-i2_s.gguf", &params)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ffi::{c_char, CString};
+use std::pin::Pin;
+
+use crate::model::params::kv_overrides::ParamOverrideValue;
+use crate::model::params::{
+    LlamaModelParams, LlamaModelParamsError, LlamaSplitMode, KV_OVERRIDE_BUF_SIZE,
+};
+
+/// The value of a key-value override, see [`ParamOverrideValue`]. Strings must be shorter than
+/// 128 bytes.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(untagged)
+)]
+#[allow(missing_docs)]
+pub enum KvOverrideConfig {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+/// The fields of [`LlamaModelParams`], see the [module documentation](self). `None` and empty
+/// fields keep the default.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+#[allow(clippy::module_name_repetitions)]
+pub struct LlamaModelConfig {
+    /// [`LlamaModelParams::with_n_gpu_layers`]
+    pub n_gpu_layers: Option<u32>,
+    /// [`LlamaModelParams::with_split_mode`]
+    pub split_mode: Option<LlamaSplitMode>,
+    /// [`LlamaModelParams::with_main_gpu`]
+    pub main_gpu: Option<i32>,
+    /// [`LlamaModelParams::with_tensor_split`]
+    pub tensor_split: Vec<f32>,
+    /// [`LlamaModelParams::with_rpc_servers`]
+    pub rpc_servers: Vec<String>,
+    /// [`LlamaModelParams::with_vocab_only`]
+    pub vocab_only: Option<bool>,
+    /// [`LlamaModelParams::with_use_mmap`]
+    pub use_mmap: Option<bool>,
+    /// [`LlamaModelParams::with_use_mlock`]
+    pub use_mlock: Option<bool>,
+    /// [`LlamaModelParams::with_check_tensors`]
+    pub check_tensors: Option<bool>,
+    /// [`LlamaModelParams::append_kv_override`], as `(key, value)` pairs.
+    pub kv_overrides: Vec<(String, KvOverrideConfig)>,
+}
+
+impl LlamaModelConfig {
+    /// Build the [`LlamaModelParams`] described by this config. They are pinned because of the
+    /// key-value overrides.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModelParamsError`].
+    pub fn build(&self) -> Result<Pin<Box<LlamaModelParams>>, LlamaModelParamsError> {
+        let mut params = LlamaModelParams::default()
+            .with_tensor_split(&self.tensor_split)?
+            .with_rpc_servers(&self.rpc_servers)?;
+        if let Some(n_gpu_layers) = self.n_gpu_layers {
+            params = params.with_n_gpu_layers(n_gpu_layers);
+        }
+        if let Some(split_mode) = self.split_mode {
+            params = params.with_split_mode(split_mode);
+        }
+        if let Some(main_gpu) = self.main_gpu {
+            params = params.with_main_gpu(main_gpu);
+        }
+        if let Some(vocab_only) = self.vocab_only {
+            params = params.with_vocab_only(vocab_only);
+        }
+        if let Some(use_mmap) = self.use_mmap {
+            params = params.with_use_mmap(use_mmap);
+        }
+        if let Some(use_mlock) = self.use_mlock {
+            params = params.with_use_mlock(use_mlock);
+        }
+        if let Some(check_tensors) = self.check_tensors {
+            params = params.with_check_tensors(check_tensors);
+        }
+
+        let mut params = Box::pin(params);
+        for (key, value) in &self.kv_overrides {
+            let (key, value) = kv_override(key, value)?;
+            params.as_mut().append_kv_override(&key, value)?;
+        }
+        Ok(params)
+    }
+}
+
+fn kv_override(
+    key: &str,
+    value: &KvOverrideConfig,
+) -> Result<(CString, ParamOverrideValue), LlamaModelParamsError> {
+    let invalid = || LlamaModelParamsError::InvalidKvOverride(key.to_owned());
+    // the length of the key is checked by `append_kv_override`
+    let c_key = CString::new(key).map_err(|_| invalid())?;
+    let value = match value {
+        KvOverrideConfig::Bool(value) => ParamOverrideValue::Bool(*value),
+        KvOverrideConfig::Int(value) => ParamOverrideValue::Int(*value),
+        KvOverrideConfig::Float(value) => ParamOverrideValue::Float(*value),
+        KvOverrideConfig::Str(value) => {
+            if value.len() >= KV_OVERRIDE_BUF_SIZE || value.contains('\0') {
+                return Err(invalid());
+            }
+            let mut buf = [0; KV_OVERRIDE_BUF_SIZE];
+            for (c, &byte) in buf.iter_mut().zip(value.as_bytes()) {
+                #[allow(clippy::cast_possible_wrap)] // c_char is i8 on some platforms
+                let byte = byte as c_char;
+                *c = byte;
+            }
+            ParamOverrideValue::Str(buf)
+        }
+    };
+    Ok((c_key, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    #[test]
+    fn validates_kv_overrides() {
+        let (key, value) =
+            kv_override("general.name", &KvOverrideConfig::Str("ok".to_owned())).unwrap();
+        assert_eq!(key.as_bytes(), b"general.name");
+        let ParamOverrideValue::Str(buf) = value else {
+            panic!("expected a string, got {value:?}");
+        };
+        assert_eq!(unsafe { CStr::from_ptr(buf.as_ptr()) }.to_bytes(), b"ok");
+
+        let long = "x".repeat(KV_OVERRIDE_BUF_SIZE);
+        for (key, value) in [
+            ("a\0b", KvOverrideConfig::Bool(true)),
+            ("general.name", KvOverrideConfig::Str(long.clone())),
+        ] {
+            assert_eq!(
+                kv_override(key, &value),
+                Err(LlamaModelParamsError::InvalidKvOverride(key.to_owned()))
+            );
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn deserializes_partial_configs() {
+        let config: LlamaModelConfig = serde_json::from_str(
+            r#"{"n_gpu_layers": 20, "split_mode": "row", "kv_overrides": [["a", 1], ["b", "c"]]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            LlamaModelConfig {
+                n_gpu_layers: Some(20),
+                split_mode: Some(LlamaSplitMode::Row),
+                kv_overrides: vec![
+                    ("a".to_owned(), KvOverrideConfig::Int(1)),
+                    ("b".to_owned(), KvOverrideConfig::Str("c".to_owned())),
+                ],
+                ..LlamaModelConfig::default()
+            }
+        );
+    }
+}
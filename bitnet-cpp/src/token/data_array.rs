@@ -0,0 +1,179 @@
+//! Safe wrapper around `llama_token_data_array`.
+use crate::token::data::LlamaTokenData;
+use crate::token::LlamaToken;
+
+/// The candidates a sampler stage chooses from, see `llama_token_data_array`.
+#[derive(Debug, Clone, PartialEq, Default)]
+#[allow(clippy::module_name_repetitions)]
+pub struct LlamaTokenDataArray {
+    /// the candidates
+    pub data: Vec<LlamaTokenData>,
+    /// the index of the selected candidate in `data`, if a token was picked
+    pub selected: Option<usize>,
+    /// whether `data` is sorted by descending logit
+    pub sorted: bool,
+}
+
+impl LlamaTokenDataArray {
+    /// Create a new `LlamaTokenDataArray` from a vector of candidates.
+    ///
+    /// ```
+    /// # use bitnet_cpp::token::data::LlamaTokenData;
+    /// # use bitnet_cpp::token::data_array::LlamaTokenDataArray;
+    /// # use bitnet_cpp::token::LlamaToken;
+    /// let candidates = LlamaTokenDataArray::new(
+    ///     vec![
+    ///         LlamaTokenData::new(LlamaToken::new(0), 0.5, 0.0),
+    ///         LlamaTokenData::new(LlamaToken::new(1), 1.5, 0.0),
+    ///     ],
+    ///     false,
+    /// );
+    /// assert_eq!(candidates.data.len(), 2);
+    /// assert_eq!(candidates.selected_token(), None);
+    /// ```
+    #[must_use]
+    pub fn new(data: Vec<LlamaTokenData>, sorted: bool) -> Self {
+        Self {
+            data,
+            selected: None,
+            sorted,
+        }
+    }
+
+    /// Create a new `LlamaTokenDataArray` from an iterator of candidates.
+    #[must_use]
+    pub fn from_iter<T>(data: T, sorted: bool) -> Self
+    where
+        T: IntoIterator<Item = LlamaTokenData>,
+    {
+        Self::new(data.into_iter().collect(), sorted)
+    }
+
+    /// The selected token, if any.
+    #[must_use]
+    pub fn selected_token(&self) -> Option<LlamaToken> {
+        self.data.get(self.selected?).map(LlamaTokenData::id)
+    }
+
+    /// Sort the candidates by descending logit. Does nothing if they are already sorted.
+    pub fn sort(&mut self) {
+        if !self.sorted {
+            self.data.sort_by(|a, b| b.logit().total_cmp(&a.logit()));
+            self.selected = None;
+            self.sorted = true;
+        }
+    }
+
+    /// Sort the candidates and set their probabilities to the softmax of the logits.
+    ///
+    /// ```
+    /// # use bitnet_cpp::token::data::LlamaTokenData;
+    /// # use bitnet_cpp::token::data_array::LlamaTokenDataArray;
+    /// # use bitnet_cpp::token::LlamaToken;
+    /// let mut candidates = LlamaTokenDataArray::from_iter(
+    ///     [0.0, 2.0_f32.ln()]
+    ///         .into_iter()
+    ///         .enumerate()
+    ///         .map(|(id, logit)| LlamaTokenData::new(LlamaToken::new(id as i32), logit, 0.0)),
+    ///     false,
+    /// );
+    /// candidates.softmax();
+    /// assert_eq!(candidates.data[0].id(), LlamaToken::new(1));
+    /// assert!((candidates.data[0].p() - 2.0 / 3.0).abs() < 1e-6);
+    /// ```
+    pub fn softmax(&mut self) {
+        self.sort();
+        let Some(max_logit) = self.data.first().map(LlamaTokenData::logit) else {
+            return;
+        };
+        let mut sum = 0.0;
+        for candidate in &mut self.data {
+            let p = (candidate.logit() - max_logit).exp();
+            candidate.set_p(p);
+            sum += p;
+        }
+        for candidate in &mut self.data {
+            candidate.set_p(candidate.p() / sum);
+        }
+    }
+
+    /// Run `f` on a `llama_token_data_array` view of `self` and apply the changes `f` made to the
+    /// size, the selected candidate and the sorted flag.
+    pub(crate) fn modify_as_c_llama_token_data_array<T>(
+        &mut self,
+        f: impl FnOnce(&mut bitnet_cpp_sys::llama_token_data_array) -> T,
+    ) -> T {
+        let data = self
+            .data
+            .as_mut_ptr()
+            .cast::<bitnet_cpp_sys::llama_token_data>();
+        let mut array = bitnet_cpp_sys::llama_token_data_array {
+            data,
+            size: self.data.len(),
+            selected: self
+                .selected
+                .and_then(|selected| i64::try_from(selected).ok())
+                .unwrap_or(-1),
+            sorted: self.sorted,
+        };
+        let result = f(&mut array);
+
+        // samplers may skip candidates by advancing the data pointer
+        let offset = usize::try_from(unsafe { array.data.offset_from(data) })
+            .expect("samplers only advance the data pointer");
+        assert!(
+            offset + array.size <= self.data.len(),
+            "samplers can not add candidates"
+        );
+        self.data.truncate(offset + array.size);
+        self.data.drain(..offset);
+        self.selected = usize::try_from(array.selected).ok();
+        self.sorted = array.sorted;
+
+        result
+    }
+
+    /// Replace the contents of `self` with a copy of `array`.
+    ///
+    /// # Safety
+    ///
+    /// `array.data` must point to `array.size` valid elements.
+    pub(crate) unsafe fn copy_from_c(&mut self, array: &bitnet_cpp_sys::llama_token_data_array) {
+        self.data.clear();
+        if array.size > 0 {
+            // `LlamaTokenData` is `repr(transparent)` over `llama_token_data`.
+            let data = std::slice::from_raw_parts(array.data.cast::<LlamaTokenData>(), array.size);
+            self.data.extend_from_slice(data);
+        }
+        self.selected = usize::try_from(array.selected).ok();
+        self.sorted = array.sorted;
+    }
+
+    /// Write the contents of `self` back into `array`.
+    ///
+    /// `array` has no room for additional candidates, so only the first `array.size` candidates
+    /// are kept.
+    ///
+    /// # Safety
+    ///
+    /// `array.data` must point to `array.size` valid elements.
+    pub(crate) unsafe fn copy_to_c(&self, array: &mut bitnet_cpp_sys::llama_token_data_array) {
+        let size = self.data.len().min(array.size);
+        if size > 0 {
+            std::ptr::copy_nonoverlapping(
+                self.data
+                    .as_ptr()
+                    .cast::<bitnet_cpp_sys::llama_token_data>(),
+                array.data,
+                size,
+            );
+        }
+        array.size = size;
+        array.selected = self
+            .selected
+            .filter(|&selected| selected < size)
+            .and_then(|selected| i64::try_from(selected).ok())
+            .unwrap_or(-1);
+        array.sorted = self.sorted;
+    }
+}
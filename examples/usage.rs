@@ -14,16 +14,14 @@
 //! ```console
 //! I'm here to help! Are you a programmer?
 //! ```
+use bitnet_cpp::context::generate::GenerateParams;
 use bitnet_cpp::context::params::LlamaContextParams;
 use bitnet_cpp::context::sampler::LlamaSampler;
 use bitnet_cpp::llama_backend::LlamaBackend;
-use bitnet_cpp::llama_batch::LlamaBatch;
 use bitnet_cpp::model::params::LlamaModelParams;
 use bitnet_cpp::model::LlamaModel;
-use bitnet_cpp::model::{AddBos, Special};
 use std::io::Write;
 
-#[allow(clippy::cast_possible_wrap, clippy::cast_possible_truncation)]
 fn main() {
     let model_path = std::env::args().nth(1).expect("Please specify model path");
     let backend = LlamaBackend::init().unwrap();
@@ -31,63 +29,26 @@ fn main() {
 
     let prompt =
         "<|im_start|>user\nHello! how are you?<|im_end|>\n<|im_start|>assistant\n".to_string();
-    LlamaContextParams::default();
     let model =
         LlamaModel::load_from_file(&backend, model_path, &params).expect("unable to load model");
     let ctx_params = LlamaContextParams::default();
     let mut ctx = model
         .new_context(&backend, ctx_params)
         .expect("unable to create the llama_context");
-    let tokens_list = model
-        .str_to_token(&prompt, AddBos::Always)
-        .unwrap_or_else(|_| panic!("failed to tokenize {prompt}"));
-    let n_len = 64;
 
-    // create a llama_batch with size 512
-    // we use this object to submit token data for decoding
-    let mut batch = LlamaBatch::new(512, 1);
-
-    let last_index = tokens_list.len() as i32 - 1;
-    for (i, token) in (0_i32..).zip(tokens_list.into_iter()) {
-        // llama_decode will output logits only for the last token of the prompt
-        let is_last = i == last_index;
-        batch.add(token, i, &[0], is_last).unwrap();
-    }
-    ctx.decode(&mut batch).expect("llama_decode() failed");
-
-    let mut n_cur = batch.n_tokens();
-
-    // The `Decoder`
-    let mut decoder = encoding_rs::UTF_8.new_decoder();
     let sampler = LlamaSampler::default();
-
-    while n_cur <= n_len {
-        // sample the next token
-        {
-            // sample the most likely token
-            let new_token_id = sampler.sample(&ctx, batch.n_tokens() - 1);
-
-            // is it an end of stream?
-            if new_token_id == model.token_eos() {
-                eprintln!();
-                break;
-            }
-
-            let output_bytes = model
-                .token_to_bytes(new_token_id, Special::Tokenize)
-                .unwrap();
-            // use `Decoder.decode_to_string()` to avoid the intermediate buffer
-            let mut output_string = String::with_capacity(32);
-            let _decode_result = decoder.decode_to_string(&output_bytes, &mut output_string, false);
-            print!("{output_string}");
-            std::io::stdout().flush().unwrap();
-
-            batch.clear();
-            batch.add(new_token_id, n_cur, &[0], true).unwrap();
-        }
-
-        n_cur += 1;
-
-        ctx.decode(&mut batch).expect("failed to eval");
+    let generate_params = GenerateParams::default().with_max_tokens(Some(64));
+
+    // tokenizes and decodes the prompt, then samples one token per iteration until the model
+    // produces an end of generation token or 64 tokens were generated
+    let generator = ctx
+        .generate(&prompt, &sampler, generate_params)
+        .unwrap_or_else(|_| panic!("failed to process {prompt}"));
+
+    for piece in generator {
+        let piece = piece.expect("failed to generate");
+        print!("{}", piece.text);
+        std::io::stdout().flush().unwrap();
     }
+    eprintln!();
 }